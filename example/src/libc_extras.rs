@@ -150,6 +150,25 @@ pub mod libc {
         }
     }
 
+    // Linux has no position argument; it's only used for resource forks on Mac OS X.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn lsetxattr(path: *const c_char, name: *const c_char, value: *const c_void,
+                            size: size_t, flags: c_int, _position: u32) -> c_int {
+        ::libc::lsetxattr(path, name, value, size, flags)
+    }
+
+    // Mac OS X has no l*xattr functions; an option flag is used instead.
+    #[cfg(target_os = "macos")]
+    pub unsafe fn lsetxattr(path: *const c_char, name: *const c_char, value: *const c_void,
+                            size: size_t, flags: c_int, position: u32) -> c_int {
+        setxattr(path, name, value, size, position, flags | XATTR_NOFOLLOW)
+    }
+
+    #[cfg(target_os = "macos")]
+    pub unsafe fn lremovexattr(path: *const c_char, name: *const c_char) -> c_int {
+        removexattr(path, name, XATTR_NOFOLLOW)
+    }
+
     // the value is ignored; this is for OS X compat
     #[cfg(target_os = "macos")]
     pub const AT_FDCWD: c_int = -100;
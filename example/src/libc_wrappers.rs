@@ -45,6 +45,26 @@ pub fn readdir(fh: u64) -> Result<Option<libc::dirent>, libc::c_int> {
     Ok(Some(entry))
 }
 
+pub fn rewinddir(fh: u64) {
+    let dir: *mut libc::DIR = unsafe { mem::transmute(fh as usize) };
+    unsafe { libc::rewinddir(dir) };
+}
+
+pub fn telldir(fh: u64) -> Result<u64, libc::c_int> {
+    let dir: *mut libc::DIR = unsafe { mem::transmute(fh as usize) };
+    let offset = unsafe { libc::telldir(dir) };
+    if offset == -1 {
+        return Err(io::Error::last_os_error().raw_os_error().unwrap());
+    }
+
+    Ok(offset as u64)
+}
+
+pub fn seekdir(fh: u64, offset: u64) {
+    let dir: *mut libc::DIR = unsafe { mem::transmute(fh as usize) };
+    unsafe { libc::seekdir(dir, offset as libc::c_long) };
+}
+
 pub fn closedir(fh: u64) -> Result<(), libc::c_int> {
     let dir: *mut libc::DIR = unsafe { mem::transmute(fh as usize) };
     if -1 == unsafe { libc::closedir(dir) } {
@@ -155,3 +175,58 @@ pub fn lgetxattr(path: OsString, name: OsString, buf: &mut [u8]) -> Result<usize
         nbytes => Ok(nbytes as usize),
     }
 }
+
+pub fn lsetxattr(path: OsString, name: OsString, value: &[u8], flags: u32, position: u32) -> Result<(), libc::c_int> {
+    let path_c = match CString::new(path.into_vec()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("lsetxattr: path {:?} contains interior NUL byte",
+                   OsString::from_vec(e.into_vec()));
+            return Err(libc::EINVAL);
+        }
+    };
+
+    let name_c = match CString::new(name.into_vec()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("lsetxattr: attr name {:?} contains interior NUL byte",
+                   OsString::from_vec(e.into_vec()));
+            return Err(libc::EINVAL);
+        }
+    };
+
+    let result = unsafe {
+        libc::lsetxattr(path_c.as_ptr(), name_c.as_ptr(), value.as_ptr() as *const libc::c_void,
+            value.len(), flags as libc::c_int, position)
+    };
+    match result {
+        -1 => Err(io::Error::last_os_error().raw_os_error().unwrap()),
+        _ => Ok(()),
+    }
+}
+
+pub fn lremovexattr(path: OsString, name: OsString) -> Result<(), libc::c_int> {
+    let path_c = match CString::new(path.into_vec()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("lremovexattr: path {:?} contains interior NUL byte",
+                   OsString::from_vec(e.into_vec()));
+            return Err(libc::EINVAL);
+        }
+    };
+
+    let name_c = match CString::new(name.into_vec()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("lremovexattr: attr name {:?} contains interior NUL byte",
+                   OsString::from_vec(e.into_vec()));
+            return Err(libc::EINVAL);
+        }
+    };
+
+    let result = unsafe { libc::lremovexattr(path_c.as_ptr(), name_c.as_ptr()) };
+    match result {
+        -1 => Err(io::Error::last_os_error().raw_os_error().unwrap()),
+        _ => Ok(()),
+    }
+}
@@ -4,11 +4,9 @@
 //
 
 use std::env;
-use std::ffi::{OsStr, OsString};
+use std::ffi::OsString;
 
-extern crate fuse;
 extern crate libc;
-extern crate time;
 
 #[macro_use]
 extern crate log;
@@ -49,7 +47,12 @@ fn main() {
         target: args[1].clone(),
     };
 
-    let fuse_args: Vec<&OsStr> = vec![&OsStr::new("-o"), &OsStr::new("auto_unmount")];
+    let options = [fuse_mt::MountOption::AutoUnmount];
 
-    fuse::mount(fuse_mt::FuseMT::new(filesystem, 1), &args[2], &fuse_args);
+    // Use the backing filesystem's inode numbers, so that hard links show up as such.
+    let fs = fuse_mt::FuseMT::new(filesystem)
+        .threads(1)
+        .inode_assignment(fuse_mt::InodeAssignment::Filesystem);
+
+    fuse_mt::mount(fs, &args[2], &options).unwrap();
 }
@@ -11,13 +11,73 @@ use std::io::{self, Read, Write, Seek, SeekFrom};
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::io::{FromRawFd, IntoRawFd};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use super::libc_extras::libc;
 use super::libc_wrappers;
 
-use fuse::*;
 use fuse_mt::*;
-use time::*;
+
+// Reads the entries of an open directory as they're asked for.
+struct DirectoryStream {
+    fh: u64,
+    path: PathBuf,
+    real_path: PathBuf,
+}
+
+impl Iterator for DirectoryStream {
+    type Item = Result<DirectoryEntry, Errno>;
+
+    fn next(&mut self) -> Option<Result<DirectoryEntry, Errno>> {
+        let entry = match libc_wrappers::readdir(self.fh) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return None,
+            Err(e) => {
+                error!("readdir: {:?}: {}", self.path, e);
+                return Some(Err(Errno(e)));
+            }
+        };
+
+        // Where the stream is now, so that the listing can carry on from after this entry.
+        let offset = match libc_wrappers::telldir(self.fh) {
+            Ok(offset) => offset,
+            Err(e) => {
+                error!("telldir: {:?}: {}", self.path, e);
+                return Some(Err(Errno(e)));
+            }
+        };
+
+        let name_c = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) };
+        let name = OsStr::from_bytes(name_c.to_bytes()).to_os_string();
+
+        let filetype = match entry.d_type {
+            libc::DT_DIR => FileType::Directory,
+            libc::DT_REG => FileType::RegularFile,
+            libc::DT_LNK => FileType::Symlink,
+            libc::DT_BLK => FileType::BlockDevice,
+            libc::DT_CHR => FileType::CharDevice,
+            libc::DT_FIFO => FileType::NamedPipe,
+            libc::DT_SOCK => FileType::Socket,
+            0 | _ => {
+                let real_path = self.real_path.join(&name).into_os_string();
+                match libc_wrappers::lstat(real_path) {
+                    Ok(stat64) => mode_to_filetype(stat64.st_mode),
+                    Err(errno) => {
+                        let ioerr = io::Error::from_raw_os_error(errno);
+                        panic!("lstat failed after readdir_r gave no file type for {:?}: {}",
+                               self.path.join(&name), ioerr);
+                    }
+                }
+            }
+        };
+
+        Some(Ok(DirectoryEntry {
+            name: name,
+            kind: filetype,
+            offset: Some(offset),
+        }))
+    }
+}
 
 pub struct PassthroughFS {
     pub target: OsString,
@@ -31,33 +91,58 @@ fn mode_to_filetype(mode: libc::mode_t) -> FileType {
         libc::S_IFBLK => FileType::BlockDevice,
         libc::S_IFCHR => FileType::CharDevice,
         libc::S_IFIFO  => FileType::NamedPipe,
-        libc::S_IFSOCK => {
-            warn!("FUSE doesn't support Socket file type; translating to NamedPipe instead.");
-            FileType::NamedPipe
-        },
+        libc::S_IFSOCK => FileType::Socket,
         _ => { panic!("unknown file type"); }
     }
 }
 
+// Convert seconds and nanoseconds since the epoch, as stat returns them, to a time.
+fn system_time(sec: i64, nsec: i64) -> SystemTime {
+    if sec >= 0 {
+        UNIX_EPOCH + Duration::new(sec as u64, nsec as u32)
+    } else {
+        UNIX_EPOCH - Duration::new((-sec) as u64, 0) + Duration::new(0, nsec as u32)
+    }
+}
+
+// Convert a time to seconds and nanoseconds since the epoch, as utimensat takes them.
+fn system_time_to_libc(time: SystemTime) -> libc::timespec {
+    let (sec, nsec) = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => (since.as_secs() as i64, since.subsec_nanos() as i64),
+        Err(e) => {
+            let before = e.duration();
+            match before.subsec_nanos() {
+                0 => (-(before.as_secs() as i64), 0),
+                nsec => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nsec as i64),
+            }
+        },
+    };
+    libc::timespec {
+        tv_sec: sec as libc::time_t,
+        tv_nsec: nsec as libc::c_long,
+    }
+}
+
 fn stat_to_fuse(stat: libc::stat64) -> FileAttr {
     let kind = mode_to_filetype(stat.st_mode);
 
     let mode = stat.st_mode & 0o7777; // st_mode encodes the type AND the mode.
 
     FileAttr {
-        ino: 0,
+        ino: stat.st_ino as u64,
         size: stat.st_size as u64,
         blocks: stat.st_blocks as u64,
-        atime: Timespec { sec: stat.st_atime as i64, nsec: stat.st_atime_nsec as i32 },
-        mtime: Timespec { sec: stat.st_mtime as i64, nsec: stat.st_mtime_nsec as i32 },
-        ctime: Timespec { sec: stat.st_ctime as i64, nsec: stat.st_ctime_nsec as i32 },
-        crtime: Timespec { sec: 0, nsec: 0 },
+        atime: system_time(stat.st_atime as i64, stat.st_atime_nsec as i64),
+        mtime: system_time(stat.st_mtime as i64, stat.st_mtime_nsec as i64),
+        ctime: system_time(stat.st_ctime as i64, stat.st_ctime_nsec as i64),
+        crtime: UNIX_EPOCH,
         kind: kind,
         perm: mode as u16,
         nlink: stat.st_nlink as u32,
         uid: stat.st_uid,
         gid: stat.st_gid,
         rdev: stat.st_rdev as u32,
+        blksize: stat.st_blksize as u32,
         flags: 0,
     }
 }
@@ -71,8 +156,8 @@ fn statfs_to_fuse(statfs: libc::statfs) -> Statfs {
         files: statfs.f_files,
         ffree: statfs.f_ffree,
         bsize: statfs.f_bsize as u32,
-        namelen: 0, // TODO
-        frsize: 0, // TODO
+        namelen: 255, // Mac OS X's statfs doesn't report this; MAXNAMLEN is 255.
+        frsize: statfs.f_bsize as u32, // f_bsize is the fundamental block size on Mac OS X.
     }
 }
 
@@ -114,9 +199,14 @@ impl PassthroughFS {
     }
 }
 
-const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+const TTL: Duration = Duration::from_secs(1);
 
 impl FilesystemMT for PassthroughFS {
+    // The file descriptor.
+    type FileHandle = u64;
+    // The DIR pointer.
+    type DirHandle = u64;
+
     fn init(&self, _req: RequestInfo) -> ResultEmpty {
         debug!("init");
         Ok(())
@@ -126,18 +216,18 @@ impl FilesystemMT for PassthroughFS {
         debug!("destroy");
     }
 
-    fn getattr(&self, _req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultGetattr {
+    fn getattr(&self, _req: RequestInfo, path: &Path, fh: Option<&u64>) -> ResultGetattr {
         debug!("getattr: {:?}", path);
 
-        if let Some(fh) = fh {
+        if let Some(&fh) = fh {
             match libc_wrappers::fstat(fh) {
                 Ok(stat) => Ok((TTL, stat_to_fuse(stat))),
-                Err(e) => Err(e)
+                Err(e) => Err(Errno(e))
             }
         } else {
             match self.stat_real(path) {
                 Ok(attr) => Ok((TTL, attr)),
-                Err(e) => Err(e.raw_os_error().unwrap())
+                Err(e) => Err(e.into())
             }
         }
     }
@@ -147,162 +237,121 @@ impl FilesystemMT for PassthroughFS {
 
         let path = PathBuf::from(parent).join(name);
         match self.stat_real(&path) {
-            Ok(attr) => Ok((TTL, attr, 0)),
+            Ok(attr) => Ok(Entry::new(TTL, attr)),
             Err(e) => {
                 error!("stat_real({:?}): {}", path, e);
-                Err(e.raw_os_error().unwrap())
+                Err(e.into())
             },
         }
     }
 
-    fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
+    fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen<u64> {
         let real = self.real_path(path);
         debug!("opendir: {:?}", real);
         match libc_wrappers::opendir(real) {
-            Ok(fh) => Ok((fh, 0)),
+            Ok(fh) => Ok((fh, OpenFlags::empty())),
             Err(e) => {
                 let ioerr = io::Error::from_raw_os_error(e);
                 error!("opendir({:?}): {}", path, ioerr);
-                Err(e)
+                Err(Errno(e))
             }
         }
     }
 
-    fn releasedir(&self, _req: RequestInfo, path: &Path, fh: u64, _flags: u32) -> ResultEmpty {
+    fn releasedir(&self, _req: RequestInfo, path: &Path, fh: &u64, _flags: u32) -> ResultEmpty {
         debug!("releasedir: {:?}", path);
-        libc_wrappers::closedir(fh)
+        libc_wrappers::closedir(*fh).map_err(Errno)
     }
 
-    fn readdir(&self, _req: RequestInfo, path: &Path, fh: u64, _offset: u64) -> ResultReaddir {
-        debug!("readdir: {:?}", path);
-        let mut entries: Vec<DirectoryEntry> = vec![];
+    fn readdir(&self, _req: RequestInfo, path: &Path, fh: &u64, offset: u64) -> ResultReaddir {
+        debug!("readdir: {:?} @ {}", path, offset);
 
-        if fh == 0 {
-            error!("readdir: missing fh");
-            return Err(libc::EINVAL);
-        }
-
-        loop {
-            match libc_wrappers::readdir(fh) {
-                Ok(Some(entry)) => {
-                    let name_c = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) };
-                    let name_path = PathBuf::from(OsStr::from_bytes(name_c.to_bytes()));
-
-                    let filetype = match entry.d_type {
-                        libc::DT_DIR => FileType::Directory,
-                        libc::DT_REG => FileType::RegularFile,
-                        libc::DT_LNK => FileType::Symlink,
-                        libc::DT_BLK => FileType::BlockDevice,
-                        libc::DT_CHR => FileType::CharDevice,
-                        libc::DT_FIFO => FileType::NamedPipe,
-                        libc::DT_SOCK => {
-                            warn!("FUSE doesn't support Socket file type; translating to NamedPipe instead.");
-                            FileType::NamedPipe
-                        },
-                        0 | _ => {
-                            let entry_path = PathBuf::from(path).join(&name_path);
-                            let real_path = self.real_path(&entry_path);
-                            match libc_wrappers::lstat(real_path) {
-                                Ok(stat64) => mode_to_filetype(stat64.st_mode),
-                                Err(errno) => {
-                                    let ioerr = io::Error::from_raw_os_error(errno);
-                                    panic!("lstat failed after readdir_r gave no file type for {:?}: {}",
-                                           entry_path, ioerr);
-                                }
-                            }
-                        }
-                    };
-
-                    entries.push(DirectoryEntry {
-                        name: name_path,
-                        kind: filetype,
-                    })
-                },
-                Ok(None) => { break; },
-                Err(e) => {
-                    error!("readdir: {:?}: {}", path, e);
-                    return Err(e);
-                }
-            }
+        // Offsets are positions in the directory stream, from telldir after each entry, so pick up
+        // where the last entry left off.
+        if offset == 0 {
+            libc_wrappers::rewinddir(*fh);
+        } else {
+            libc_wrappers::seekdir(*fh, offset);
         }
-
-        Ok(entries)
+        let entries = DirectoryStream {
+            fh: *fh,
+            path: path.to_owned(),
+            real_path: PathBuf::from(self.real_path(path)),
+        };
+        Ok(Box::new(entries))
     }
 
-    fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
-        debug!("open: {:?} flags={:#x}", path, flags);
+    fn open(&self, _req: RequestInfo, path: &Path, flags: FileFlags) -> ResultOpen<u64> {
+        debug!("open: {:?} flags={:#x}", path, flags.raw());
 
         let real = self.real_path(path);
-        match libc_wrappers::open(real, flags as libc::c_int) {
-            Ok(fh) => Ok((fh, flags)),
+        match libc_wrappers::open(real, flags.raw() as libc::c_int) {
+            Ok(fh) => Ok((fh, OpenFlags::empty())),
             Err(e) => {
                 error!("open({:?}): {}", path, io::Error::from_raw_os_error(e));
-                Err(e)
+                Err(Errno(e))
             }
         }
     }
 
-    fn release(&self, _req: RequestInfo, path: &Path, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> ResultEmpty {
+    fn release(&self, _req: RequestInfo, path: &Path, fh: &u64, _flags: u32, _lock_owner: u64, _flush: bool) -> ResultEmpty {
         debug!("release: {:?}", path);
-        libc_wrappers::close(fh)
+        libc_wrappers::close(*fh).map_err(Errno)
     }
 
-    fn read(&self, _req: RequestInfo, path: &Path, fh: u64, offset: u64, size: u32) -> ResultData {
+    fn read(&self, _req: RequestInfo, path: &Path, fh: &u64, offset: u64, size: u32, callback: impl FnOnce(ResultSlice) -> CallbackResult) -> CallbackResult {
         debug!("read: {:?} {:#x} @ {:#x}", path, size, offset);
-        let mut file = unsafe { UnmanagedFile::new(fh) };
+        let mut file = unsafe { UnmanagedFile::new(*fh) };
 
-        let mut data = Vec::<u8>::with_capacity(size as usize);
-        unsafe { data.set_len(size as usize) };
+        let mut data = ReadBuffer::new(size as usize);
 
         if let Err(e) = file.seek(SeekFrom::Start(offset)) {
             error!("seek({:?}, {}): {}", path, offset, e);
-            return Err(e.raw_os_error().unwrap());
+            return callback(Err(e.into()));
         }
         match file.read(&mut data) {
-            Ok(n) => { data.truncate(n); },
+            Ok(n) => callback(Ok(&data[..n])),
             Err(e) => {
                 error!("read {:?}, {:#x} @ {:#x}: {}", path, size, offset, e);
-                return Err(e.raw_os_error().unwrap());
+                callback(Err(e.into()))
             }
         }
-
-        Ok(data)
     }
 
-    fn write(&self, _req: RequestInfo, path: &Path, fh: u64, offset: u64, data: &[u8], _flags: u32) -> ResultWrite {
+    fn write(&self, _req: RequestInfo, path: &Path, fh: &u64, offset: u64, data: &[u8], _flags: u32) -> ResultWrite {
         debug!("write: {:?} {:#x} @ {:#x}", path, data.len(), offset);
-        let mut file = unsafe { UnmanagedFile::new(fh) };
+        let mut file = unsafe { UnmanagedFile::new(*fh) };
 
         if let Err(e) = file.seek(SeekFrom::Start(offset)) {
             error!("seek({:?}, {}): {}", path, offset, e);
-            return Err(e.raw_os_error().unwrap());
+            return Err(e.into());
         }
         let nwritten: u32 = match file.write(data) {
             Ok(n) => n as u32,
             Err(e) => {
                 error!("write {:?}, {:#x} @ {:#x}: {}", path, data.len(), offset, e);
-                return Err(e.raw_os_error().unwrap());
+                return Err(e.into());
             }
         };
 
         Ok(nwritten)
     }
 
-    fn flush(&self, _req: RequestInfo, path: &Path, fh: u64, _lock_owner: u64) -> ResultEmpty {
+    fn flush(&self, _req: RequestInfo, path: &Path, fh: &u64, _lock_owner: u64) -> ResultEmpty {
         debug!("flush: {:?}", path);
-        let mut file = unsafe { UnmanagedFile::new(fh) };
+        let mut file = unsafe { UnmanagedFile::new(*fh) };
 
         if let Err(e) = file.flush() {
             error!("flush({:?}): {}", path, e);
-            return Err(e.raw_os_error().unwrap());
+            return Err(e.into());
         }
 
         Ok(())
     }
 
-    fn fsync(&self, _req: RequestInfo, path: &Path, fh: u64, datasync: bool) -> ResultEmpty {
+    fn fsync(&self, _req: RequestInfo, path: &Path, fh: &u64, datasync: bool) -> ResultEmpty {
         debug!("fsync: {:?}, data={:?}", path, datasync);
-        let file = unsafe { UnmanagedFile::new(fh) };
+        let file = unsafe { UnmanagedFile::new(*fh) };
 
         if let Err(e) = if datasync {
             file.sync_data()
@@ -310,16 +359,16 @@ impl FilesystemMT for PassthroughFS {
             file.sync_all()
         } {
             error!("fsync({:?}, {:?}): {}", path, datasync, e);
-            return Err(e.raw_os_error().unwrap());
+            return Err(e.into());
         }
 
         Ok(())
     }
 
-    fn chmod(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, mode: u32) -> ResultEmpty {
+    fn chmod(&self, _req: RequestInfo, path: &Path, fh: Option<&u64>, mode: u32) -> ResultEmpty {
         debug!("chown: {:?} to {:#o}", path, mode);
 
-        let result = if let Some(fh) = fh {
+        let result = if let Some(&fh) = fh {
             unsafe { libc::fchmod(fh as libc::c_int, mode as libc::mode_t) }
         } else {
             let real = self.real_path(path);
@@ -332,18 +381,18 @@ impl FilesystemMT for PassthroughFS {
         if -1 == result {
             let e = io::Error::last_os_error();
             error!("chown({:?}, {:#o}): {}", path, mode, e);
-            Err(e.raw_os_error().unwrap())
+            Err(e.into())
         } else {
             Ok(())
         }
     }
 
-    fn chown(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, uid: Option<u32>, gid: Option<u32>) -> ResultEmpty {
+    fn chown(&self, _req: RequestInfo, path: &Path, fh: Option<&u64>, uid: Option<u32>, gid: Option<u32>) -> ResultEmpty {
         let uid = uid.unwrap_or(::std::u32::MAX);   // docs say "-1", but uid_t is unsigned
         let gid = gid.unwrap_or(::std::u32::MAX);   // ditto for gid_t
         debug!("chmod: {:?} to {}:{}", path, uid, gid);
 
-        let result = if let Some(fd) = fh {
+        let result = if let Some(&fd) = fh {
             unsafe { libc::fchown(fd as libc::c_int, uid, gid) }
         } else {
             let real = self.real_path(path);
@@ -356,16 +405,16 @@ impl FilesystemMT for PassthroughFS {
         if -1 == result {
             let e = io::Error::last_os_error();
             error!("chmod({:?}, {}, {}): {}", path, uid, gid, e);
-            Err(e.raw_os_error().unwrap())
+            Err(e.into())
         } else {
             Ok(())
         }
     }
 
-    fn truncate(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, size: u64) -> ResultEmpty {
+    fn truncate(&self, _req: RequestInfo, path: &Path, fh: Option<&u64>, size: u64) -> ResultEmpty {
         debug!("truncate: {:?} to {:#x}", path, size);
 
-        let result = if let Some(fd) = fh {
+        let result = if let Some(&fd) = fh {
             unsafe { libc::ftruncate64(fd as libc::c_int, size as i64) }
         } else {
             let real = self.real_path(path);
@@ -378,33 +427,33 @@ impl FilesystemMT for PassthroughFS {
         if -1 == result {
             let e = io::Error::last_os_error();
             error!("truncate({:?}, {}): {}", path, size, e);
-            Err(e.raw_os_error().unwrap())
+            Err(e.into())
         } else {
             Ok(())
         }
     }
 
-    fn utimens(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, atime: Option<Timespec>, mtime: Option<Timespec>) -> ResultEmpty {
+    fn utimens(&self, _req: RequestInfo, path: &Path, fh: Option<&u64>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>) -> ResultEmpty {
         debug!("utimens: {:?}: {:?}, {:?}", path, atime, mtime);
 
 
-        fn timespec_to_libc(time: Option<Timespec>) -> libc::timespec {
-            if let Some(time) = time {
-                libc::timespec {
-                    tv_sec: time.sec as libc::time_t,
-                    tv_nsec: time.nsec as libc::time_t,
-                }
-            } else {
-                libc::timespec {
+        fn timespec_to_libc(time: Option<TimeOrNow>) -> libc::timespec {
+            match time {
+                Some(TimeOrNow::SpecificTime(time)) => system_time_to_libc(time),
+                Some(TimeOrNow::Now) => libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: libc::UTIME_NOW,
+                },
+                None => libc::timespec {
                     tv_sec: 0,
                     tv_nsec: libc::UTIME_OMIT,
-                }
+                },
             }
         }
 
         let times = [timespec_to_libc(atime), timespec_to_libc(mtime)];
 
-        let result = if let Some(fd) = fh {
+        let result = if let Some(&fd) = fh {
             unsafe { libc::futimens(fd as libc::c_int, &times as *const libc::timespec) }
         } else {
             let real = self.real_path(path);
@@ -417,7 +466,7 @@ impl FilesystemMT for PassthroughFS {
         if -1 == result {
             let e = io::Error::last_os_error();
             error!("utimens({:?}, {:?}, {:?}): {}", path, atime, mtime, e);
-            Err(e.raw_os_error().unwrap())
+            Err(e.into())
         } else {
             Ok(())
         }
@@ -429,7 +478,7 @@ impl FilesystemMT for PassthroughFS {
         let real = self.real_path(path);
         match ::std::fs::read_link(real) {
             Ok(target) => Ok(target.into_os_string().into_vec()),
-            Err(e) => Err(e.raw_os_error().unwrap()),
+            Err(e) => Err(e.into()),
         }
     }
 
@@ -446,21 +495,22 @@ impl FilesystemMT for PassthroughFS {
         if -1 == result {
             let e = io::Error::last_os_error();
             error!("statfs({:?}): {}", path, e);
-            Err(e.raw_os_error().unwrap())
+            Err(e.into())
         } else {
             Ok(statfs_to_fuse(buf))
         }
     }
 
-    fn fsyncdir(&self, _req: RequestInfo, path: &Path, fh: u64, datasync: bool) -> ResultEmpty {
+    fn fsyncdir(&self, _req: RequestInfo, path: &Path, fh: &u64, datasync: bool) -> ResultEmpty {
         debug!("fsyncdir: {:?} (datasync = {:?})", path, datasync);
 
         // TODO: what does datasync mean with regards to a directory handle?
-        let result = unsafe { libc::fsync(fh as libc::c_int) };
+        // fh is a DIR pointer, from opendir.
+        let result = unsafe { libc::fsync(libc::dirfd(*fh as *mut libc::DIR)) };
         if -1 == result {
             let e = io::Error::last_os_error();
             error!("fsyncdir({:?}): {}", path, e);
-            Err(e.raw_os_error().unwrap())
+            Err(e.into())
         } else {
             Ok(())
         }
@@ -478,11 +528,11 @@ impl FilesystemMT for PassthroughFS {
         if -1 == result {
             let e = io::Error::last_os_error();
             error!("mknod({:?}, {}, {}): {}", real, mode, rdev, e);
-            Err(e.raw_os_error().unwrap())
+            Err(e.into())
         } else {
             match libc_wrappers::lstat(real.into_os_string()) {
-                Ok(attr) => Ok((TTL, stat_to_fuse(attr), 0)),
-                Err(e) => Err(e),   // if this happens, yikes
+                Ok(attr) => Ok(Entry::new(TTL, stat_to_fuse(attr))),
+                Err(e) => Err(Errno(e)),   // if this happens, yikes
             }
         }
     }
@@ -499,13 +549,13 @@ impl FilesystemMT for PassthroughFS {
         if -1 == result {
             let e = io::Error::last_os_error();
             error!("mkdir({:?}, {:#o}): {}", real, mode, e);
-            Err(e.raw_os_error().unwrap())
+            Err(e.into())
         } else {
             match libc_wrappers::lstat(real.clone().into_os_string()) {
-                Ok(attr) => Ok((TTL, stat_to_fuse(attr), 0)),
+                Ok(attr) => Ok(Entry::new(TTL, stat_to_fuse(attr))),
                 Err(e) => {
                     error!("lstat after mkdir({:?}, {:#o}): {}", real, mode, e);
-                    Err(e)   // if this happens, yikes
+                    Err(Errno(e))   // if this happens, yikes
                 },
             }
         }
@@ -518,7 +568,7 @@ impl FilesystemMT for PassthroughFS {
         fs::remove_file(&real)
             .map_err(|ioerr| {
                 error!("unlink({:?}): {}", real, ioerr);
-                ioerr.raw_os_error().unwrap()
+                Errno::from(ioerr)
             })
     }
 
@@ -529,7 +579,7 @@ impl FilesystemMT for PassthroughFS {
         fs::remove_dir(&real)
             .map_err(|ioerr| {
                 error!("rmdir({:?}): {}", real, ioerr);
-                ioerr.raw_os_error().unwrap()
+                Errno::from(ioerr)
             })
     }
 
@@ -540,16 +590,16 @@ impl FilesystemMT for PassthroughFS {
         match ::std::os::unix::fs::symlink(target, &real) {
             Ok(()) => {
                 match libc_wrappers::lstat(real.clone().into_os_string()) {
-                    Ok(attr) => Ok((TTL, stat_to_fuse(attr), 0)),
+                    Ok(attr) => Ok(Entry::new(TTL, stat_to_fuse(attr))),
                     Err(e) => {
                         error!("lstat after symlink({:?}, {:?}): {}", real, target, e);
-                        Err(e)
+                        Err(Errno(e))
                     },
                 }
             },
             Err(e) => {
                 error!("symlink({:?}, {:?}): {}", real, target, e);
-                Err(e.raw_os_error().unwrap())
+                Err(e.into())
             }
         }
     }
@@ -562,7 +612,7 @@ impl FilesystemMT for PassthroughFS {
         fs::rename(&real, &newreal)
             .map_err(|ioerr| {
                 error!("rename({:?}, {:?}): {}", real, newreal, ioerr);
-                ioerr.raw_os_error().unwrap()
+                Errno::from(ioerr)
             })
     }
 
@@ -574,33 +624,33 @@ impl FilesystemMT for PassthroughFS {
         match fs::hard_link(&real, &newreal) {
             Ok(()) => {
                 match libc_wrappers::lstat(real.clone()) {
-                    Ok(attr) => Ok((TTL, stat_to_fuse(attr), 0)),
+                    Ok(attr) => Ok(Entry::new(TTL, stat_to_fuse(attr))),
                     Err(e) => {
                         error!("lstat after link({:?}, {:?}): {}", real, newreal, e);
-                        Err(e)
+                        Err(Errno(e))
                     },
                 }
             },
             Err(e) => {
                 error!("link({:?}, {:?}): {}", real, newreal, e);
-                Err(e.raw_os_error().unwrap())
+                Err(e.into())
             },
         }
     }
 
-    fn create(&self, _req: RequestInfo, parent: &Path, name: &OsStr, mode: u32, flags: u32) -> ResultCreate {
-        debug!("create: {:?}/{:?} (mode={:#o}, flags={:#x})", parent, name, mode, flags);
+    fn create(&self, _req: RequestInfo, parent: &Path, name: &OsStr, mode: u32, flags: FileFlags) -> ResultCreate<u64> {
+        debug!("create: {:?}/{:?} (mode={:#o}, flags={:#x})", parent, name, mode, flags.raw());
 
         let real = PathBuf::from(self.real_path(parent)).join(name);
         let fd = unsafe {
             let real_c = CString::from_vec_unchecked(real.clone().into_os_string().into_vec());
-            libc::open(real_c.as_ptr(), flags as i32 | libc::O_CREAT | libc::O_EXCL, mode)
+            libc::open(real_c.as_ptr(), flags.raw() as i32 | libc::O_CREAT | libc::O_EXCL, mode)
         };
 
         if -1 == fd {
             let ioerr = io::Error::last_os_error();
             error!("create({:?}): {}", real, ioerr);
-            Err(ioerr.raw_os_error().unwrap())
+            Err(ioerr.into())
         } else {
             match libc_wrappers::lstat(real.clone().into_os_string()) {
                 Ok(attr) => Ok(CreatedEntry {
@@ -608,11 +658,11 @@ impl FilesystemMT for PassthroughFS {
                     attr: stat_to_fuse(attr),
                     generation: 0,
                     fh: fd as u64,
-                    flags: flags,
+                    flags: OpenFlags::empty(),
                 }),
                 Err(e) => {
                     error!("lstat after create({:?}): {}", real, io::Error::from_raw_os_error(e));
-                    Err(e)
+                    Err(Errno(e))
                 },
             }
 
@@ -636,6 +686,12 @@ impl FilesystemMT for PassthroughFS {
         }
     }
 
+    fn setxattr(&self, _req: RequestInfo, path: &Path, name: &OsStr, value: &[u8], flags: u32, position: u32) -> ResultEmpty {
+        debug!("setxattr: {:?} {:?} {} bytes, flags = {:#x}, pos = {}", path, name, value.len(), flags, position);
+        let real = self.real_path(path);
+        libc_wrappers::lsetxattr(real, name.to_owned(), value, flags, position).map_err(Errno)
+    }
+
     fn getxattr(&self, _req: RequestInfo, path: &Path, name: &OsStr, size: u32) -> ResultXattr {
         debug!("getxattr: {:?} {:?} {}", path, name, size);
 
@@ -652,6 +708,12 @@ impl FilesystemMT for PassthroughFS {
             Ok(Xattr::Size(nbytes as u32))
         }
     }
+
+    fn removexattr(&self, _req: RequestInfo, path: &Path, name: &OsStr) -> ResultEmpty {
+        debug!("removexattr: {:?} {:?}", path, name);
+        let real = self.real_path(path);
+        libc_wrappers::lremovexattr(real, name.to_owned()).map_err(Errno)
+    }
 }
 
 /// A file that is not closed upon leaving scope.
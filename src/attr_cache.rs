@@ -0,0 +1,298 @@
+// AttrCacheFs :: A wrapper around a filesystem that caches the results of getattr, for
+//                filesystems whose metadata is slow to get.
+//
+// Copyright (c) 2016 by William R. Fraser
+//
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+
+use executor::{self, BoxFuture};
+use fusemt::*;
+
+// Don't bother dropping expired entries until there are at least this many.
+const MIN_SWEEP: usize = 1024;
+
+struct Entries {
+    // Sorted by path, so that everything under a directory comes right after it.
+    attrs: BTreeMap<PathBuf, (Instant, Duration, FileAttr)>,
+    // How many entries there were after expired ones were last dropped.
+    swept_len: usize,
+    // Counts invalidations, so that attributes a `getattr` got from before one aren't cached
+    // after it.
+    epoch: u64,
+}
+
+// The cached attributes, which are shared with the futures of asynchronous operations.
+struct AttrCache {
+    ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl AttrCache {
+    // Get the cached attributes of a path, with what's left of the time the kernel may cache them
+    // for.
+    fn get(&self, path: &Path) -> Option<(Duration, FileAttr)> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.attrs.get(path) {
+            Some(&(cached, ttl, attr)) if cached.elapsed() < self.ttl => {
+                return Some((ttl.saturating_sub(cached.elapsed()), attr));
+            },
+            Some(_) => (),
+            None => return None,
+        }
+        entries.attrs.remove(path);
+        None
+    }
+
+    // Get the epoch to pass to `insert` for attributes that are about to be fetched.
+    fn epoch(&self) -> u64 {
+        self.entries.lock().unwrap().epoch
+    }
+
+    // Cache the attributes of a path, unless something was invalidated since they were fetched,
+    // in which case they may be out of date.
+    fn insert(&self, path: &Path, (ttl, attr): (Duration, FileAttr), epoch: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.epoch != epoch {
+            return;
+        }
+        entries.attrs.insert(path.to_owned(), (Instant::now(), ttl, attr));
+
+        // Drop the expired entries every so often, so that paths that are never looked at again
+        // don't pile up.
+        if entries.attrs.len() >= MIN_SWEEP && entries.attrs.len() >= 2 * entries.swept_len {
+            let max_age = self.ttl;
+            entries.attrs.retain(|_, &mut (cached, _, _)| cached.elapsed() < max_age);
+            entries.swept_len = entries.attrs.len();
+        }
+    }
+
+    // Forget the attributes of a path.
+    fn invalidate(&self, path: &Path) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.epoch += 1;
+        entries.attrs.remove(path);
+    }
+
+    // Forget the attributes of a directory entry, everything under it (in case it's a directory
+    // that was moved or removed), and its parent, whose times and link count change along with
+    // its entries.
+    fn invalidate_entry(&self, parent: &Path, name: &OsStr) {
+        let path = parent.join(name);
+        let mut entries = self.entries.lock().unwrap();
+        entries.epoch += 1;
+        entries.attrs.remove(parent);
+        let stale: Vec<PathBuf> = entries.attrs.range(path.clone()..)
+            .map(|(cached_path, _)| cached_path)
+            .take_while(|cached_path| cached_path.starts_with(&path))
+            .cloned()
+            .collect();
+        for stale_path in stale {
+            entries.attrs.remove(&stale_path);
+        }
+    }
+}
+
+/// Wraps a filesystem, caching the attributes it returns from `getattr` for a while, so that a
+/// slow metadata backend (e.g. an object store) isn't asked for the same ones over and over.
+///
+/// Operations that go through the wrapper and change attributes (`setattr`, `write`, `rename`,
+/// `unlink`, etc.) drop the attributes they affect from the cache. Changes made some other way
+/// (e.g. directly on the backend) are only seen once the cached attributes expire. Attributes
+/// looked up by file handle (for files that have been deleted) aren't cached. Cached attributes
+/// are returned with what's left of the time the filesystem said the kernel may cache them for.
+pub struct AttrCacheFs<T> {
+    inner: Arc<T>,
+    cache: Arc<AttrCache>,
+}
+
+impl<T: FilesystemMT + Sync + Send + 'static> AttrCacheFs<T> {
+    /// Wrap a filesystem, caching attributes for the given time.
+    pub fn new(inner: T, ttl: Duration) -> AttrCacheFs<T> {
+        AttrCacheFs {
+            inner: Arc::new(inner),
+            cache: Arc::new(AttrCache {
+                ttl: ttl,
+                entries: Mutex::new(Entries {
+                    attrs: BTreeMap::new(),
+                    swept_len: 0,
+                    epoch: 0,
+                }),
+            }),
+        }
+    }
+}
+
+impl<T: FilesystemMT + Sync + Send + 'static> FilesystemMT for AttrCacheFs<T> {
+    type FileHandle = T::FileHandle;
+    type DirHandle = T::DirHandle;
+
+    fn getattr(&self, req: RequestInfo, path: &Path, fh: Option<&Self::FileHandle>) -> ResultGetattr {
+        if fh.is_none() {
+            if let Some(cached) = self.cache.get(path) {
+                return Ok(cached);
+            }
+        }
+        let epoch = self.cache.epoch();
+        let result = self.inner.getattr(req, path, fh);
+        if let (None, Ok(attr)) = (fh, result) {
+            self.cache.insert(path, attr, epoch);
+        }
+        result
+    }
+
+    fn setattr(&self, req: RequestInfo, path: &Path, fh: Option<&Self::FileHandle>, attrs: SetAttrRequest) -> ResultGetattr {
+        let result = self.inner.setattr(req, path, fh, attrs);
+        self.cache.invalidate(path);
+        result
+    }
+
+    fn chmod(&self, req: RequestInfo, path: &Path, fh: Option<&Self::FileHandle>, mode: u32) -> ResultEmpty {
+        let result = self.inner.chmod(req, path, fh, mode);
+        self.cache.invalidate(path);
+        result
+    }
+
+    fn chown(&self, req: RequestInfo, path: &Path, fh: Option<&Self::FileHandle>, uid: Option<u32>, gid: Option<u32>) -> ResultEmpty {
+        let result = self.inner.chown(req, path, fh, uid, gid);
+        self.cache.invalidate(path);
+        result
+    }
+
+    fn truncate(&self, req: RequestInfo, path: &Path, fh: Option<&Self::FileHandle>, size: u64) -> ResultEmpty {
+        let result = self.inner.truncate(req, path, fh, size);
+        self.cache.invalidate(path);
+        result
+    }
+
+    fn utimens(&self, req: RequestInfo, path: &Path, fh: Option<&Self::FileHandle>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>) -> ResultEmpty {
+        let result = self.inner.utimens(req, path, fh, atime, mtime);
+        self.cache.invalidate(path);
+        result
+    }
+
+    fn utimens_macos(&self, req: RequestInfo, path: &Path, fh: Option<&Self::FileHandle>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>) -> ResultEmpty {
+        let result = self.inner.utimens_macos(req, path, fh, crtime, chgtime, bkuptime, flags);
+        self.cache.invalidate(path);
+        result
+    }
+
+    fn mknod(&self, req: RequestInfo, parent: &Path, name: &OsStr, mode: u32, rdev: u32) -> ResultEntry {
+        let result = self.inner.mknod(req, parent, name, mode, rdev);
+        self.cache.invalidate_entry(parent, name);
+        result
+    }
+
+    fn mkdir(&self, req: RequestInfo, parent: &Path, name: &OsStr, mode: u32) -> ResultEntry {
+        let result = self.inner.mkdir(req, parent, name, mode);
+        self.cache.invalidate_entry(parent, name);
+        result
+    }
+
+    fn unlink(&self, req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+        let result = self.inner.unlink(req, parent, name);
+        self.cache.invalidate_entry(parent, name);
+        result
+    }
+
+    fn rmdir(&self, req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+        let result = self.inner.rmdir(req, parent, name);
+        self.cache.invalidate_entry(parent, name);
+        result
+    }
+
+    fn symlink(&self, req: RequestInfo, parent: &Path, name: &OsStr, target: &Path) -> ResultEntry {
+        let result = self.inner.symlink(req, parent, name, target);
+        self.cache.invalidate_entry(parent, name);
+        result
+    }
+
+    fn rename(&self, req: RequestInfo, parent: &Path, name: &OsStr, newparent: &Path, newname: &OsStr) -> ResultEmpty {
+        let result = self.inner.rename(req, parent, name, newparent, newname);
+        self.cache.invalidate_entry(parent, name);
+        self.cache.invalidate_entry(newparent, newname);
+        result
+    }
+
+    fn link(&self, req: RequestInfo, path: &Path, newparent: &Path, newname: &OsStr) -> ResultEntry {
+        let result = self.inner.link(req, path, newparent, newname);
+        self.cache.invalidate(path);
+        self.cache.invalidate_entry(newparent, newname);
+        result
+    }
+
+    fn write(&self, req: RequestInfo, path: &Path, fh: &Self::FileHandle, offset: u64, data: &[u8], flags: u32) -> ResultWrite {
+        let result = self.inner.write(req, path, fh, offset, data, flags);
+        self.cache.invalidate(path);
+        result
+    }
+
+    fn setxattr(&self, req: RequestInfo, path: &Path, name: &OsStr, value: &[u8], flags: u32, position: u32) -> ResultEmpty {
+        let result = self.inner.setxattr(req, path, name, value, flags, position);
+        self.cache.invalidate(path);
+        result
+    }
+
+    fn removexattr(&self, req: RequestInfo, path: &Path, name: &OsStr) -> ResultEmpty {
+        let result = self.inner.removexattr(req, path, name);
+        self.cache.invalidate(path);
+        result
+    }
+
+    fn create(&self, req: RequestInfo, parent: &Path, name: &OsStr, mode: u32, flags: FileFlags) -> ResultCreate<Self::FileHandle> {
+        let result = self.inner.create(req, parent, name, mode, flags);
+        self.cache.invalidate_entry(parent, name);
+        result
+    }
+
+    #[cfg(target_os = "macos")]
+    fn exchange(&self, req: RequestInfo, parent: &Path, name: &OsStr, newparent: &Path, newname: &OsStr, options: u64) -> ResultEmpty {
+        let result = self.inner.exchange(req, parent, name, newparent, newname, options);
+        self.cache.invalidate_entry(parent, name);
+        self.cache.invalidate_entry(newparent, newname);
+        result
+    }
+
+    fn write_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, offset: u64, data: Vec<u8>, flags: u32) -> BoxFuture<ResultWrite> {
+        let cache = self.cache.clone();
+        let future = self.inner.clone().write_async(req, path.clone(), fh, offset, data, flags);
+        executor::then(future, move|result| {
+            cache.invalidate(&path);
+            result
+        })
+    }
+
+    fn getattr_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Option<Arc<Self::FileHandle>>) -> BoxFuture<ResultGetattr> {
+        if fh.is_some() {
+            return self.inner.clone().getattr_async(req, path, fh);
+        }
+        if let Some(cached) = self.cache.get(&path) {
+            return Box::pin(future::ready(Ok(cached)));
+        }
+        let cache = self.cache.clone();
+        let epoch = cache.epoch();
+        let future = self.inner.clone().getattr_async(req, path.clone(), fh);
+        executor::then(future, move|result| {
+            if let Ok(attr) = result {
+                cache.insert(&path, attr, epoch);
+            }
+            result
+        })
+    }
+
+    forward! {
+        handles: same,
+        methods: [
+            init, destroy, lookup, readlink, open, read, flush, release, fsync, opendir, readdir,
+            releasedir, fsyncdir, statfs, getxattr, listxattr, access, getlk, setlk, bmap,
+            setvolname, getxtimes, read_async, flush_async, fsync_async, readdir_async,
+            setlk_async,
+        ],
+    }
+}
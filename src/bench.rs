@@ -0,0 +1,242 @@
+// Bench :: Mounts a filesystem in a temporary directory and times a set of standard workloads
+//          against it, so that changes to the dispatcher can be measured the same way each time.
+//
+// Copyright (c) 2016 by William R. Fraser
+//
+
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use fusemt::*;
+use mount::spawn_mount;
+
+/// A workload to run against the mounted filesystem. Each one works in its own file or directory
+/// under the root of the filesystem, and removes it again when it's done.
+#[derive(Clone, Debug)]
+pub enum Workload {
+    /// Write a file of `file_size` bytes, `block_size` bytes at a time. One operation is one write.
+    SequentialWrite { file_size: u64, block_size: usize },
+
+    /// Write a file of `file_size` bytes (untimed), then read it back `block_size` bytes at a
+    /// time. One operation is one read.
+    SequentialRead { file_size: u64, block_size: usize },
+
+    /// Create `count` empty files in one directory. One operation is creating and closing a file.
+    CreateFiles { count: usize },
+
+    /// Make a tree of directories `depth` levels deep, each holding `width` files and (except at
+    /// the bottom) one subdirectory (untimed), then list every directory in it from the top down.
+    /// One operation is listing one directory to the end.
+    Readdir { depth: usize, width: usize },
+}
+
+impl Workload {
+    /// The workloads run by `run_standard`.
+    pub fn standard() -> Vec<Workload> {
+        vec![
+            Workload::SequentialWrite { file_size: 64 * 1024 * 1024, block_size: 128 * 1024 },
+            Workload::SequentialRead { file_size: 64 * 1024 * 1024, block_size: 128 * 1024 },
+            Workload::CreateFiles { count: 10_000 },
+            Workload::Readdir { depth: 32, width: 1000 },
+        ]
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Workload::SequentialWrite { .. } => "sequential write",
+            Workload::SequentialRead { .. } => "sequential read",
+            Workload::CreateFiles { .. } => "create files",
+            Workload::Readdir { .. } => "readdir",
+        }
+    }
+
+    fn run(&self, root: &Path) -> io::Result<Report> {
+        let mut report = Report::new(self.clone());
+        match *self {
+            Workload::SequentialWrite { file_size, block_size } => {
+                let path = root.join("sequential-write");
+                let start = Instant::now();
+                write_file(&path, file_size, block_size, Some(&mut report))?;
+                report.elapsed = start.elapsed();
+                fs::remove_file(&path)?;
+            },
+            Workload::SequentialRead { file_size, block_size } => {
+                let path = root.join("sequential-read");
+                write_file(&path, file_size, block_size, None)?;
+                let start = Instant::now();
+                let mut file = File::open(&path)?;
+                let mut buf = vec![0u8; block_size];
+                loop {
+                    let op = Instant::now();
+                    let n = file.read(&mut buf)?;
+                    if n == 0 {
+                        break;
+                    }
+                    report.record(op);
+                }
+                report.elapsed = start.elapsed();
+                drop(file);
+                fs::remove_file(&path)?;
+            },
+            Workload::CreateFiles { count } => {
+                let dir = root.join("create-files");
+                fs::create_dir(&dir)?;
+                let start = Instant::now();
+                for i in 0 .. count {
+                    let op = Instant::now();
+                    File::create(dir.join(i.to_string()))?;
+                    report.record(op);
+                }
+                report.elapsed = start.elapsed();
+                fs::remove_dir_all(&dir)?;
+            },
+            Workload::Readdir { depth, width } => {
+                let top = root.join("readdir");
+                let mut dir = top.clone();
+                let mut dirs = vec![];
+                for _ in 0 .. depth {
+                    fs::create_dir(&dir)?;
+                    for i in 0 .. width {
+                        File::create(dir.join(i.to_string()))?;
+                    }
+                    dirs.push(dir.clone());
+                    dir.push("d");
+                }
+                let start = Instant::now();
+                for dir in &dirs {
+                    let op = Instant::now();
+                    for entry in fs::read_dir(dir)? {
+                        entry?;
+                    }
+                    report.record(op);
+                }
+                report.elapsed = start.elapsed();
+                if !dirs.is_empty() {
+                    fs::remove_dir_all(&top)?;
+                }
+            },
+        }
+        Ok(report)
+    }
+}
+
+fn write_file(path: &Path, size: u64, block_size: usize, mut report: Option<&mut Report>)
+        -> io::Result<()> {
+    let mut file = File::create(path)?;
+    let buf = vec![0xa5u8; block_size];
+    let mut written = 0;
+    while written < size {
+        let n = ::std::cmp::min(block_size as u64, size - written) as usize;
+        let op = Instant::now();
+        file.write_all(&buf[.. n])?;
+        if let Some(ref mut report) = report {
+            report.record(op);
+        }
+        written += n as u64;
+    }
+    Ok(())
+}
+
+/// How long a workload took.
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub workload: Workload,
+
+    /// How long the timed part of the workload took altogether.
+    pub elapsed: Duration,
+
+    /// How long each operation took, in the order they were done.
+    pub latencies: Vec<Duration>,
+}
+
+impl Report {
+    fn new(workload: Workload) -> Report {
+        Report {
+            workload: workload,
+            elapsed: Duration::from_secs(0),
+            latencies: vec![],
+        }
+    }
+
+    fn record(&mut self, start: Instant) {
+        self.latencies.push(start.elapsed());
+    }
+
+    /// The number of operations done.
+    pub fn ops(&self) -> usize {
+        self.latencies.len()
+    }
+
+    /// The number of operations done per second.
+    pub fn ops_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 / 1e9;
+        if secs == 0. {
+            0.
+        } else {
+            self.ops() as f64 / secs
+        }
+    }
+
+    /// The latency that the given percentage (0 to 100) of operations were at least as fast as.
+    pub fn percentile(&self, percent: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::from_secs(0);
+        }
+        let mut sorted = self.latencies.clone();
+        sorted.sort();
+        let rank = (percent / 100. * (sorted.len() - 1) as f64).round() as usize;
+        sorted[::std::cmp::min(rank, sorted.len() - 1)]
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} ops in {:?} ({:.0} ops/sec); latency p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+               self.workload.name(), self.ops(), self.elapsed, self.ops_per_sec(),
+               self.percentile(50.), self.percentile(90.), self.percentile(99.),
+               self.percentile(100.))
+    }
+}
+
+static NEXT_MOUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Mount a filesystem in a new directory under the system's temporary directory, run the given
+/// workloads against it one after another, and unmount it again.
+///
+/// The filesystem has to be writable, and start out empty enough that the names the workloads use
+/// are free. Running it on top of something like the example passthrough filesystem pointed at a
+/// scratch directory measures the dispatcher plus the backing filesystem.
+pub fn run<T>(fs: FuseMT<T>, workloads: &[Workload]) -> io::Result<Vec<Report>>
+    where T: FilesystemMT + Sync + Send + 'static
+{
+    let mountpoint: PathBuf = env::temp_dir().join(format!("fuse_mt-bench.{}.{}",
+        process::id(), NEXT_MOUNT.fetch_add(1, Ordering::SeqCst)));
+    fs::create_dir(&mountpoint)?;
+
+    // Dropping the session at the end unmounts the filesystem.
+    let result = match spawn_mount(fs, &mountpoint, &[]) {
+        Ok(_session) => workloads.iter().map(|workload| {
+            info!("bench: running {:?}", workload);
+            workload.run(&mountpoint)
+        }).collect(),
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = fs::remove_dir(&mountpoint) {
+        warn!("bench: failed to remove {:?}: {}", mountpoint, e);
+    }
+    result
+}
+
+/// Run the standard set of workloads (see `Workload::standard`).
+pub fn run_standard<T>(fs: FuseMT<T>) -> io::Result<Vec<Report>>
+    where T: FilesystemMT + Sync + Send + 'static
+{
+    run(fs, &Workload::standard())
+}
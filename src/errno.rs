@@ -0,0 +1,60 @@
+// Errno :: The error type returned by filesystem operations.
+//
+// Copyright (c) 2016 by William R. Fraser
+//
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use libc;
+
+#[cfg(feature = "nix")]
+use nix;
+
+/// An error to reply to the kernel with, as an errno value (e.g. `libc::ENOENT`).
+///
+/// It can be made from a raw errno value (`Errno(libc::ENOENT)`, or `libc::ENOENT.into()`), or
+/// from an `io::Error` (with `?` or `into()`), which gives its OS error code, or EIO if it doesn't
+/// have one. With the `nix` feature, it can be made from a `nix::Error` too.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Errno(pub libc::c_int);
+
+impl From<libc::c_int> for Errno {
+    fn from(errno: libc::c_int) -> Errno {
+        Errno(errno)
+    }
+}
+
+impl From<Errno> for libc::c_int {
+    fn from(errno: Errno) -> libc::c_int {
+        errno.0
+    }
+}
+
+impl From<io::Error> for Errno {
+    fn from(e: io::Error) -> Errno {
+        Errno(e.raw_os_error().unwrap_or(libc::EIO))
+    }
+}
+
+impl From<Errno> for io::Error {
+    fn from(errno: Errno) -> io::Error {
+        io::Error::from_raw_os_error(errno.0)
+    }
+}
+
+#[cfg(feature = "nix")]
+impl From<nix::Error> for Errno {
+    fn from(e: nix::Error) -> Errno {
+        Errno(e as libc::c_int)
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&io::Error::from_raw_os_error(self.0), f)
+    }
+}
+
+impl Error for Errno {}
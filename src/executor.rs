@@ -0,0 +1,270 @@
+// Executor :: Drives the futures returned by the asynchronous operations of a filesystem, polling
+//             them on the thread pool whenever they are woken up.
+//
+// Copyright (c) 2016 by William R. Fraser
+//
+
+use std::any::Any;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+
+use libc;
+use threadpool::ThreadPool;
+
+use errno::Errno;
+
+/// A boxed future, as returned by the asynchronous operations of `FilesystemMT`.
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+// A future that hasn't completed yet, and the pool to poll it on when it's woken up.
+struct Task {
+    // None once the future has completed.
+    future: Mutex<Option<BoxFuture<()>>>,
+    pool: ThreadPool,
+}
+
+impl Task {
+    fn poll(self: Arc<Self>) {
+        // The lock is held while polling, so if the future is woken up in the meantime, the next
+        // poll waits for this one to finish.
+        let mut slot = self.future.lock().unwrap();
+        if let Some(mut future) = slot.take() {
+            let waker = Waker::from(self.clone());
+            let mut cx = Context::from_waker(&waker);
+            if future.as_mut().poll(&mut cx).is_pending() {
+                *slot = Some(future);
+            }
+        }
+    }
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        let pool = self.pool.clone();
+        pool.execute(move|| self.poll());
+    }
+}
+
+/// Run a future: poll it on the current thread first, and then on the pool's threads whenever it
+/// is woken up, until it completes.
+pub fn spawn(pool: &ThreadPool, future: BoxFuture<()>) {
+    let task = Arc::new(Task {
+        future: Mutex::new(Some(future)),
+        pool: pool.clone(),
+    });
+    task.poll();
+}
+
+/// Make a future that calls a function to get another future the first time it's polled, and
+/// then completes when that one does.
+pub fn lazy<F>(f: F) -> BoxFuture<()>
+    where F: FnOnce() -> BoxFuture<()> + Send + 'static
+{
+    Box::pin(Lazy::Start(Some(f)))
+}
+
+enum Lazy<F> {
+    Start(Option<F>),
+    Running(BoxFuture<()>),
+}
+
+// The function is never pinned, so this is fine even if it isn't Unpin itself.
+impl<F> Unpin for Lazy<F> {}
+
+impl<F: FnOnce() -> BoxFuture<()>> Future for Lazy<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let future = match *self {
+            Lazy::Start(ref mut f) => (f.take().expect("future polled after it completed"))(),
+            Lazy::Running(ref mut future) => return future.as_mut().poll(cx),
+        };
+        *self = Lazy::Running(future);
+        self.poll(cx)
+    }
+}
+
+/// Make a future that calls a function with the output of another future once it's ready, and
+/// completes with what the function returns.
+pub fn then<T, U, F>(future: BoxFuture<T>, f: F) -> BoxFuture<U>
+    where T: 'static,
+          F: FnOnce(T) -> U + Send + 'static
+{
+    Box::pin(Then {
+        future: future,
+        f: Some(f),
+    })
+}
+
+struct Then<T, F> {
+    future: BoxFuture<T>,
+    f: Option<F>,
+}
+
+// The function is never pinned, so this is fine even if it isn't Unpin itself.
+impl<T, F> Unpin for Then<T, F> {}
+
+impl<T, U, F: FnOnce(T) -> U> Future for Then<T, F> {
+    type Output = U;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<U> {
+        match self.future.as_mut().poll(cx) {
+            Poll::Ready(value) => {
+                let f = self.f.take().expect("future polled after it completed");
+                Poll::Ready(f(value))
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Make a future that calls a function with the output of another future once it's ready, and
+/// then completes when the future the function returns does.
+pub fn and_then<T, U, F>(future: BoxFuture<T>, f: F) -> BoxFuture<U>
+    where T: 'static,
+          U: 'static,
+          F: FnOnce(T) -> BoxFuture<U> + Send + 'static
+{
+    Box::pin(AndThen::First(future, Some(f)))
+}
+
+enum AndThen<T, U, F> {
+    First(BoxFuture<T>, Option<F>),
+    Second(BoxFuture<U>),
+}
+
+// The function is never pinned, so this is fine even if it isn't Unpin itself.
+impl<T, U, F> Unpin for AndThen<T, U, F> {}
+
+impl<T, U, F: FnOnce(T) -> BoxFuture<U>> Future for AndThen<T, U, F> {
+    type Output = U;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<U> {
+        let second = match *self {
+            AndThen::First(ref mut future, ref mut f) => match future.as_mut().poll(cx) {
+                Poll::Ready(value) => (f.take().expect("future polled after it completed"))(value),
+                Poll::Pending => return Poll::Pending,
+            },
+            AndThen::Second(ref mut future) => return future.as_mut().poll(cx),
+        };
+        *self = AndThen::Second(second);
+        self.poll(cx)
+    }
+}
+
+/// Make a future that completes with the output of another future, or if polling it panics, with
+/// what a function returns when called with the panic's payload.
+pub fn catch_unwind<T, F>(future: BoxFuture<T>, on_panic: F) -> BoxFuture<T>
+    where T: 'static,
+          F: FnOnce(Box<dyn Any + Send>) -> T + Send + 'static
+{
+    Box::pin(CatchUnwind {
+        future: future,
+        on_panic: Some(on_panic),
+    })
+}
+
+struct CatchUnwind<T, F> {
+    future: BoxFuture<T>,
+    on_panic: Option<F>,
+}
+
+// The function is never pinned, so this is fine even if it isn't Unpin itself.
+impl<T, F> Unpin for CatchUnwind<T, F> {}
+
+impl<T, F: FnOnce(Box<dyn Any + Send>) -> T> Future for CatchUnwind<T, F> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let polled = {
+            let future = &mut self.future;
+            panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx)))
+        };
+        match polled {
+            Ok(poll) => poll,
+            Err(payload) => {
+                let on_panic = self.on_panic.take().expect("future polled after it completed");
+                Poll::Ready(on_panic(payload))
+            },
+        }
+    }
+}
+
+/// Make a token for replying to an operation later, along with the future for an asynchronous
+/// operation (e.g. `read_async`) to return, which completes when the token is used.
+///
+/// The token can be sent to any thread (e.g. the one running a custom event loop, which uses it
+/// once a response arrives from a network backend). If it's dropped without being used, the
+/// operation fails with EIO.
+pub fn deferred<R: Send + 'static>() -> (ReplyToken<R>, BoxFuture<Result<R, Errno>>) {
+    let slot = Arc::new(Mutex::new(Slot {
+        result: None,
+        waker: None,
+    }));
+    let token = ReplyToken {
+        slot: Some(slot.clone()),
+    };
+    (token, Box::pin(Deferred { slot: slot }))
+}
+
+struct Slot<R> {
+    result: Option<Result<R, Errno>>,
+    waker: Option<Waker>,
+}
+
+/// Completes the operation it was made for; see `deferred`.
+pub struct ReplyToken<R> {
+    // None once it has been used.
+    slot: Option<Arc<Mutex<Slot<R>>>>,
+}
+
+impl<R> ReplyToken<R> {
+    /// Complete the operation with the given result.
+    pub fn reply(mut self, result: Result<R, Errno>) {
+        self.send(result);
+    }
+
+    fn send(&mut self, result: Result<R, Errno>) {
+        if let Some(slot) = self.slot.take() {
+            let waker = {
+                let mut slot = slot.lock().unwrap();
+                slot.result = Some(result);
+                slot.waker.take()
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<R> Drop for ReplyToken<R> {
+    fn drop(&mut self) {
+        if self.slot.is_some() {
+            warn!("reply token dropped without being used; replying with EIO");
+            self.send(Err(Errno(libc::EIO)));
+        }
+    }
+}
+
+struct Deferred<R> {
+    slot: Arc<Mutex<Slot<R>>>,
+}
+
+impl<R> Future for Deferred<R> {
+    type Output = Result<R, Errno>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<R, Errno>> {
+        let mut slot = self.slot.lock().unwrap();
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
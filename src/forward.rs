@@ -0,0 +1,342 @@
+// Forward :: Macros for implementing FilesystemMT on a wrapper around another filesystem, by
+//            passing the operations the wrapper doesn't change on to the one it wraps.
+//
+// Copyright (c) 2016 by William R. Fraser
+//
+
+// Implement the named operations of a wrapper by calling the same ones on `self.inner`, an
+// `Arc` of the wrapped filesystem, so that the wrapper only has to write out the ones it changes:
+//
+//     forward! {
+//         handles: same,
+//         methods: [init, destroy, lookup, read_async],
+//     }
+//
+// `handles` says how to get the wrapped filesystem's file handle from the wrapper's: `same` if the
+// wrapper uses the same handles, or `wrapped` if its handles keep the wrapped one in an `inner`
+// field, as an `Arc`. Directory handles are always passed on as they are.
+//
+// `via` optionally names macros to make the calls with instead of calling `self.inner` directly,
+// e.g. to measure them: one for synchronous operations, which is called as
+// `m!(self, op(args...), "format", args...)`, and one for asynchronous ones, which is called as
+// `m!(self, sync_op, op_async(args...), "format", args...)`. The format string and its arguments
+// describe the call, for logging. `destroy` has no result, so it's always called directly.
+macro_rules! forward {
+    (handles: $h:ident, methods: [$($method:ident),* $(,)*] $(,)*) => {
+        forward! {
+            handles: $h,
+            via: [forward_call, forward_call_async],
+            methods: [$($method),*],
+        }
+    };
+    (handles: $h:ident, via: [$via:ident, $via_async:ident], methods: [$($method:ident),* $(,)*] $(,)*) => {
+        $( forward_method!($method, $h, $via, $via_async); )*
+    };
+}
+
+// Call an operation of the wrapped filesystem.
+macro_rules! forward_call {
+    ($s:expr, $op:ident($($arg:expr),*), $($desc:tt)*) => {
+        $s.inner.$op($($arg),*)
+    }
+}
+
+// Call an asynchronous operation of the wrapped filesystem.
+macro_rules! forward_call_async {
+    ($s:expr, $name:ident, $op:ident($($arg:expr),*), $($desc:tt)*) => {
+        $s.inner.clone().$op($($arg),*)
+    }
+}
+
+// Get the wrapped filesystem's file handle from a wrapper's, passed by reference (`ref`), as an
+// `Option` of a reference (`opt`), as an `Arc` (`arc`), or as an `Option` of an `Arc` (`opt_arc`).
+macro_rules! forward_fh {
+    (same, $how:ident, $fh:expr) => { $fh };
+    (wrapped, ref, $fh:expr) => { &*$fh.inner };
+    (wrapped, opt, $fh:expr) => { $fh.map(|fh| &*fh.inner) };
+    (wrapped, arc, $fh:expr) => { $fh.inner.clone() };
+    (wrapped, opt_arc, $fh:expr) => { $fh.map(|fh| fh.inner.clone()) };
+}
+
+// One forwarded operation; see `forward!`.
+macro_rules! forward_method {
+    (init, $h:ident, $via:ident, $via_async:ident) => {
+        fn init(&self, req: $crate::RequestInfo) -> $crate::ResultEmpty {
+            $via!(self, init(req), "")
+        }
+    };
+    (destroy, $h:ident, $via:ident, $via_async:ident) => {
+        fn destroy(&self, req: $crate::RequestInfo) {
+            self.inner.destroy(req)
+        }
+    };
+    (lookup, $h:ident, $via:ident, $via_async:ident) => {
+        fn lookup(&self, req: $crate::RequestInfo, parent: &::std::path::Path, name: &::std::ffi::OsStr) -> $crate::ResultEntry {
+            $via!(self, lookup(req, parent, name), "{:?}, {:?}", parent, name)
+        }
+    };
+    (getattr, $h:ident, $via:ident, $via_async:ident) => {
+        fn getattr(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: Option<&Self::FileHandle>) -> $crate::ResultGetattr {
+            $via!(self, getattr(req, path, forward_fh!($h, opt, fh)), "{:?}{}", path,
+                  if fh.is_some() { " (by handle)" } else { "" })
+        }
+    };
+    (setattr, $h:ident, $via:ident, $via_async:ident) => {
+        fn setattr(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: Option<&Self::FileHandle>, attrs: $crate::SetAttrRequest) -> $crate::ResultGetattr {
+            $via!(self, setattr(req, path, forward_fh!($h, opt, fh), attrs), "{:?}, {:?}", path,
+                  attrs)
+        }
+    };
+    (chmod, $h:ident, $via:ident, $via_async:ident) => {
+        fn chmod(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: Option<&Self::FileHandle>, mode: u32) -> $crate::ResultEmpty {
+            $via!(self, chmod(req, path, forward_fh!($h, opt, fh), mode), "{:?}, mode={:#o}", path,
+                  mode)
+        }
+    };
+    (chown, $h:ident, $via:ident, $via_async:ident) => {
+        fn chown(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: Option<&Self::FileHandle>, uid: Option<u32>, gid: Option<u32>) -> $crate::ResultEmpty {
+            $via!(self, chown(req, path, forward_fh!($h, opt, fh), uid, gid),
+                  "{:?}, uid={:?}, gid={:?}", path, uid, gid)
+        }
+    };
+    (truncate, $h:ident, $via:ident, $via_async:ident) => {
+        fn truncate(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: Option<&Self::FileHandle>, size: u64) -> $crate::ResultEmpty {
+            $via!(self, truncate(req, path, forward_fh!($h, opt, fh), size), "{:?}, size={}", path,
+                  size)
+        }
+    };
+    (utimens, $h:ident, $via:ident, $via_async:ident) => {
+        fn utimens(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: Option<&Self::FileHandle>, atime: Option<$crate::TimeOrNow>, mtime: Option<$crate::TimeOrNow>) -> $crate::ResultEmpty {
+            $via!(self, utimens(req, path, forward_fh!($h, opt, fh), atime, mtime),
+                  "{:?}, atime={:?}, mtime={:?}", path, atime, mtime)
+        }
+    };
+    (utimens_macos, $h:ident, $via:ident, $via_async:ident) => {
+        fn utimens_macos(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: Option<&Self::FileHandle>, crtime: Option<::std::time::SystemTime>, chgtime: Option<::std::time::SystemTime>, bkuptime: Option<::std::time::SystemTime>, flags: Option<u32>) -> $crate::ResultEmpty {
+            $via!(self, utimens_macos(req, path, forward_fh!($h, opt, fh), crtime, chgtime,
+                                      bkuptime, flags),
+                  "{:?}, crtime={:?}, chgtime={:?}, bkuptime={:?}, flags={:?}", path, crtime,
+                  chgtime, bkuptime, flags)
+        }
+    };
+    (readlink, $h:ident, $via:ident, $via_async:ident) => {
+        fn readlink(&self, req: $crate::RequestInfo, path: &::std::path::Path) -> $crate::ResultData {
+            $via!(self, readlink(req, path), "{:?}", path)
+        }
+    };
+    (mknod, $h:ident, $via:ident, $via_async:ident) => {
+        fn mknod(&self, req: $crate::RequestInfo, parent: &::std::path::Path, name: &::std::ffi::OsStr, mode: u32, rdev: u32) -> $crate::ResultEntry {
+            $via!(self, mknod(req, parent, name, mode, rdev), "{:?}, {:?}, mode={:#o}, rdev={:?}",
+                  parent, name, mode, $crate::DeviceNumber::from_rdev(rdev))
+        }
+    };
+    (mkdir, $h:ident, $via:ident, $via_async:ident) => {
+        fn mkdir(&self, req: $crate::RequestInfo, parent: &::std::path::Path, name: &::std::ffi::OsStr, mode: u32) -> $crate::ResultEntry {
+            $via!(self, mkdir(req, parent, name, mode), "{:?}, {:?}, mode={:#o}", parent, name,
+                  mode)
+        }
+    };
+    (unlink, $h:ident, $via:ident, $via_async:ident) => {
+        fn unlink(&self, req: $crate::RequestInfo, parent: &::std::path::Path, name: &::std::ffi::OsStr) -> $crate::ResultEmpty {
+            $via!(self, unlink(req, parent, name), "{:?}, {:?}", parent, name)
+        }
+    };
+    (rmdir, $h:ident, $via:ident, $via_async:ident) => {
+        fn rmdir(&self, req: $crate::RequestInfo, parent: &::std::path::Path, name: &::std::ffi::OsStr) -> $crate::ResultEmpty {
+            $via!(self, rmdir(req, parent, name), "{:?}, {:?}", parent, name)
+        }
+    };
+    (symlink, $h:ident, $via:ident, $via_async:ident) => {
+        fn symlink(&self, req: $crate::RequestInfo, parent: &::std::path::Path, name: &::std::ffi::OsStr, target: &::std::path::Path) -> $crate::ResultEntry {
+            $via!(self, symlink(req, parent, name, target), "{:?}, {:?}, target={:?}", parent,
+                  name, target)
+        }
+    };
+    (rename, $h:ident, $via:ident, $via_async:ident) => {
+        fn rename(&self, req: $crate::RequestInfo, parent: &::std::path::Path, name: &::std::ffi::OsStr, newparent: &::std::path::Path, newname: &::std::ffi::OsStr) -> $crate::ResultEmpty {
+            $via!(self, rename(req, parent, name, newparent, newname), "{:?}, {:?}, {:?}, {:?}",
+                  parent, name, newparent, newname)
+        }
+    };
+    (link, $h:ident, $via:ident, $via_async:ident) => {
+        fn link(&self, req: $crate::RequestInfo, path: &::std::path::Path, newparent: &::std::path::Path, newname: &::std::ffi::OsStr) -> $crate::ResultEntry {
+            $via!(self, link(req, path, newparent, newname), "{:?}, {:?}, {:?}", path, newparent,
+                  newname)
+        }
+    };
+    (open, $h:ident, $via:ident, $via_async:ident) => {
+        fn open(&self, req: $crate::RequestInfo, path: &::std::path::Path, flags: $crate::FileFlags) -> $crate::ResultOpen<Self::FileHandle> {
+            $via!(self, open(req, path, flags), "{:?}, flags={:#x}", path, flags.raw())
+        }
+    };
+    (read, $h:ident, $via:ident, $via_async:ident) => {
+        fn read(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: &Self::FileHandle, offset: u64, size: u32, callback: impl FnOnce($crate::ResultSlice) -> $crate::CallbackResult) -> $crate::CallbackResult {
+            $via!(self, read(req, path, forward_fh!($h, ref, fh), offset, size, callback),
+                  "{:?}, offset={}, size={}", path, offset, size)
+        }
+    };
+    (write, $h:ident, $via:ident, $via_async:ident) => {
+        fn write(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: &Self::FileHandle, offset: u64, data: &[u8], flags: u32) -> $crate::ResultWrite {
+            $via!(self, write(req, path, forward_fh!($h, ref, fh), offset, data, flags),
+                  "{:?}, offset={}, size={}, flags={:#x}", path, offset, data.len(), flags)
+        }
+    };
+    (flush, $h:ident, $via:ident, $via_async:ident) => {
+        fn flush(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: &Self::FileHandle, lock_owner: u64) -> $crate::ResultEmpty {
+            $via!(self, flush(req, path, forward_fh!($h, ref, fh), lock_owner), "{:?}", path)
+        }
+    };
+    (release, $h:ident, $via:ident, $via_async:ident) => {
+        fn release(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: &Self::FileHandle, flags: u32, lock_owner: u64, flush: bool) -> $crate::ResultEmpty {
+            $via!(self, release(req, path, forward_fh!($h, ref, fh), flags, lock_owner, flush),
+                  "{:?}, flags={:#x}, flush={}", path, flags, flush)
+        }
+    };
+    (fsync, $h:ident, $via:ident, $via_async:ident) => {
+        fn fsync(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: &Self::FileHandle, datasync: bool) -> $crate::ResultEmpty {
+            $via!(self, fsync(req, path, forward_fh!($h, ref, fh), datasync), "{:?}, datasync={}",
+                  path, datasync)
+        }
+    };
+    (opendir, $h:ident, $via:ident, $via_async:ident) => {
+        fn opendir(&self, req: $crate::RequestInfo, path: &::std::path::Path, flags: u32) -> $crate::ResultOpen<Self::DirHandle> {
+            $via!(self, opendir(req, path, flags), "{:?}, flags={:#x}", path, flags)
+        }
+    };
+    (readdir, $h:ident, $via:ident, $via_async:ident) => {
+        fn readdir(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: &Self::DirHandle, offset: u64) -> $crate::ResultReaddir {
+            $via!(self, readdir(req, path, fh, offset), "{:?}, offset={}", path, offset)
+        }
+    };
+    (releasedir, $h:ident, $via:ident, $via_async:ident) => {
+        fn releasedir(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: &Self::DirHandle, flags: u32) -> $crate::ResultEmpty {
+            $via!(self, releasedir(req, path, fh, flags), "{:?}, flags={:#x}", path, flags)
+        }
+    };
+    (fsyncdir, $h:ident, $via:ident, $via_async:ident) => {
+        fn fsyncdir(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: &Self::DirHandle, datasync: bool) -> $crate::ResultEmpty {
+            $via!(self, fsyncdir(req, path, fh, datasync), "{:?}, datasync={}", path, datasync)
+        }
+    };
+    (statfs, $h:ident, $via:ident, $via_async:ident) => {
+        fn statfs(&self, req: $crate::RequestInfo, path: &::std::path::Path) -> $crate::ResultStatfs {
+            $via!(self, statfs(req, path), "{:?}", path)
+        }
+    };
+    (setxattr, $h:ident, $via:ident, $via_async:ident) => {
+        fn setxattr(&self, req: $crate::RequestInfo, path: &::std::path::Path, name: &::std::ffi::OsStr, value: &[u8], flags: u32, position: u32) -> $crate::ResultEmpty {
+            $via!(self, setxattr(req, path, name, value, flags, position),
+                  "{:?}, {:?}, size={}, flags={:#x}, position={}", path, name, value.len(), flags,
+                  position)
+        }
+    };
+    (getxattr, $h:ident, $via:ident, $via_async:ident) => {
+        fn getxattr(&self, req: $crate::RequestInfo, path: &::std::path::Path, name: &::std::ffi::OsStr, size: u32) -> $crate::ResultXattr {
+            $via!(self, getxattr(req, path, name, size), "{:?}, {:?}, size={}", path, name, size)
+        }
+    };
+    (listxattr, $h:ident, $via:ident, $via_async:ident) => {
+        fn listxattr(&self, req: $crate::RequestInfo, path: &::std::path::Path, size: u32) -> $crate::ResultXattr {
+            $via!(self, listxattr(req, path, size), "{:?}, size={}", path, size)
+        }
+    };
+    (removexattr, $h:ident, $via:ident, $via_async:ident) => {
+        fn removexattr(&self, req: $crate::RequestInfo, path: &::std::path::Path, name: &::std::ffi::OsStr) -> $crate::ResultEmpty {
+            $via!(self, removexattr(req, path, name), "{:?}, {:?}", path, name)
+        }
+    };
+    (access, $h:ident, $via:ident, $via_async:ident) => {
+        fn access(&self, req: $crate::RequestInfo, path: &::std::path::Path, mask: u32) -> $crate::ResultEmpty {
+            $via!(self, access(req, path, mask), "{:?}, mask={:#o}", path, mask)
+        }
+    };
+    (create, $h:ident, $via:ident, $via_async:ident) => {
+        fn create(&self, req: $crate::RequestInfo, parent: &::std::path::Path, name: &::std::ffi::OsStr, mode: u32, flags: $crate::FileFlags) -> $crate::ResultCreate<Self::FileHandle> {
+            $via!(self, create(req, parent, name, mode, flags), "{:?}, {:?}, mode={:#o}, flags={:#x}",
+                  parent, name, mode, flags.raw())
+        }
+    };
+    (getlk, $h:ident, $via:ident, $via_async:ident) => {
+        fn getlk(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: &Self::FileHandle, lock_owner: u64, lock: $crate::FileLock) -> $crate::ResultLock {
+            $via!(self, getlk(req, path, forward_fh!($h, ref, fh), lock_owner, lock), "{:?}, {:?}",
+                  path, lock)
+        }
+    };
+    (setlk, $h:ident, $via:ident, $via_async:ident) => {
+        fn setlk(&self, req: $crate::RequestInfo, path: &::std::path::Path, fh: &Self::FileHandle, lock_owner: u64, lock: $crate::FileLock, sleep: bool) -> $crate::ResultEmpty {
+            $via!(self, setlk(req, path, forward_fh!($h, ref, fh), lock_owner, lock, sleep),
+                  "{:?}, {:?}, sleep={}", path, lock, sleep)
+        }
+    };
+    (bmap, $h:ident, $via:ident, $via_async:ident) => {
+        fn bmap(&self, req: $crate::RequestInfo, path: &::std::path::Path, blocksize: u32, idx: u64) -> $crate::ResultBmap {
+            $via!(self, bmap(req, path, blocksize, idx), "{:?}, blocksize={}, idx={}", path,
+                  blocksize, idx)
+        }
+    };
+    (setvolname, $h:ident, $via:ident, $via_async:ident) => {
+        #[cfg(target_os = "macos")]
+        fn setvolname(&self, req: $crate::RequestInfo, name: &::std::ffi::OsStr) -> $crate::ResultEmpty {
+            $via!(self, setvolname(req, name), "{:?}", name)
+        }
+    };
+    (exchange, $h:ident, $via:ident, $via_async:ident) => {
+        #[cfg(target_os = "macos")]
+        fn exchange(&self, req: $crate::RequestInfo, parent: &::std::path::Path, name: &::std::ffi::OsStr, newparent: &::std::path::Path, newname: &::std::ffi::OsStr, options: u64) -> $crate::ResultEmpty {
+            $via!(self, exchange(req, parent, name, newparent, newname, options),
+                  "{:?}, {:?}, {:?}, {:?}, options={:#x}", parent, name, newparent, newname,
+                  options)
+        }
+    };
+    (getxtimes, $h:ident, $via:ident, $via_async:ident) => {
+        #[cfg(target_os = "macos")]
+        fn getxtimes(&self, req: $crate::RequestInfo, path: &::std::path::Path) -> $crate::ResultXTimes {
+            $via!(self, getxtimes(req, path), "{:?}", path)
+        }
+    };
+    (read_async, $h:ident, $via:ident, $via_async:ident) => {
+        fn read_async(self: ::std::sync::Arc<Self>, req: $crate::RequestInfo, path: ::std::sync::Arc<::std::path::PathBuf>, fh: ::std::sync::Arc<Self::FileHandle>, offset: u64, size: u32, callback: $crate::ReadCallback) -> $crate::BoxFuture<$crate::CallbackResult> {
+            $via_async!(self, read, read_async(req, path, forward_fh!($h, arc, fh), offset, size,
+                                               callback),
+                        "{:?}, offset={}, size={}", path, offset, size)
+        }
+    };
+    (write_async, $h:ident, $via:ident, $via_async:ident) => {
+        fn write_async(self: ::std::sync::Arc<Self>, req: $crate::RequestInfo, path: ::std::sync::Arc<::std::path::PathBuf>, fh: ::std::sync::Arc<Self::FileHandle>, offset: u64, data: Vec<u8>, flags: u32) -> $crate::BoxFuture<$crate::ResultWrite> {
+            $via_async!(self, write, write_async(req, path, forward_fh!($h, arc, fh), offset, data,
+                                                 flags),
+                        "{:?}, offset={}, size={}, flags={:#x}", path, offset, data.len(), flags)
+        }
+    };
+    (flush_async, $h:ident, $via:ident, $via_async:ident) => {
+        fn flush_async(self: ::std::sync::Arc<Self>, req: $crate::RequestInfo, path: ::std::sync::Arc<::std::path::PathBuf>, fh: ::std::sync::Arc<Self::FileHandle>, lock_owner: u64) -> $crate::BoxFuture<$crate::ResultEmpty> {
+            $via_async!(self, flush, flush_async(req, path, forward_fh!($h, arc, fh), lock_owner),
+                        "{:?}", path)
+        }
+    };
+    (fsync_async, $h:ident, $via:ident, $via_async:ident) => {
+        fn fsync_async(self: ::std::sync::Arc<Self>, req: $crate::RequestInfo, path: ::std::sync::Arc<::std::path::PathBuf>, fh: ::std::sync::Arc<Self::FileHandle>, datasync: bool) -> $crate::BoxFuture<$crate::ResultEmpty> {
+            $via_async!(self, fsync, fsync_async(req, path, forward_fh!($h, arc, fh), datasync),
+                        "{:?}, datasync={}", path, datasync)
+        }
+    };
+    (getattr_async, $h:ident, $via:ident, $via_async:ident) => {
+        fn getattr_async(self: ::std::sync::Arc<Self>, req: $crate::RequestInfo, path: ::std::sync::Arc<::std::path::PathBuf>, fh: Option<::std::sync::Arc<Self::FileHandle>>) -> $crate::BoxFuture<$crate::ResultGetattr> {
+            $via_async!(self, getattr, getattr_async(req, path, forward_fh!($h, opt_arc, fh)),
+                        "{:?}{}", path, if fh.is_some() { " (by handle)" } else { "" })
+        }
+    };
+    (readdir_async, $h:ident, $via:ident, $via_async:ident) => {
+        fn readdir_async(self: ::std::sync::Arc<Self>, req: $crate::RequestInfo, path: ::std::sync::Arc<::std::path::PathBuf>, fh: ::std::sync::Arc<Self::DirHandle>, offset: u64) -> $crate::BoxFuture<$crate::ResultReaddir> {
+            $via_async!(self, readdir, readdir_async(req, path, fh, offset), "{:?}, offset={}",
+                        path, offset)
+        }
+    };
+    (setlk_async, $h:ident, $via:ident, $via_async:ident) => {
+        fn setlk_async(self: ::std::sync::Arc<Self>, req: $crate::RequestInfo, path: ::std::sync::Arc<::std::path::PathBuf>, fh: ::std::sync::Arc<Self::FileHandle>, lock_owner: u64, lock: $crate::FileLock, sleep: bool) -> $crate::BoxFuture<$crate::ResultEmpty> {
+            $via_async!(self, setlk, setlk_async(req, path, forward_fh!($h, arc, fh), lock_owner,
+                                                 lock, sleep),
+                        "{:?}, {:?}, sleep={}", path, lock, sleep)
+        }
+    };
+}
@@ -4,21 +4,49 @@
 // Copyright (c) 2016 by William R. Fraser
 //
 
-use std::ffi::OsStr;
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::future::{self, Future};
+use std::io::{self, BufReader, BufWriter};
+use std::mem;
+use std::ops::{BitOr, BitOrAssign};
+use std::os::unix::ffi::OsStrExt;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-
-use fuse::*;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, Once};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use fuse::{self, *};
 use libc;
 use threadpool::ThreadPool;
 use time::Timespec;
 
+use errno::Errno;
+use executor::{self, BoxFuture};
+use handle_table::HandleTable;
+use inode_cache::InodeCache;
 use inode_table::*;
+use mount::{self, MountOption};
+use read_buffer::{self, ReadBuffer};
+use watchdog::{Deadline, Watchdog};
 
+/// Information about the request that an operation is being done for, copied out of the FUSE
+/// request so that it can be passed by value to any thread the operation runs on.
+#[derive(Clone, Copy, Debug)]
 pub struct RequestInfo {
+    /// The unique ID of the request, as assigned by the kernel.
     pub unique: u64,
+    /// The user ID of the process making the request.
     pub uid: u32,
+    /// The group ID of the process making the request.
     pub gid: u32,
+    /// The process ID of the process making the request.
     pub pid: u32,
 }
 
@@ -37,9 +65,139 @@ impl<'a> IntoRequestInfo for Request<'a> {
     }
 }
 
+/// The type of a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FileType {
+    RegularFile,
+    Directory,
+    Symlink,
+    /// A Unix domain socket. rust-fuse has no type for these, so the kernel is told they're named
+    /// pipes instead (and a warning is logged the first time).
+    Socket,
+    CharDevice,
+    BlockDevice,
+    NamedPipe,
+}
+
+impl FileType {
+    // Convert to rust-fuse's version, to reply with.
+    fn to_fuse(self) -> fuse::FileType {
+        match self {
+            FileType::RegularFile => fuse::FileType::RegularFile,
+            FileType::Directory => fuse::FileType::Directory,
+            FileType::Symlink => fuse::FileType::Symlink,
+            FileType::Socket => {
+                // rust-fuse doesn't have a type for sockets. There may be lots of them, so only
+                // say so once.
+                static WARNED: Once = Once::new();
+                WARNED.call_once(|| {
+                    warn!("sockets can't be passed to the kernel; presenting them as named pipes");
+                });
+                fuse::FileType::NamedPipe
+            },
+            FileType::CharDevice => fuse::FileType::CharDevice,
+            FileType::BlockDevice => fuse::FileType::BlockDevice,
+            FileType::NamedPipe => fuse::FileType::NamedPipe,
+        }
+    }
+}
+
+/// An entry listed by `readdir`.
+#[derive(Clone, Debug)]
 pub struct DirectoryEntry {
-    pub name: PathBuf,
+    /// The entry's name within the directory: a single component, which can't be empty or contain
+    /// `/` or NUL. Entries with names that aren't valid are left out of the listing.
+    pub name: OsString,
+    pub kind: FileType,
+    /// Where to continue the listing after this entry, which is passed back to `readdir` as its
+    /// `offset` if the kernel's buffer fills up before the next one. This lets filesystems with a
+    /// cursor of their own (e.g. a position in a directory stream, or a database's page token)
+    /// pick up from there. It mustn't be 0, which means the start of the directory.
+    ///
+    /// If it's `None`, the number of entries listed on the handle so far is used, counting this
+    /// one.
+    pub offset: Option<u64>,
+}
+
+/// The attributes of a file or directory.
+#[derive(Clone, Copy, Debug)]
+pub struct FileAttr {
+    /// The inode number. This is only used if the filesystem supplies inode numbers (see
+    /// `InodeAssignment::Filesystem`); otherwise it's ignored, and FuseMT fills in its own.
+    pub ino: u64,
+    pub size: u64,
+    /// The number of 512-byte blocks allocated to the file.
+    pub blocks: u64,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    /// The time the file was created (macOS only).
+    pub crtime: SystemTime,
     pub kind: FileType,
+    /// The permission bits of the mode (e.g. 0o644), without the file type.
+    pub perm: u16,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// The device number, for device files; see `DeviceNumber`.
+    pub rdev: u32,
+    /// The preferred size for I/O, or 0 for the default. This is ignored for now: rust-fuse's
+    /// attributes have no field for it, so the kernel always uses its default.
+    pub blksize: u32,
+    /// Flags, as set by chflags(2) (macOS only).
+    pub flags: u32,
+}
+
+impl FileAttr {
+    // Convert to rust-fuse's version, to reply with.
+    fn to_fuse(&self) -> fuse::FileAttr {
+        fuse::FileAttr {
+            ino: self.ino,
+            size: self.size,
+            blocks: self.blocks,
+            atime: to_timespec(self.atime),
+            mtime: to_timespec(self.mtime),
+            ctime: to_timespec(self.ctime),
+            crtime: to_timespec(self.crtime),
+            kind: self.kind.to_fuse(),
+            perm: self.perm,
+            nlink: self.nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: self.rdev,
+            flags: self.flags,
+        }
+    }
+}
+
+// Convert a time to seconds and nanoseconds since the epoch, which may be negative.
+fn to_timespec(time: SystemTime) -> Timespec {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => Timespec::new(since.as_secs() as i64, since.subsec_nanos() as i32),
+        Err(e) => {
+            let before = e.duration();
+            let (sec, nsec) = (-(before.as_secs() as i64), before.subsec_nanos() as i32);
+            if nsec == 0 {
+                Timespec::new(sec, 0)
+            } else {
+                Timespec::new(sec - 1, 1_000_000_000 - nsec)
+            }
+        },
+    }
+}
+
+// Convert a time given as seconds and nanoseconds since the epoch back into a SystemTime.
+fn from_timespec(time: Timespec) -> SystemTime {
+    if time.sec >= 0 {
+        UNIX_EPOCH + Duration::new(time.sec as u64, time.nsec as u32)
+    } else {
+        UNIX_EPOCH - Duration::new((-time.sec) as u64, 0) + Duration::new(0, time.nsec as u32)
+    }
+}
+
+// Convert a time to live into the form the kernel takes it in.
+fn ttl_to_timespec(ttl: Duration) -> Timespec {
+    Timespec::new(ttl.as_secs() as i64, ttl.subsec_nanos() as i32)
 }
 
 pub struct Statfs {
@@ -53,33 +211,311 @@ pub struct Statfs {
     pub frsize: u32,
 }
 
-pub struct CreatedEntry {
-    pub ttl: Timespec,
+/// A device number, split into its major and minor parts.
+///
+/// This is used to interpret the `rdev` argument of `mknod` and the `rdev` field of `FileAttr`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceNumber {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl DeviceNumber {
+    /// Split an `rdev` value, as encoded by the kernel, into its major and minor parts.
+    #[cfg(not(target_os = "macos"))]
+    pub fn from_rdev(rdev: u32) -> DeviceNumber {
+        DeviceNumber {
+            major: (rdev & 0xfff00) >> 8,
+            minor: (rdev & 0xff) | ((rdev >> 12) & 0xfff00),
+        }
+    }
+
+    /// Combine the major and minor parts into an `rdev` value, as encoded by the kernel.
+    #[cfg(not(target_os = "macos"))]
+    pub fn rdev(&self) -> u32 {
+        (self.minor & 0xff) | ((self.major & 0xfff) << 8) | ((self.minor & !0xff) << 12)
+    }
+
+    /// Split an `rdev` value, as encoded by the kernel, into its major and minor parts.
+    #[cfg(target_os = "macos")]
+    pub fn from_rdev(rdev: u32) -> DeviceNumber {
+        DeviceNumber {
+            major: (rdev >> 24) & 0xff,
+            minor: rdev & 0xffffff,
+        }
+    }
+
+    /// Combine the major and minor parts into an `rdev` value, as encoded by the kernel.
+    #[cfg(target_os = "macos")]
+    pub fn rdev(&self) -> u32 {
+        ((self.major & 0xff) << 24) | (self.minor & 0xffffff)
+    }
+}
+
+/// A time to set a file's access or modification time to.
+///
+/// FuseMT only ever passes `SpecificTime` for now: rust-fuse drops the kernel's flags that say the
+/// current time was asked for, so a plain `touch` arrives as the current time given explicitly,
+/// and can't be told apart from `touch -d` with the same time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeOrNow {
+    /// A particular time, e.g. from `touch -d`.
+    SpecificTime(SystemTime),
+    /// The current time, as with `touch` (`UTIME_NOW`). This is never passed yet (see above); it's
+    /// here so that filesystems handle it already, for when rust-fuse passes the flags on.
+    Now,
+}
+
+/// The attributes to change in a `setattr` call. Fields which are `None` should be left alone.
+#[derive(Clone, Copy, Debug)]
+pub struct SetAttrRequest {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<TimeOrNow>,
+    pub mtime: Option<TimeOrNow>,
+    /// OS X only.
+    pub crtime: Option<SystemTime>,
+    /// OS X only.
+    pub chgtime: Option<SystemTime>,
+    /// OS X only.
+    pub bkuptime: Option<SystemTime>,
+    /// OS X only.
+    pub flags: Option<u32>,
+}
+
+/// A POSIX record lock (as used by `fcntl`), for `getlk` and `setlk`.
+#[derive(Clone, Copy, Debug)]
+pub struct FileLock {
+    pub start: u64,
+    pub end: u64,
+    /// One of `libc::F_RDLCK`, `libc::F_WRLCK`, or `libc::F_UNLCK`.
+    pub typ: u32,
+    pub pid: u32,
+}
+
+/// The OS X-specific backup and creation times of a file.
+#[cfg(target_os = "macos")]
+pub struct XTimes {
+    pub bkuptime: SystemTime,
+    pub crtime: SystemTime,
+}
+
+/// Flags returned by `open`, `opendir`, and `create`, which tell the kernel how to treat the open
+/// file (the `FOPEN_*` flags of the FUSE protocol). They can be combined with `|`.
+///
+/// Kernels that are too old for a flag ignore it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    /// Bypass the page cache for this file; reads and writes go straight to the filesystem.
+    pub const DIRECT_IO: OpenFlags = OpenFlags(1 << 0);
+    /// Don't invalidate the data cached for the file when it's opened.
+    pub const KEEP_CACHE: OpenFlags = OpenFlags(1 << 1);
+    /// The file can't be seeked.
+    pub const NONSEEKABLE: OpenFlags = OpenFlags(1 << 2);
+    /// Allow the kernel to cache the directory's entries (for `opendir`).
+    pub const CACHE_DIR: OpenFlags = OpenFlags(1 << 3);
+    /// The file is a stream, with no file position at all.
+    pub const STREAM: OpenFlags = OpenFlags(1 << 4);
+
+    /// No flags.
+    pub fn empty() -> OpenFlags {
+        OpenFlags(0)
+    }
+
+    /// The flags as the kernel gets them.
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Whether all of the given flags are set.
+    pub fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for OpenFlags {
+    fn bitor_assign(&mut self, other: OpenFlags) {
+        self.0 |= other.0;
+    }
+}
+
+/// The flags that a file is opened with, as given to `open(2)` (the `O_*` flags), for `open` and
+/// `create`.
+///
+/// The kernel handles some flags itself: `O_CREAT` and `O_EXCL` are only passed to `create`, and
+/// `O_TRUNC` is usually done with a `setattr` call before `open` instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileFlags(u32);
+
+impl FileFlags {
+    pub fn new(flags: u32) -> FileFlags {
+        FileFlags(flags)
+    }
+
+    /// The flags as the kernel gave them, e.g. to pass on to `open(2)`.
+    pub fn raw(self) -> u32 {
+        self.0
+    }
+
+    fn has(self, flag: libc::c_int) -> bool {
+        self.0 & flag as u32 != 0
+    }
+
+    fn access_mode(self) -> libc::c_int {
+        self.0 as libc::c_int & libc::O_ACCMODE
+    }
+
+    /// Whether the file is opened for reading only (`O_RDONLY`).
+    pub fn read_only(self) -> bool {
+        self.access_mode() == libc::O_RDONLY
+    }
+
+    /// Whether the file is opened for reading (`O_RDONLY` or `O_RDWR`).
+    pub fn read(self) -> bool {
+        self.access_mode() != libc::O_WRONLY
+    }
+
+    /// Whether the file is opened for writing (`O_WRONLY` or `O_RDWR`).
+    pub fn write(self) -> bool {
+        self.access_mode() != libc::O_RDONLY
+    }
+
+    /// Whether every write goes to the end of the file (`O_APPEND`).
+    pub fn append(self) -> bool {
+        self.has(libc::O_APPEND)
+    }
+
+    /// Whether creating the file should fail if it already exists (`O_EXCL`).
+    pub fn excl(self) -> bool {
+        self.has(libc::O_EXCL)
+    }
+
+    /// Whether the file should be truncated to zero length (`O_TRUNC`).
+    pub fn truncate(self) -> bool {
+        self.has(libc::O_TRUNC)
+    }
+}
+
+/// A directory entry, returned by operations that create or look it up: the attributes of the
+/// file, and how long the kernel may cache them (and the entry) for.
+///
+/// The inode number in the attributes and the generation number of the entry are filled in by
+/// FuseMT, so the filesystem doesn't need to worry about them, unless it supplies its own inode
+/// numbers (see `InodeAssignment::Filesystem`), in which case the number it returns is used.
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    pub ttl: Duration,
+    pub attr: FileAttr,
+}
+
+impl Entry {
+    pub fn new(ttl: Duration, attr: FileAttr) -> Entry {
+        Entry {
+            ttl: ttl,
+            attr: attr,
+        }
+    }
+
+    /// An entry that the kernel may cache for the given time, e.g.
+    /// `Entry::with_ttl(attr, Duration::from_secs(1))`.
+    pub fn with_ttl(attr: FileAttr, ttl: Duration) -> Entry {
+        Entry::new(ttl, attr)
+    }
+}
+
+/// The result of `create`: the new file's entry, as for `ResultEntry`, and the handle it was opened
+/// with, as for `ResultOpen`.
+pub struct CreatedEntry<H> {
+    pub ttl: Duration,
     pub attr: FileAttr,
+    /// The generation number of the new file. Like the inode number in `attr`, this is only used
+    /// if the filesystem supplies its own inode numbers (see `InodeAssignment::Filesystem`), and
+    /// then only if it isn't 0; otherwise FuseMT fills it in.
     pub generation: u64,
-    pub fh: u64,
-    pub flags: u32,
+    pub fh: H,
+    pub flags: OpenFlags,
 }
 
+/// The answer to `getxattr` or `listxattr`, which are called first with a size of 0 to find out
+/// how big a buffer is needed, and then again with a buffer of that size.
+#[derive(Clone, Debug)]
 pub enum Xattr {
+    /// The size of the value, in answer to a call with a size of 0.
     Size(u32),
+
+    /// The value itself. This can also be given in answer to a call with a size of 0, in which
+    /// case FuseMT replies with its size.
     Data(Vec<u8>),
 }
 
-pub type ResultEmpty = Result<(), libc::c_int>;
-pub type ResultGetattr = Result<(Timespec, FileAttr), libc::c_int>;
-pub type ResultEntry = Result<(Timespec, FileAttr, u64), libc::c_int>;
-pub type ResultOpen = Result<(u64, u32), libc::c_int>;
-pub type ResultReaddir = Result<Vec<DirectoryEntry>, libc::c_int>;
-pub type ResultData = Result<Vec<u8>, libc::c_int>;
-pub type ResultWrite = Result<u32, libc::c_int>;
-pub type ResultStatfs = Result<Statfs, libc::c_int>;
-pub type ResultCreate = Result<CreatedEntry, libc::c_int>;
-pub type ResultXattr = Result<Xattr, libc::c_int>;
+pub type ResultEmpty = Result<(), Errno>;
+/// The attributes of a file, and how long the kernel may cache them for.
+pub type ResultGetattr = Result<(Duration, FileAttr), Errno>;
+/// The result of operations that create or look up a directory entry.
+pub type ResultEntry = Result<Entry, Errno>;
+/// The result of opening a file or directory: its handle, and flags for the kernel.
+pub type ResultOpen<H> = Result<(H, OpenFlags), Errno>;
+/// The entries of a directory, which are read from the iterator only as they're needed.
+pub type DirectoryEntries = Box<dyn Iterator<Item = Result<DirectoryEntry, Errno>> + Send>;
+pub type ResultReaddir = Result<DirectoryEntries, Errno>;
+pub type ResultData = Result<Vec<u8>, Errno>;
+pub type ResultSlice<'a> = Result<&'a [u8], Errno>;
+pub type ResultWrite = Result<u32, Errno>;
+pub type ResultStatfs = Result<Statfs, Errno>;
+pub type ResultCreate<H> = Result<CreatedEntry<H>, Errno>;
+pub type ResultXattr = Result<Xattr, Errno>;
+pub type ResultLock = Result<FileLock, Errno>;
+pub type ResultBmap = Result<u64, Errno>;
+#[cfg(target_os = "macos")]
+pub type ResultXTimes = Result<XTimes, Errno>;
+
+/// What the callback given to `read` returns. The only way to get one is to call the callback, so
+/// `read` can't return without replying.
+pub struct CallbackResult {
+    _private: (),
+}
+
+impl CallbackResult {
+    // For when the callback was dropped without being called, which fails the read.
+    pub(crate) fn dropped() -> CallbackResult {
+        CallbackResult { _private: () }
+    }
+}
+
+/// The callback that `read_async` replies through. It can be called from any thread.
+pub type ReadCallback = Box<dyn FnOnce(ResultSlice) -> CallbackResult + Send>;
 
+/// The operations of a filesystem, in terms of paths instead of inodes.
+///
+/// All the methods take `&self`, because FuseMT calls them from several threads at once (the
+/// filesystem is shared between them in an `Arc`, so it must also be `Send` and `Sync`). State
+/// that operations change needs to be behind e.g. a `Mutex`.
 pub trait FilesystemMT {
+    /// What the filesystem keeps for each open file, e.g. a file descriptor, or an object with a
+    /// buffer and a connection to a backend. `open` and `create` return one, FuseMT keeps it while
+    /// the file is open (giving the kernel a number to refer to it by), passes it back to the
+    /// operations on the open file, and drops it after `release`.
+    type FileHandle: Send + Sync + 'static;
+
+    /// What the filesystem keeps for each open directory, e.g. a directory stream, or a cursor
+    /// into a backend's listing. Like `FileHandle`, `opendir` returns one, and FuseMT passes it
+    /// back to the operations on the open directory, and drops it after `releasedir`.
+    type DirHandle: Send + Sync + 'static;
+
     fn init(&self, _req: RequestInfo) -> ResultEmpty {
-        Err(0)
+        Err(Errno(0))
     }
 
     fn destroy(&self, _req: RequestInfo) {
@@ -87,225 +523,1604 @@ pub trait FilesystemMT {
     }
 
     fn lookup(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEntry {
-        Err(libc::ENOSYS)
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Get the attributes of a file or directory.
+    ///
+    /// `fh` is the handle of one of the file's open files if its path has been unlinked, so that
+    /// it can be looked up through that instead (e.g. with `fstat`). The kernel's own handle for an
+    /// `fstat` isn't passed on by rust-fuse, so for files that still have a path, it's `None`.
+    fn getattr(&self, _req: RequestInfo, _path: &Path, _fh: Option<&Self::FileHandle>) -> ResultGetattr {
+        Err(Errno(libc::ENOSYS))
     }
 
-    fn getattr(&self, _req: RequestInfo, _path: &Path, _fh: Option<u64>) -> ResultGetattr {
-        Err(libc::ENOSYS)
+    /// Change any of a file's attributes, and return the attributes as they are afterwards.
+    ///
+    /// The default implementation splits the request up into calls to `chmod`, `chown`,
+    /// `truncate`, `utimens`, and `utimens_macos` (to match the C API's behavior), stopping at the
+    /// first error, and then calls `getattr`. Filesystems that can apply several changes at once
+    /// can override this instead.
+    ///
+    /// `fh` is the handle of the open file, if the change was made through one (e.g.
+    /// `ftruncate`).
+    fn setattr(&self, req: RequestInfo, path: &Path, fh: Option<&Self::FileHandle>, attrs: SetAttrRequest) -> ResultGetattr {
+        if let Some(mode) = attrs.mode {
+            self.chmod(req, path, fh, mode)?;
+        }
+
+        if attrs.uid.is_some() || attrs.gid.is_some() {
+            self.chown(req, path, fh, attrs.uid, attrs.gid)?;
+        }
+
+        if let Some(size) = attrs.size {
+            self.truncate(req, path, fh, size)?;
+        }
+
+        if attrs.atime.is_some() || attrs.mtime.is_some() {
+            self.utimens(req, path, fh, attrs.atime, attrs.mtime)?;
+        }
+
+        if attrs.crtime.is_some() || attrs.chgtime.is_some() || attrs.bkuptime.is_some()
+                || attrs.flags.is_some() {
+            self.utimens_macos(req, path, fh, attrs.crtime, attrs.chgtime, attrs.bkuptime,
+                               attrs.flags)?;
+        }
+
+        self.getattr(req, path, fh)
     }
 
     // The following operations in the FUSE C API are all one kernel call: setattr
-    // We split them out to match the C API's behavior.
+    // We split them out to match the C API's behavior; the default `setattr` calls them.
+
+    fn chmod(&self, _req: RequestInfo, _path: &Path, _fh: Option<&Self::FileHandle>, _mode: u32) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    fn chown(&self, _req: RequestInfo, _path: &Path, _fh: Option<&Self::FileHandle>, _uid: Option<u32>, _gid: Option<u32>) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Change the size of a file.
+    ///
+    /// If the truncation was done through an open file (i.e. `ftruncate`), `fh` is its handle,
+    /// and should be used in preference to the path, which may have been unlinked or renamed since
+    /// the file was opened.
+    fn truncate(&self, _req: RequestInfo, _path: &Path, _fh: Option<&Self::FileHandle>, _size: u64) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Change the access and modification times of a file. Times which are `None` should be left
+    /// alone, as with `UTIME_OMIT`.
+    fn utimens(&self, _req: RequestInfo, _path: &Path, _fh: Option<&Self::FileHandle>, _atime: Option<TimeOrNow>, _mtime: Option<TimeOrNow>) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    fn utimens_macos(&self, _req: RequestInfo, _path: &Path, _fh: Option<&Self::FileHandle>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    // END OF SETATTR FUNCTIONS
+
+    /// Read the target of a symbolic link. The returned data is the raw bytes of the target path.
+    fn readlink(&self, _req: RequestInfo, _path: &Path) -> ResultData {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Create a file node, such as a device file or a named pipe. `mode` includes the type of the
+    /// file, and the calling process's umask has already been applied to its permission bits.
+    fn mknod(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32, _rdev: u32) -> ResultEntry {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Create a directory. The calling process's umask has already been applied to `mode`.
+    fn mkdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32) -> ResultEntry {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    fn rmdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    fn symlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _target: &Path) -> ResultEntry {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    fn rename(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _newparent: &Path, _newname: &OsStr) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    fn link(&self, _req: RequestInfo, _path: &Path, _newparent: &Path, _newname: &OsStr) -> ResultEntry {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Open a file, and return the handle the operations on the open file are given.
+    fn open(&self, _req: RequestInfo, _path: &Path, _flags: FileFlags) -> ResultOpen<Self::FileHandle> {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Read data from a file, and pass it to the callback, which replies with it right away. The
+    /// data doesn't have to be copied into a new buffer first, so it can come straight from e.g.
+    /// a memory map or a pooled buffer (see `ReadBuffer`).
+    ///
+    /// Returning less data than was asked for means the end of the file was reached, unless the
+    /// file was opened with `OpenFlags::DIRECT_IO`. Filesystems that can return less at other
+    /// times (e.g. whatever a stream has ready) can have FuseMT ask for the rest with
+    /// `FuseMT::fill_short_reads`.
+    fn read(&self, _req: RequestInfo, _path: &Path, _fh: &Self::FileHandle, _offset: u64, _size: u32, callback: impl FnOnce(ResultSlice) -> CallbackResult) -> CallbackResult {
+        callback(Err(Errno(libc::ENOSYS)))
+    }
+
+    fn write(&self, _req: RequestInfo, _path: &Path, _fh: &Self::FileHandle, _offset: u64, _data: &[u8], _flags: u32) -> ResultWrite {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    fn flush(&self, _req: RequestInfo, _path: &Path, _fh: &Self::FileHandle, _lock_owner: u64) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Close an open file, once nothing refers to it anymore. FuseMT drops the handle after this
+    /// returns (or once operations still using it have finished, if any are).
+    fn release(&self, _req: RequestInfo, _path: &Path, _fh: &Self::FileHandle, _flags: u32, _lock_owner: u64, _flush: bool) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Write out any buffered data for a file to permanent storage. If `datasync` is true, only
+    /// the file's contents need to be written, not its metadata.
+    fn fsync(&self, _req: RequestInfo, _path: &Path, _fh: &Self::FileHandle, _datasync: bool) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Open a directory, and return the handle the operations on the open directory are given.
+    fn opendir(&self, _req: RequestInfo, _path: &Path, _flags: u32) -> ResultOpen<Self::DirHandle> {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// List the entries of a directory, starting at `offset`, which is 0 at first, and after that
+    /// is the offset of the last entry that earlier calls on the same handle returned: by default,
+    /// the number of entries returned so far, or the one the filesystem gave it (see
+    /// `DirectoryEntry::offset`).
+    ///
+    /// The iterator is only read until the reply to the kernel is full, and then dropped, so the
+    /// entries should be read from the directory as they are needed, rather than all up front. If
+    /// reading one fails, the listing stops there, and the error is returned by the next call.
+    fn readdir(&self, _req: RequestInfo, _path: &Path, _fh: &Self::DirHandle, _offset: u64) -> ResultReaddir {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Close an open directory. FuseMT drops the handle after this returns.
+    fn releasedir(&self, _req: RequestInfo, _path: &Path, _fh: &Self::DirHandle, _flags: u32) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Write out any buffered changes to a directory (e.g. entries added by `rename`) to
+    /// permanent storage. `fh` is the handle returned by `opendir`.
+    fn fsyncdir(&self, _req: RequestInfo, _path: &Path, _fh: &Self::DirHandle, _datasync: bool) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Get filesystem statistics, as reported by `statfs`/`df`.
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Set the value of an extended attribute. `flags` may contain `XATTR_CREATE` or
+    /// `XATTR_REPLACE`. `position` is only used on OS X, for resource forks.
+    fn setxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Get the value of an extended attribute.
+    ///
+    /// If `size` is 0, the caller only wants to know how big the value is, and the result should
+    /// be `Xattr::Size`. Otherwise, the result should be `Xattr::Data` containing at most `size`
+    /// bytes, or `Err(Errno(libc::ERANGE))` if the value doesn't fit. (Returning `Xattr::Data`
+    /// regardless of `size` works too; FuseMT replies with its size, or ERANGE, as needed.)
+    fn getxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _size: u32) -> ResultXattr {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// List the names of a file's extended attributes, as a sequence of NUL-terminated strings.
+    ///
+    /// Like `getxattr`, if `size` is 0, the result should be `Xattr::Size` with the size of the
+    /// list; otherwise it should be `Xattr::Data`, or `Err(Errno(libc::ERANGE))` if it doesn't fit.
+    fn listxattr(&self, _req: RequestInfo, _path: &Path, _size: u32) -> ResultXattr {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    fn removexattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Check whether the calling process may access a file. `mask` is a combination of
+    /// `libc::R_OK`, `libc::W_OK`, and `libc::X_OK`, or is `libc::F_OK` to only check that the
+    /// file exists.
+    ///
+    /// This is only called if the filesystem is not mounted with the `default_permissions`
+    /// option. The default implementation checks the mask against the permission bits returned by
+    /// `getattr`, the way the kernel would: using the owner's bits if the request's uid owns the
+    /// file, the group's bits if the request's gid or one of the process's supplementary groups
+    /// is the file's group, and the others' bits otherwise. Root may read and write anything, and
+    /// execute anything that has an execute bit set (or is a directory).
+    fn access(&self, req: RequestInfo, path: &Path, mask: u32) -> ResultEmpty {
+        let (_ttl, attr) = self.getattr(req, path, None)?;
+        check_access(&attr, req, mask)
+    }
+
+    /// Create a file and open it. The calling process's umask has already been applied to `mode`.
+    fn create(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32, _flags: FileFlags) -> ResultCreate<Self::FileHandle> {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Test whether a POSIX record lock could be placed. Returns the first lock that would conflict
+    /// with `lock`, or `lock` with its type changed to `libc::F_UNLCK` if there is none.
+    ///
+    /// `lock_owner` identifies the owner of the lock, and is passed through unchanged from the
+    /// kernel.
+    fn getlk(&self, _req: RequestInfo, _path: &Path, _fh: &Self::FileHandle, _lock_owner: u64, _lock: FileLock) -> ResultLock {
+        Err(Errno(libc::ENOSYS))
+    }
 
-    fn chmod(&self, _req: RequestInfo, _path: &Path, _fh: Option<u64>, _mode: u32) -> ResultEmpty {
-        Err(libc::ENOSYS)
+    /// Acquire, modify, or release a POSIX record lock. If `sleep` is true and the lock is
+    /// contended, this should block until it can be acquired; otherwise it should fail with
+    /// `libc::EAGAIN`.
+    fn setlk(&self, _req: RequestInfo, _path: &Path, _fh: &Self::FileHandle, _lock_owner: u64, _lock: FileLock, _sleep: bool) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Map a block index within a file (in units of `blocksize`) to a block index within the
+    /// device. This is only meaningful for filesystems backed by a block device (mounted with the
+    /// `blkdev` option).
+    fn bmap(&self, _req: RequestInfo, _path: &Path, _blocksize: u32, _idx: u64) -> ResultBmap {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    // OS X only:
+
+    /// Set the name of the volume, as shown in the Finder.
+    #[cfg(target_os = "macos")]
+    fn setvolname(&self, _req: RequestInfo, _name: &OsStr) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Atomically swap the contents of two files, as used by `exchangedata` for safe saves.
+    #[cfg(target_os = "macos")]
+    fn exchange(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _newparent: &Path, _newname: &OsStr, _options: u64) -> ResultEmpty {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    /// Get the backup and creation times of a file.
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&self, _req: RequestInfo, _path: &Path) -> ResultXTimes {
+        Err(Errno(libc::ENOSYS))
+    }
+
+    // Asynchronous versions of the operations that are dispatched to worker threads:
+
+    /// Asynchronous version of `read`. This is what FuseMT actually calls, on a worker thread.
+    ///
+    /// The default implementation calls `read` and returns its result right away. Filesystems that
+    /// are naturally asynchronous (e.g. backed by a network service) can instead return a future
+    /// that completes when the data arrives. In the meantime, the worker thread is free to handle
+    /// other requests; the future is polled again on one of the worker threads when it is woken.
+    ///
+    /// A filesystem that completes requests from its own event loop can return the future from
+    /// `fuse_mt::deferred()`, and hand the `ReplyToken` that comes with it to the event loop.
+    ///
+    /// Unlike the other asynchronous operations, this replies through the callback, like `read`
+    /// does, and the future completes with what the callback returns.
+    fn read_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, offset: u64, size: u32, callback: ReadCallback) -> BoxFuture<CallbackResult> {
+        Box::pin(future::ready(self.read(req, &path, &fh, offset, size, callback)))
+    }
+
+    /// Asynchronous version of `write`; see `read_async`.
+    fn write_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, offset: u64, data: Vec<u8>, flags: u32) -> BoxFuture<ResultWrite> {
+        Box::pin(future::ready(self.write(req, &path, &fh, offset, &data, flags)))
+    }
+
+    /// Asynchronous version of `flush`; see `read_async`.
+    fn flush_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, lock_owner: u64) -> BoxFuture<ResultEmpty> {
+        Box::pin(future::ready(self.flush(req, &path, &fh, lock_owner)))
+    }
+
+    /// Asynchronous version of `fsync`; see `read_async`.
+    fn fsync_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, datasync: bool) -> BoxFuture<ResultEmpty> {
+        Box::pin(future::ready(self.fsync(req, &path, &fh, datasync)))
+    }
+
+    /// Asynchronous version of `getattr`; see `read_async`.
+    fn getattr_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Option<Arc<Self::FileHandle>>) -> BoxFuture<ResultGetattr> {
+        Box::pin(future::ready(self.getattr(req, &path, fh.as_deref())))
+    }
+
+    /// Asynchronous version of `readdir`; see `read_async`.
+    fn readdir_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::DirHandle>, offset: u64) -> BoxFuture<ResultReaddir> {
+        Box::pin(future::ready(self.readdir(req, &path, &fh, offset)))
+    }
+
+    /// Asynchronous version of `setlk`; see `read_async`. A future that waits for a contended
+    /// lock doesn't hold up a worker thread while it waits.
+    fn setlk_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, lock_owner: u64, lock: FileLock, sleep: bool) -> BoxFuture<ResultEmpty> {
+        Box::pin(future::ready(self.setlk(req, &path, &fh, lock_owner, lock, sleep)))
+    }
+}
+
+// The supplementary groups of a process, which the kernel doesn't send with requests. Like libfuse's
+// fuse_getgroups(), this reads them from /proc; where that can't be done (e.g. the process has
+// exited already, or there's no /proc, as on OS X), there are none.
+fn process_groups(pid: u32) -> Vec<u32> {
+    let status = match fs::read_to_string(format!("/proc/{}/status", pid)) {
+        Ok(status) => status,
+        Err(_) => return vec![],
+    };
+    status.lines()
+        .find(|line| line.starts_with("Groups:"))
+        .map(|line| {
+            line["Groups:".len() ..].split_whitespace().filter_map(|gid| gid.parse().ok()).collect()
+        })
+        .unwrap_or_default()
+}
+
+fn check_access(attr: &FileAttr, req: RequestInfo, mask: u32) -> ResultEmpty {
+    let mask = mask & (libc::R_OK | libc::W_OK | libc::X_OK) as u32;
+    if mask == 0 {
+        // F_OK: the file exists, which getattr already established.
+        return Ok(());
+    }
+
+    let perm = attr.perm as u32;
+    let granted = if req.uid == 0 {
+        let exec = if attr.kind == FileType::Directory || perm & 0o111 != 0 {
+            libc::X_OK as u32
+        } else {
+            0
+        };
+        (libc::R_OK | libc::W_OK) as u32 | exec
+    } else if req.uid == attr.uid {
+        (perm >> 6) & 0o7
+    } else if req.gid == attr.gid || process_groups(req.pid).contains(&attr.gid) {
+        (perm >> 3) & 0o7
+    } else {
+        perm & 0o7
+    };
+
+    if granted & mask == mask {
+        Ok(())
+    } else {
+        Err(Errno(libc::EACCES))
+    }
+}
+
+// Reply to getxattr or listxattr with the size or the data, whichever was asked for.
+fn reply_xattr(op: &str, size: u32, result: ResultXattr, reply: ReplyXattr) {
+    match result {
+        Ok(Xattr::Size(len)) if size == 0 => {
+            debug!("{}: sending size {}", op, len);
+            reply.size(len)
+        },
+        Ok(Xattr::Data(ref vec)) if size == 0 => {
+            debug!("{}: sending size {}", op, vec.len());
+            reply.size(vec.len() as u32)
+        },
+        Ok(Xattr::Data(ref vec)) if vec.len() <= size as usize => {
+            debug!("{}: sending {} bytes", op, vec.len());
+            reply.data(vec)
+        },
+        Ok(Xattr::Data(ref vec)) => {
+            debug!("{}: {} bytes don't fit in {}", op, vec.len(), size);
+            reply.error(libc::ERANGE)
+        },
+        Ok(Xattr::Size(len)) if len > size => {
+            debug!("{}: {} bytes don't fit in {}", op, len, size);
+            reply.error(libc::ERANGE)
+        },
+        Ok(Xattr::Size(len)) => {
+            error!("{}: filesystem returned a size ({}) when the data was asked for", op, len);
+            reply.error(libc::EIO)
+        },
+        Err(e) => {
+            debug!("{}: error {}", op, e);
+            reply.error(e.0)
+        },
+    }
+}
+
+// The attributes of a negative directory entry, of which only the inode number matters.
+fn negative_entry_attr() -> FileAttr {
+    FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm: 0,
+        nlink: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: 0,
+        flags: 0,
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "(unknown)"
+    }
+}
+
+// What a dispatched operation results in if the filesystem panics.
+trait Panicked {
+    fn panicked() -> Self;
+}
+
+impl<R> Panicked for Result<R, Errno> {
+    fn panicked() -> Self {
+        Err(Errno(libc::EIO))
+    }
+}
+
+impl Panicked for CallbackResult {
+    fn panicked() -> Self {
+        // The callback was dropped while unwinding, which already failed the read.
+        CallbackResult::dropped()
+    }
+}
+
+// Start a dispatched operation on the filesystem. If it panics, either while starting or while
+// the returned future runs, the operation fails with EIO instead, so that the request still gets
+// a reply and the operations waiting behind it still get to run.
+fn guard<T, F>(op: &'static str, f: F) -> BoxFuture<T>
+    where T: Panicked + Send + 'static,
+          F: FnOnce() -> BoxFuture<T>
+{
+    let on_panic = move|payload: Box<dyn Any + Send>| {
+        error!("{}: filesystem panicked: {}", op, panic_message(&*payload));
+        T::panicked()
+    };
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(future) => executor::catch_unwind(future, on_panic),
+        Err(payload) => Box::pin(future::ready(on_panic(payload))),
+    }
+}
+
+// The listings of open directories, by file handle, when they're being snapshotted.
+type Snapshots = Arc<Mutex<HashMap<u64, Arc<Vec<DirectoryEntry>>>>>;
+
+// Serve the entries of a snapshot, starting at an offset.
+fn snapshot_entries(snapshot: Arc<Vec<DirectoryEntry>>, offset: u64) -> DirectoryEntries {
+    let len = snapshot.len();
+    Box::new((offset as usize..len).map(move|index| Ok(snapshot[index].clone())))
+}
+
+// Read the whole listing from the start, and keep it for the readdir calls that follow on the
+// same handle.
+fn take_snapshot(snapshots: Snapshots, fh: u64, result: ResultReaddir) -> ResultReaddir {
+    // The filesystem's code runs while reading the entries, so guard against panics here too.
+    let listed = panic::catch_unwind(AssertUnwindSafe(|| {
+        result.and_then(|entries| entries.collect::<Result<Vec<DirectoryEntry>, Errno>>())
+    }));
+    let snapshot = match listed {
+        Ok(Ok(mut entries)) => {
+            // The listing continues from its index in the snapshot, rather than from wherever the
+            // filesystem's own offsets would lead.
+            for entry in &mut entries {
+                entry.offset = None;
+            }
+            Arc::new(entries)
+        },
+        Ok(Err(e)) => return Err(e),
+        Err(payload) => {
+            error!("readdir: filesystem panicked: {}", panic_message(&*payload));
+            return Err(Errno(libc::EIO));
+        }
+    };
+    debug!("readdir: snapshot of {} entries", snapshot.len());
+    snapshots.lock().unwrap().insert(fh, snapshot.clone());
+    Ok(snapshot_entries(snapshot, 0))
+}
+
+// The getattr calls in progress, by path and file handle, and the replies to the ones that are
+// waiting for them to complete.
+type GetattrKey = (Arc<PathBuf>, Option<u64>);
+type GetattrReplies = Arc<Mutex<Vec<(Inode, Deadline<ReplyAttr>)>>>;
+type GetattrWaiting = Arc<Mutex<HashMap<GetattrKey, GetattrReplies>>>;
+
+// The getattr calls waiting on one that's in progress. If it never completes (its future is
+// dropped), they fail with EIO, and later calls for the same file don't wait on it anymore.
+struct GetattrFollowers {
+    waiting: Option<GetattrWaiting>,
+    key: GetattrKey,
+    replies: GetattrReplies,
+}
+
+impl GetattrFollowers {
+    fn take(&mut self) -> Vec<(Inode, Deadline<ReplyAttr>)> {
+        match self.waiting.take() {
+            Some(waiting) => {
+                // Once it's out of the map, nothing else can join it. It may be out already,
+                // because the file changed, and another call may have taken its place since.
+                {
+                    let mut waiting = waiting.lock().unwrap();
+                    let ours = match waiting.get(&self.key) {
+                        Some(replies) => Arc::ptr_eq(replies, &self.replies),
+                        None => false,
+                    };
+                    if ours {
+                        waiting.remove(&self.key);
+                    }
+                }
+                mem::take(&mut *self.replies.lock().unwrap())
+            },
+            None => vec![],
+        }
+    }
+
+    fn reply(mut self, result: ResultGetattr) {
+        for (ino, follower) in self.take() {
+            if let Some(reply) = follower.complete() {
+                match result {
+                    Ok((ref ttl, mut attr)) => {
+                        attr.ino = ino;
+                        reply.attr(&ttl_to_timespec(*ttl), &attr.to_fuse())
+                    },
+                    Err(e) => reply.error(e.0),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for GetattrFollowers {
+    fn drop(&mut self) {
+        for (_, follower) in self.take() {
+            if let Some(reply) = follower.complete() {
+                reply.error(libc::EIO);
+            }
+        }
+    }
+}
+
+// Stop getattr calls for a path from waiting on the ones already in progress, after a change to
+// the file has completed: those may have started before it, and return what the file was like
+// then. The calls already waiting on them started before the change completed too, so they still
+// get that result.
+fn forget_getattrs(waiting: &Option<GetattrWaiting>, path: &Path) {
+    if let Some(ref waiting) = *waiting {
+        waiting.lock().unwrap().retain(|key, _| key.0.as_path() != path);
+    }
+}
+
+// Forget the getattr calls in progress for a directory entry, and for its parent, whose times and
+// link count change along with its entries.
+fn forget_entry_getattrs(waiting: &Option<GetattrWaiting>, parent: &Path, name: &OsStr) {
+    if let Some(ref waiting) = *waiting {
+        let path = parent.join(name);
+        waiting.lock().unwrap().retain(|key, _| key.0.as_path() != path && key.0.as_path() != parent);
+    }
+}
+
+// The kinds of operations that can be dispatched to the thread pool, which determine when they're
+// run on the session thread instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Dispatch {
+    /// Reading or writing file data, of the given inode.
+    Data(Inode),
+    /// Getting metadata, which runs on its own threads, so that it isn't held up behind data
+    /// operations. It may also be configured to run on the session thread.
+    Metadata,
+    /// Operations that may block waiting for another operation, which must never run on the
+    /// session thread, or hold up other operations by taking a worker thread.
+    Blocking,
+}
+
+// An operation that hasn't been started yet. Calling it starts the operation, and returns a
+// future that completes when it does.
+type Job = Box<dyn FnOnce() -> BoxFuture<()> + Send>;
+
+// Something that starts running a future, without waiting for it.
+type Launcher = Arc<dyn Fn(BoxFuture<()>) + Send + Sync>;
+
+// Something that starts an operation, possibly after waiting for others to complete.
+type JobLauncher = Arc<dyn Fn(Job) + Send + Sync>;
+
+// The file handles that have operations in progress, and the operations waiting behind them.
+type Lanes = Arc<Mutex<HashMap<u64, VecDeque<Job>>>>;
+
+// Run an operation on a file handle, and when it completes, launch the next one waiting on the
+// same handle, if any.
+fn run_in_lane(lanes: Lanes, fh: u64, job: Job, launch: JobLauncher) -> BoxFuture<()> {
+    executor::then(job(), move|()| {
+        let next = {
+            let mut lanes_map = lanes.lock().unwrap();
+            let next = lanes_map.get_mut(&fh).and_then(|queue| queue.pop_front());
+            if next.is_none() {
+                lanes_map.remove(&fh);
+            }
+            next
+        };
+        if let Some(next) = next {
+            let lanes = lanes.clone();
+            let relaunch = launch.clone();
+            launch(Box::new(move|| run_in_lane(lanes, fh, next, relaunch)));
+        }
+    })
+}
+
+// The operations that have been started but haven't completed yet, and the ones waiting to start
+// because there were too many, along with how to launch them when it's their turn.
+struct InFlight {
+    limit: Option<usize>,
+    count: usize,
+    waiting: VecDeque<(Job, Launcher)>,
+}
+
+type Limiter = Arc<Mutex<InFlight>>;
+
+// Called with the name of each dispatched operation and how long it took; see
+// `FuseMT::operation_hook`.
+type OperationHook = Arc<dyn Fn(&'static str, Duration) + Send + Sync>;
+
+// Get an operation ready to be started now, if there aren't too many in flight. Otherwise, it's
+// put in line, and launched with `later` once enough of the others have completed.
+fn admit(limiter: &Limiter, job: Job, later: Launcher) -> Option<Job> {
+    let mut in_flight = limiter.lock().unwrap();
+    match in_flight.limit {
+        None => Some(job),
+        Some(limit) if in_flight.count >= limit => {
+            debug!("dispatch: {} operations in flight; waiting for one to complete", limit);
+            in_flight.waiting.push_back((job, later));
+            None
+        },
+        Some(_) => {
+            in_flight.count += 1;
+            let limiter = limiter.clone();
+            Some(Box::new(move|| run_limited(limiter, job)))
+        },
+    }
+}
+
+// Run an operation that was admitted, and when it completes, hand its place over to the next one
+// in line, if any.
+fn run_limited(limiter: Limiter, job: Job) -> BoxFuture<()> {
+    executor::then(job(), move|()| {
+        let next = {
+            let mut in_flight = limiter.lock().unwrap();
+            let next = in_flight.waiting.pop_front();
+            if next.is_none() {
+                in_flight.count -= 1;
+            }
+            next
+        };
+        if let Some((next, launch)) = next {
+            let limiter = limiter.clone();
+            launch(executor::lazy(move|| run_limited(limiter, next)));
+        }
+    })
+}
+
+pub struct FuseMT<T: FilesystemMT> {
+    target: Arc<T>,
+    inodes: Arc<InodeCache>,
+    threads: ThreadPool,
+    shards: Vec<ThreadPool>,
+    metadata_threads: ThreadPool,
+    /// The number of operations waiting for a worker thread.
+    queued: Arc<AtomicUsize>,
+    max_queue: Option<usize>,
+    metadata_on_pool: bool,
+    spawner: Option<Launcher>,
+    ordered_handles: bool,
+    fill_reads: bool,
+    lanes: Lanes,
+    in_flight: Limiter,
+    /// The number of dispatched operations that haven't replied yet, which is waited on to reach
+    /// zero when unmounting.
+    outstanding: Arc<(Mutex<usize>, Condvar)>,
+    shutdown_timeout: Duration,
+    timeout: Option<(Duration, libc::c_int)>,
+    watchdog: Arc<Watchdog>,
+    negative_ttl: Option<Timespec>,
+    snapshots: Option<Snapshots>,
+    getattr_waiting: Option<GetattrWaiting>,
+    files: HandleTable<T::FileHandle>,
+    dirs: HandleTable<T::DirHandle>,
+    open_files: HashMap<Inode, Vec<u64>>,
+    state_file: Option<PathBuf>,
+    mount_options: Vec<MountOption>,
+    operation_hook: Option<OperationHook>,
+}
+
+impl<T: FilesystemMT + Sync + Send + 'static> FuseMT<T> {
+    /// Wrap a filesystem, with the default configuration, which the other methods change.
+    ///
+    /// Data operations are dispatched to one worker thread per CPU by default; see `threads`.
+    pub fn new(target_fs: T) -> FuseMT<T> {
+        let num_threads = thread::available_parallelism().map_or(1, |n| n.get());
+        FuseMT {
+            target: Arc::new(target_fs),
+            inodes: Arc::new(InodeCache::new(InodeTable::new())),
+            threads: ThreadPool::new(num_threads),
+            shards: vec![],
+            metadata_threads: ThreadPool::new(1),
+            queued: Arc::new(AtomicUsize::new(0)),
+            max_queue: None,
+            metadata_on_pool: true,
+            spawner: None,
+            ordered_handles: true,
+            fill_reads: false,
+            lanes: Arc::new(Mutex::new(HashMap::new())),
+            in_flight: Arc::new(Mutex::new(InFlight {
+                limit: None,
+                count: 0,
+                waiting: VecDeque::new(),
+            })),
+            outstanding: Arc::new((Mutex::new(0), Condvar::new())),
+            shutdown_timeout: Duration::from_secs(10),
+            timeout: None,
+            watchdog: Watchdog::new(),
+            negative_ttl: None,
+            snapshots: None,
+            getattr_waiting: Some(Arc::new(Mutex::new(HashMap::new()))),
+            files: HandleTable::new(),
+            dirs: HandleTable::new(),
+            open_files: HashMap::new(),
+            state_file: None,
+            mount_options: vec![],
+            operation_hook: None,
+        }
+    }
+
+    /// Change the number of worker threads data operations are dispatched to.
+    ///
+    /// `setlk` isn't run on these, since it can wait a long time for a lock: each call gets a
+    /// thread of its own, so that waiting for locks can't hold up other operations.
+    pub fn threads(mut self, num_threads: usize) -> FuseMT<T> {
+        self.threads = ThreadPool::new(num_threads);
+        self
+    }
+
+    /// Dispatch data operations (`read`, `write`, `flush`, and `fsync`) to the given number of
+    /// single-threaded workers instead, choosing the worker by the file's inode number, so that
+    /// all the operations on a file run on the same thread. This helps when the filesystem keeps
+    /// per-file state that is faster to use from one thread (e.g. a cache per thread), at the cost
+    /// of a busy file only ever getting one thread.
+    pub fn shard_by_inode(mut self, num_shards: usize) -> FuseMT<T> {
+        self.shards = (0..num_shards).map(|_| ThreadPool::new(1)).collect();
+        self
+    }
+
+    /// Change the number of worker threads metadata operations (`getattr` and `readdir`) are
+    /// dispatched to. They have their own threads, so that they are answered promptly even when
+    /// the other threads are busy with e.g. a flood of writes. By default, there is one.
+    pub fn metadata_threads(mut self, num_threads: usize) -> FuseMT<T> {
+        self.metadata_threads = ThreadPool::new(num_threads);
+        self
+    }
+
+    // Get the worker threads to run a kind of operation on.
+    fn pool(&self, kind: Dispatch) -> &ThreadPool {
+        match kind {
+            Dispatch::Metadata => &self.metadata_threads,
+            Dispatch::Data(ino) if !self.shards.is_empty() => {
+                &self.shards[(ino % self.shards.len() as u64) as usize]
+            },
+            // Blocking operations start on threads of their own (see `dispatch`), and only come
+            // back to the pool when an asynchronous one is woken up.
+            Dispatch::Data(_) | Dispatch::Blocking => &self.threads,
+        }
+    }
+
+    /// Add options to mount the filesystem with when it's mounted with `fuse_mt::mount` or
+    /// `fuse_mt::spawn_mount`, e.g. `[MountOption::AllowOther]`. They go before any options
+    /// passed to those functions.
+    pub fn mount_options(mut self, options: &[MountOption]) -> FuseMT<T> {
+        self.mount_options.extend_from_slice(options);
+        self
+    }
+
+    // Get all the thread pools operations are dispatched to, so that they can be waited on.
+    pub(crate) fn worker_pools(&self) -> Vec<ThreadPool> {
+        let mut pools = vec![self.threads.clone(), self.metadata_threads.clone()];
+        pools.extend(self.shards.iter().cloned());
+        pools
+    }
+
+    // Get the configured mount options, followed by the given ones, as arguments for the kernel,
+    // or an error if they can't work. This is only called right before mounting, so it also sizes
+    // `ReadBuffer`s for the largest read the options let the kernel ask for.
+    pub(crate) fn all_mount_options(&self, options: &[MountOption]) -> io::Result<Vec<OsString>> {
+        let options: Vec<MountOption> = self.mount_options.iter().chain(options).cloned().collect();
+        mount::check_options(&options)?;
+        read_buffer::set_max_read(mount::max_read(&options));
+        Ok(mount::mount_args(&options))
+    }
+
+    /// Call a function with the name of each dispatched operation (e.g. "read") and how long it
+    /// took, from when it was received until it replied, e.g. to collect metrics or log slow
+    /// operations. It's called from whichever thread completed the operation, so it should be
+    /// quick.
+    pub fn operation_hook<F>(mut self, hook: F) -> FuseMT<T>
+        where F: Fn(&'static str, Duration) + Send + Sync + 'static
+    {
+        self.operation_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Limit the number of operations that can be waiting for a worker thread. When the limit is
+    /// reached, further operations are run on the session thread instead, which stops more
+    /// requests from being read from the kernel until they are done. By default, the queue is
+    /// unbounded.
+    ///
+    /// Operations that may block waiting for other operations (`setlk`) are never run on the
+    /// session thread; each one gets a thread of its own.
+    pub fn max_queue(mut self, max_queue: usize) -> FuseMT<T> {
+        self.max_queue = Some(max_queue);
+        self
+    }
+
+    /// Choose whether metadata operations (`getattr` and `readdir`) are dispatched to the worker
+    /// threads (the default), or run on the session thread.
+    ///
+    /// Running them on the session thread avoids the cost of handing them off, which is worth it
+    /// when the filesystem can answer them quickly, e.g. from a local disk; when it can't, e.g. when
+    /// it's backed by the network, they'd hold up every other operation.
+    pub fn metadata_on_pool(mut self, on_pool: bool) -> FuseMT<T> {
+        self.metadata_on_pool = on_pool;
+        self
+    }
+
+    // Run an operation on a worker thread, or on this thread if the configuration calls for it.
+    fn dispatch<F: FnOnce() + Send + 'static>(&self, kind: Dispatch, f: F) {
+        if kind == Dispatch::Metadata && !self.metadata_on_pool {
+            return f();
+        }
+
+        // Each one gets a thread of its own: a lock request waiting on a worker thread would take
+        // it away from the other operations, including the unlock that it's waiting for, so
+        // enough of them would leave nothing to run it on.
+        if kind == Dispatch::Blocking {
+            thread::spawn(f);
+            return;
+        }
+
+        if let Some(max_queue) = self.max_queue {
+            if self.queued.load(Ordering::SeqCst) >= max_queue {
+                debug!("dispatch: queue is full; running on the session thread");
+                return f();
+            }
+        }
+
+        let queued = self.queued.clone();
+        queued.fetch_add(1, Ordering::SeqCst);
+        self.pool(kind).execute(move|| {
+            queued.fetch_sub(1, Ordering::SeqCst);
+            f()
+        });
+    }
+
+    /// Run the futures returned by the asynchronous operations (e.g. `read_async`) with the given
+    /// function, e.g. to spawn them on an async runtime, instead of on the worker threads.
+    ///
+    /// The operations are called from the spawned futures when they're first polled, so the
+    /// default implementations, which call the synchronous operations, run wherever the futures
+    /// are polled.
+    pub fn spawn_futures_with<F>(mut self, spawner: F) -> FuseMT<T>
+        where F: Fn(BoxFuture<()>) + Send + Sync + 'static
+    {
+        self.spawner = Some(Arc::new(spawner));
+        self
+    }
+
+    /// Limit the number of operations that are in progress at once. Further operations wait for
+    /// some of them to complete before they are started. By default, there is no limit.
+    ///
+    /// The number of synchronous operations in progress is already limited by the number of
+    /// threads, but asynchronous operations (e.g. `read_async`) don't hold on to a thread while
+    /// they wait, so this can be used to avoid overwhelming e.g. a rate-limited backend.
+    ///
+    /// `setlk` isn't limited, since calls waiting for a lock could otherwise take up every place,
+    /// leaving none for the call that releases it.
+    pub fn max_in_flight(self, max_in_flight: usize) -> FuseMT<T> {
+        self.in_flight.lock().unwrap().limit = Some(max_in_flight);
+        self
+    }
+
+    // Get something that starts a future for a kind of operation from any thread: either the
+    // configured spawner, or one that polls it on the worker threads.
+    fn launcher(&self, kind: Dispatch) -> Launcher {
+        match self.spawner {
+            Some(ref spawner) => spawner.clone(),
+            None => {
+                // The future is launched from whichever thread completes the operation that was
+                // holding it up, so it goes through the thread pool instead of running right
+                // there.
+                let pool = self.pool(kind).clone();
+                Arc::new(move|future| {
+                    let spawn_pool = pool.clone();
+                    pool.execute(move|| executor::spawn(&spawn_pool, future));
+                })
+            }
+        }
+    }
+
+    /// Set how long to wait, when the filesystem is unmounted, for operations that are still in
+    /// progress to complete before calling `destroy`. The default is 10 seconds.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> FuseMT<T> {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Have the kernel remember, for the given time, that names the filesystem returned ENOENT for
+    /// in `lookup` don't exist, so that looking them up again (as e.g. build tools searching for
+    /// include files do a lot) doesn't even reach the filesystem. The kernel forgets them as soon
+    /// as they are created through the mount, but if files can appear some other way (e.g. on a
+    /// network backend), they won't be seen until the time runs out.
+    pub fn negative_lookup_ttl(mut self, ttl: Duration) -> FuseMT<T> {
+        self.negative_ttl = Some(ttl_to_timespec(ttl));
+        self
+    }
+
+    /// Take a snapshot of a directory's whole listing on the first `readdir` of each handle, and
+    /// answer the ones that follow (which continue from where the last one left off) from it,
+    /// until the handle is released. The filesystem then only has to list the directory from the
+    /// start, and the listing stays consistent even if the directory changes in the meantime, but
+    /// the whole of it is held in memory while it's open.
+    pub fn readdir_snapshots(mut self, enabled: bool) -> FuseMT<T> {
+        self.snapshots = if enabled {
+            Some(Arc::new(Mutex::new(HashMap::new())))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Fail dispatched operations that take longer than `timeout` with the given error (e.g.
+    /// `libc::ETIMEDOUT`), so that a hung call to the filesystem doesn't leave the process that
+    /// made it waiting forever. The call itself can't be stopped; when it does complete, its
+    /// result is discarded with a warning. `setlk` isn't subject to this, because waiting for a
+    /// lock can legitimately take any amount of time.
+    ///
+    /// Operations on a file handle that time out still finish before the handle's `release` is
+    /// called, as long as `ordered_handles` is on (the default), so the filesystem never releases
+    /// a handle that is still being used.
+    pub fn operation_timeout(mut self, timeout: Duration, errno: libc::c_int) -> FuseMT<T> {
+        self.timeout = Some((timeout, errno));
+        self
+    }
+
+    // Give an operation's reply the configured deadline, if any.
+    fn deadline<R: FailReply + Send + 'static>(&self, op: &'static str, reply: R) -> Deadline<R> {
+        let watchdog = self.timeout.map(|(timeout, _)| (&self.watchdog, timeout));
+        let errno = self.timeout.map_or(libc::EIO, |(_, errno)| errno);
+        Deadline::new(op, reply, watchdog, move|reply| reply.fail(errno))
+    }
+
+    // Count an operation as outstanding until the future it returns completes.
+    fn track<F>(&self, op: &'static str, f: F) -> Job
+        where F: FnOnce() -> BoxFuture<()> + Send + 'static
+    {
+        let outstanding = self.outstanding.clone();
+        *outstanding.0.lock().unwrap() += 1;
+        let hook = self.operation_hook.clone();
+        let received = Instant::now();
+        Box::new(move|| {
+            executor::then(f(), move|()| {
+                if let Some(hook) = hook {
+                    hook(op, received.elapsed());
+                }
+                let (ref count, ref completed) = *outstanding;
+                *count.lock().unwrap() -= 1;
+                completed.notify_all();
+            })
+        })
+    }
+
+    // Wait until all the outstanding operations have completed, or the shutdown timeout passes.
+    fn drain(&self) {
+        let (ref count, ref completed) = *self.outstanding;
+        let deadline = Instant::now() + self.shutdown_timeout;
+        let mut outstanding = count.lock().unwrap();
+        while *outstanding != 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("destroy: gave up waiting for {} operations to complete", *outstanding);
+                return;
+            }
+            debug!("destroy: waiting for {} operations to complete", *outstanding);
+            outstanding = completed.wait_timeout(outstanding, deadline - now).unwrap().0;
+        }
+    }
+
+    // Dispatch an operation that returns a future, and drive the future to completion on the
+    // worker threads, or with the configured spawner.
+    fn dispatch_future<F>(&self, op: &'static str, kind: Dispatch, f: F)
+        where F: FnOnce() -> BoxFuture<()> + Send + 'static
+    {
+        let job = self.track(op, f);
+        self.start_future(kind, job);
+    }
+
+    fn start_future(&self, kind: Dispatch, job: Job) {
+        let limiter = self.in_flight.clone();
+        let launch = self.launcher(kind);
+
+        // Lock requests don't count towards the limit: enough of them waiting for a lock would
+        // take every place, and the unlock they're waiting for would never get one.
+        if kind == Dispatch::Blocking {
+            if self.spawner.is_some() {
+                launch(executor::lazy(job));
+            } else {
+                let pool = self.pool(kind).clone();
+                self.dispatch(kind, move|| executor::spawn(&pool, job()));
+            }
+            return;
+        }
+
+        if self.spawner.is_some() {
+            if let Some(job) = admit(&limiter, job, launch.clone()) {
+                launch(executor::lazy(job));
+            }
+            return;
+        }
+
+        let pool = self.pool(kind).clone();
+        self.dispatch(kind, move|| {
+            if let Some(job) = admit(&limiter, job, launch) {
+                executor::spawn(&pool, job());
+            }
+        });
     }
 
-    fn chown(&self, _req: RequestInfo, _path: &Path, _fh: Option<u64>, _uid: Option<u32>, _gid: Option<u32>) -> ResultEmpty {
-        Err(libc::ENOSYS)
+    /// Choose whether data operations (`read`, `write`, `flush`, `fsync`, and `release`) on the
+    /// same file handle are run one at a time, in the order they were received. This is the
+    /// default, so that e.g. writes are never reordered, and a handle is never released while
+    /// another operation on it is still running.
+    ///
+    /// Filesystems that can safely handle concurrent operations on a handle can turn this off, so
+    /// that they aren't held up waiting for each other.
+    pub fn ordered_handles(mut self, ordered: bool) -> FuseMT<T> {
+        self.ordered_handles = ordered;
+        self
     }
 
-    fn truncate(&self, _req: RequestInfo, _path: &Path, _fh: Option<u64>, _size: u64) -> ResultEmpty {
-        Err(libc::ENOSYS)
+    /// Choose what happens when `read` returns less data than was asked for. By default, the
+    /// short read is passed on to the kernel as it is, which takes it to mean the end of the file.
+    ///
+    /// With this on, FuseMT calls `read` again for the rest, until it has all of it or `read`
+    /// returns no data (the end of the file) or an error (in which case the data read so far is
+    /// returned, and the error is left for the next read to find). This suits filesystems that
+    /// return data as it becomes available, e.g. from a stream. The pieces are copied into one
+    /// buffer, unless the first read returns everything.
+    pub fn fill_short_reads(mut self, fill: bool) -> FuseMT<T> {
+        self.fill_reads = fill;
+        self
     }
 
-    fn utimens(&self, _req: RequestInfo, _path: &Path, _fh: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>) -> ResultEmpty {
-        Err(libc::ENOSYS)
+    /// Choose whether a `getattr` for a file that another `getattr` is already in progress for
+    /// waits for that one to complete and gets the same result, instead of calling the filesystem
+    /// again. This is the default, so that e.g. many processes looking at the same file at once
+    /// don't each cost a call to a slow backend. (Lookups are done one at a time, so they can't
+    /// overlap like this.)
+    ///
+    /// Once a change to the file made through FuseMT (e.g. a `write`, `setattr`, `rename`, or
+    /// `unlink`) has completed, later calls don't wait on ones that were already in progress, so
+    /// they never get the attributes from before the change. Creating, removing, or renaming an
+    /// entry counts as a change to its parent directory too.
+    pub fn coalesce_getattr(mut self, enabled: bool) -> FuseMT<T> {
+        self.getattr_waiting = if enabled {
+            Some(Arc::new(Mutex::new(HashMap::new())))
+        } else {
+            None
+        };
+        self
     }
 
-    fn utimens_macos(&self, _req: RequestInfo, _path: &Path, _fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>) -> ResultEmpty {
-        Err(libc::ENOSYS)
-    }
+    // Dispatch an operation on a file handle that returns a future. If operations on the same
+    // handle are ordered, and there are some in progress, it waits for them to complete first.
+    fn dispatch_ordered<F>(&self, op: &'static str, kind: Dispatch, fh: u64, f: F)
+        where F: FnOnce() -> BoxFuture<()> + Send + 'static
+    {
+        let job = self.track(op, f);
+        if !self.ordered_handles {
+            return self.start_future(kind, job);
+        }
 
-    // END OF SETATTR FUNCTIONS
+        {
+            let mut lanes = self.lanes.lock().unwrap();
+            if let Some(queue) = lanes.get_mut(&fh) {
+                debug!("dispatch: waiting for operations in progress on handle {}", fh);
+                queue.push_back(job);
+                return;
+            }
+            lanes.insert(fh, VecDeque::new());
+        }
 
-    fn readlink(&self, _req: RequestInfo, _path: &Path) -> ResultData {
-        Err(libc::ENOSYS)
+        let limiter = self.in_flight.clone();
+        let launch_future = self.launcher(kind);
+        let launch: JobLauncher = Arc::new(move|job| {
+            if let Some(job) = admit(&limiter, job, launch_future.clone()) {
+                launch_future(executor::lazy(job));
+            }
+        });
+        let lanes = self.lanes.clone();
+        self.start_future(kind, Box::new(move|| run_in_lane(lanes, fh, job, launch)));
     }
 
-    fn mknod(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32, _rdev: u32) -> ResultEntry {
-        Err(libc::ENOSYS)
+    /// Choose how inode numbers are assigned to paths. By default, they are assigned sequentially.
+    ///
+    /// With `InodeAssignment::HashedPath`, inode numbers are derived from paths, so that they stay
+    /// the same across remounts, e.g. for re-exporting the filesystem over NFS.
+    ///
+    /// With `InodeAssignment::Filesystem`, the inode numbers in the attributes returned by
+    /// `lookup`, `create`, `mknod`, `mkdir`, `symlink`, and `link` are used, so that e.g. hard
+    /// links in a passthrough filesystem have the same number, like they do in the backing
+    /// filesystem.
+    pub fn inode_assignment(self, assignment: InodeAssignment) -> FuseMT<T> {
+        self.inodes.write().set_assignment(assignment);
+        self
     }
 
-    fn mkdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32) -> ResultEntry {
-        Err(libc::ENOSYS)
+    /// Match paths regardless of case, e.g. to present the semantics of SMB or HFS+ filesystems.
+    ///
+    /// A path is passed to the filesystem in the case it was first looked up or created with,
+    /// even if it is later looked up with a different case; the filesystem's own lookups should be
+    /// case-insensitive too.
+    pub fn case_insensitive(self) -> FuseMT<T> {
+        self.inodes.write().set_case_insensitive(true);
+        self
     }
 
-    fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
-        Err(libc::ENOSYS)
+    /// Limit the number of inodes FuseMT keeps track of.
+    ///
+    /// Inodes the kernel holds references to are always kept, but entries that were only added to
+    /// the table when listing directories are evicted, least recently used first, when the table
+    /// grows past this limit. By default, the table is unbounded.
+    pub fn inode_limit(self, limit: usize) -> FuseMT<T> {
+        self.inodes.write().set_limit(Some(limit));
+        self
     }
 
-    fn rmdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
-        Err(libc::ENOSYS)
+    /// Keep the inode table in a file across mounts, so that paths keep their inode numbers.
+    ///
+    /// If the file exists, the inode table is loaded from it when the filesystem is mounted; if it
+    /// can't be loaded (including when it was saved with a different `inode_assignment`), an error
+    /// is logged and FuseMT starts with an empty table. The table is saved to the file when the
+    /// filesystem is unmounted.
+    pub fn inode_state_file<P: AsRef<Path>>(mut self, path: P) -> FuseMT<T> {
+        self.state_file = Some(path.as_ref().to_owned());
+        self
     }
 
-    fn symlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _target: &Path) -> ResultEntry {
-        Err(libc::ENOSYS)
+    // Read the inode table from the state file, if there is one yet.
+    fn load_inode_table(&self, path: &Path) -> io::Result<()> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        self.inodes.write().load(&mut BufReader::new(file))
     }
 
-    fn rename(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _newparent: &Path, _newname: &OsStr) -> ResultEmpty {
-        Err(libc::ENOSYS)
+    // Write the inode table to the state file, replacing it only once it's completely written.
+    fn save_inode_table(&self, path: &Path) -> io::Result<()> {
+        let mut temp_name = path.as_os_str().to_owned();
+        temp_name.push(".tmp");
+        let temp_path = PathBuf::from(temp_name);
+        {
+            let mut file = BufWriter::new(File::create(&temp_path)?);
+            self.inodes.read().save(&mut file)?;
+        }
+        fs::rename(&temp_path, path)
     }
 
-    fn link(&self, _req: RequestInfo, _path: &Path, _newparent: &Path, _newname: &OsStr) -> ResultEntry {
-        Err(libc::ENOSYS)
+    /// Get a handle for inspecting the inode table, which can be used while the filesystem is
+    /// mounted, e.g. to debug stuck mounts or leaked inodes.
+    pub fn inode_table_inspector(&self) -> InodeTableInspector {
+        InodeTableInspector {
+            inodes: self.inodes.clone(),
+        }
     }
 
-    fn open(&self, _req: RequestInfo, _path: &Path, _flags: u32) -> ResultOpen {
-        Err(libc::ENOSYS)
+    // Add a path the kernel was just told about to the inode table, or get its existing inode, and
+    // count the kernel's new reference to it.
+    //
+    // `hint` is the inode number the filesystem returned, which is only used if the filesystem
+    // assigns inode numbers.
+    fn lookup_entry(&self, path: &Path, hint: Inode) -> (Inode, Generation) {
+        let mut inodes = self.inodes.write();
+        let ino = inodes.add_or_get_with_inode(path, hint);
+        inodes.lookup(ino);
+        (ino, inodes.get_generation(ino))
     }
 
-    fn read(&self, _req: RequestInfo, _path: &Path, _fh: u64, _offset: u64, _size: u32) -> ResultData {
-        Err(libc::ENOSYS)
+    fn add_open_file(&mut self, ino: Inode, fh: u64) {
+        self.open_files.entry(ino).or_insert_with(Vec::new).push(fh);
     }
 
-    fn write(&self, _req: RequestInfo, _path: &Path, _fh: u64, _offset: u64, _data: &[u8], _flags: u32) -> ResultWrite {
-        Err(libc::ENOSYS)
+    fn remove_open_file(&mut self, ino: Inode, fh: u64) {
+        let empty = match self.open_files.get_mut(&ino) {
+            Some(handles) => {
+                handles.retain(|&h| h != fh);
+                handles.is_empty()
+            },
+            None => false,
+        };
+        if empty {
+            self.open_files.remove(&ino);
+        }
     }
 
-    fn flush(&self, _req: RequestInfo, _path: &Path, _fh: u64, _lock_owner: u64) -> ResultEmpty {
-        Err(libc::ENOSYS)
+    /// If the inode's path has been unlinked, its last-known path can't be used for operations
+    /// anymore (it may even refer to a different file now), so get a handle to one of its open
+    /// files instead, if there are any.
+    fn deleted_file_handle(&self, ino: Inode) -> Option<u64> {
+        if self.inodes.read().is_deleted(ino) == Some(true) {
+            self.open_files.get(&ino).and_then(|handles| handles.first().cloned())
+        } else {
+            None
+        }
     }
+}
 
-    fn release(&self, _req: RequestInfo, _path: &Path, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> ResultEmpty {
-        Err(libc::ENOSYS)
-    }
+/// A handle for inspecting the inode table of a `FuseMT`, obtained with
+/// `FuseMT::inode_table_inspector`. It can be cloned and sent to other threads.
+#[derive(Clone)]
+pub struct InodeTableInspector {
+    inodes: Arc<InodeCache>,
+}
 
-    fn fsync(&self, _req: RequestInfo, _path: &Path, _fh: u64, _datasync: bool) -> ResultEmpty {
-        Err(libc::ENOSYS)
+impl InodeTableInspector {
+    /// Get the number of inodes in the table, the number of them referenced by the kernel, and an
+    /// estimate of the table's memory use.
+    pub fn stats(&self) -> InodeTableStats {
+        self.inodes.read().stats()
     }
 
-    fn opendir(&self, _req: RequestInfo, _path: &Path, _flags: u32) -> ResultOpen {
-        Err(libc::ENOSYS)
+    /// Get the number of references the kernel holds to an inode, or None, if FuseMT doesn't know
+    /// of it.
+    pub fn lookup_count(&self, ino: u64) -> Option<u64> {
+        self.inodes.read().lookup_count(ino)
     }
 
-    fn readdir(&self, _req: RequestInfo, _path: &Path, _fh: u64, _offset: u64) -> ResultReaddir {
-        Err(libc::ENOSYS)
+    /// Get the paths, lookup counts, and generation numbers of every inode in the table.
+    pub fn dump(&self) -> Vec<InodeInfo> {
+        self.inodes.read().dump()
     }
+}
 
-    fn releasedir(&self, _req: RequestInfo, _path: &Path, _fh: u64, _flags: u32) -> ResultEmpty {
-        Err(libc::ENOSYS)
+macro_rules! get_path {
+    ($s:expr, $ino:expr, $reply:expr) => {
+        {
+            let path = $s.inodes.get_path($ino);
+            if let Some(path) = path {
+                path
+            } else {
+                $reply.error(libc::EINVAL);
+                return;
+            }
+        }
     }
+}
 
-    fn fsyncdir(&self, _req: RequestInfo, _path: &Path, _fh: u64, _datasync: bool) -> ResultEmpty {
-        Err(libc::ENOSYS)
+// Reply with EINVAL if a name from the kernel isn't a single path component, so the filesystem is
+// never asked about one that would be joined onto the parent path as more (or less) than one.
+macro_rules! check_name {
+    ($name:expr, $reply:expr) => {
+        if !valid_name($name) {
+            error!("invalid name {:?}", $name);
+            $reply.error(libc::EINVAL);
+            return;
+        }
     }
+}
 
-    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
-        Err(libc::ENOSYS)
-    }
+// Whether a name can be an entry in a directory: it's not empty, and has no `/` or NUL in it.
+fn valid_name(name: &OsStr) -> bool {
+    !name.is_empty() && !name.as_bytes().iter().any(|&b| b == b'/' || b == 0)
+}
 
-    fn setxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32) -> ResultEmpty {
-        Err(libc::ENOSYS)
+// Get the handle of an open file or directory from the number the kernel refers to it by, or reply
+// with EBADF if there's no such handle in the table.
+macro_rules! get_handle {
+    ($table:expr, $fh:expr, $reply:expr) => {
+        {
+            let handle = $table.get($fh);
+            if let Some(handle) = handle {
+                handle
+            } else {
+                error!("unknown handle {}", $fh);
+                $reply.error(libc::EBADF);
+                return;
+            }
+        }
     }
+}
 
-    fn getxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _size: u32) -> ResultXattr {
-        Err(libc::ENOSYS)
+// Take the reply to an operation that has a deadline, or return if the deadline already passed.
+macro_rules! complete {
+    ($deadline:expr) => {
+        {
+            if let Some(reply) = $deadline.complete() {
+                reply
+            } else {
+                return;
+            }
+        }
     }
+}
 
-    fn listxattr(&self, _req: RequestInfo, _path: &Path, _size: u32) -> ResultXattr {
-        Err(libc::ENOSYS)
-    }
+// The reply to a read. It fails with EIO if the filesystem drops the callback holding it without
+// calling it (e.g. because it panicked).
+struct ReadReply(Option<Deadline<ReplyData>>);
 
-    fn removexattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr) -> ResultEmpty {
-        Err(libc::ENOSYS)
+impl ReadReply {
+    fn send(mut self, result: ResultSlice) -> CallbackResult {
+        if let Some(reply) = self.0.take().and_then(|deadline| deadline.complete()) {
+            match result {
+                Ok(data) => reply.data(data),
+                Err(e) => reply.error(e.0),
+            }
+        }
+        CallbackResult::dropped()
     }
+}
 
-    // access
-
-    fn create(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32, _flags: u32) -> ResultCreate {
-        Err(libc::ENOSYS)
+impl Drop for ReadReply {
+    fn drop(&mut self) {
+        if let Some(reply) = self.0.take().and_then(|deadline| deadline.complete()) {
+            reply.error(libc::EIO);
+        }
     }
+}
 
-    // getlk
+// The buffer being read into, how much of it has been read so far, and the reply to send it with
+// once there's the rest.
+type ShortRead = Arc<Mutex<Option<(ReadBuffer, usize, ReadReply)>>>;
 
-    // setlk
+// A read that calls the filesystem again for the rest of the data whenever it returns less than
+// was asked for; see `FuseMT::fill_short_reads`.
+struct FillRead<T: FilesystemMT> {
+    target: Arc<T>,
+    req: RequestInfo,
+    path: Arc<PathBuf>,
+    fh: Arc<T::FileHandle>,
+    offset: u64,
+    size: u32,
+    short: ShortRead,
+    current: BoxFuture<CallbackResult>,
+}
 
-    // bmap
+impl<T: FilesystemMT + Sync + Send + 'static> FillRead<T> {
+    fn start(target: Arc<T>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<T::FileHandle>,
+             offset: u64, size: u32, reply: ReadReply) -> BoxFuture<CallbackResult> {
+        let short: ShortRead = Arc::new(Mutex::new(None));
+        let callback = fill_callback(size, None, reply, short.clone());
+        let current = target.clone().read_async(req, path.clone(), fh.clone(), offset, size,
+                                                callback);
+        Box::pin(FillRead {
+            target: target,
+            req: req,
+            path: path,
+            fh: fh,
+            offset: offset,
+            size: size,
+            short: short,
+            current: current,
+        })
+    }
 }
 
-pub struct FuseMT<T> {
-    target: Arc<T>,
-    inodes: InodeTable,
-    threads: ThreadPool,
+// Handle one piece of a read: reply if it completes the read, or otherwise hand what there is so
+// far back to the FillRead for another read. Nothing is copied into a buffer (borrowed from the
+// `ReadBuffer` pool) unless the first piece is short.
+fn fill_callback(size: u32, so_far: Option<(ReadBuffer, usize)>, reply: ReadReply,
+                 short: ShortRead) -> ReadCallback {
+    Box::new(move|result| {
+        let size = size as usize;
+        let (mut buf, done) = match so_far {
+            Some(so_far) => so_far,
+            None => match result {
+                Ok(data) if data.is_empty() || data.len() >= size => return reply.send(Ok(data)),
+                Err(e) => return reply.send(Err(e)),
+                Ok(_) => (ReadBuffer::new(size), 0),
+            },
+        };
+        match result {
+            Ok(data) if data.is_empty() || done + data.len() >= size => {
+                let n = ::std::cmp::min(data.len(), size - done);
+                buf[done .. done + n].copy_from_slice(&data[.. n]);
+                let result = reply.send(Ok(&buf[.. done + n]));
+                // The kernel has the data now, so the buffer can go back to the pool.
+                drop(buf);
+                result
+            },
+            Ok(data) => {
+                buf[done .. done + data.len()].copy_from_slice(data);
+                *short.lock().unwrap() = Some((buf, done + data.len(), reply));
+                CallbackResult::dropped()
+            },
+            Err(e) => {
+                debug!("read: error {} after {:#x} bytes; returning them", e, done);
+                reply.send(Ok(&buf[.. done]))
+            },
+        }
+    })
 }
 
-impl<T: FilesystemMT + Sync + Send + 'static> FuseMT<T> {
-    pub fn new(target_fs: T, num_threads: usize) -> FuseMT<T> {
-        FuseMT {
-            target: Arc::new(target_fs),
-            inodes: InodeTable::new(),
-            threads: ThreadPool::new(num_threads),
+impl<T: FilesystemMT + Sync + Send + 'static> Future for FillRead<T> {
+    type Output = CallbackResult;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<CallbackResult> {
+        loop {
+            let result = match self.current.as_mut().poll(cx) {
+                Poll::Ready(result) => result,
+                Poll::Pending => return Poll::Pending,
+            };
+            let (buf, done, reply) = match self.short.lock().unwrap().take() {
+                Some(short) => short,
+                None => return Poll::Ready(result),
+            };
+            debug!("read: {:?} short by {:#x}; reading the rest", self.path,
+                   self.size as usize - done);
+            let callback = fill_callback(self.size, Some((buf, done)), reply, self.short.clone());
+            self.current = self.target.clone().read_async(self.req, self.path.clone(),
+                self.fh.clone(), self.offset + done as u64, self.size - done as u32, callback);
         }
     }
 }
 
-macro_rules! get_path {
-    ($s:expr, $ino:expr, $reply:expr) => {
-        if let Some(path) = $s.inodes.get_path($ino) {
-            path
-        } else {
-            $reply.error(libc::EINVAL);
-            return;
-        }
+// The replies to operations that can time out, which all fail the same way.
+trait FailReply {
+    fn fail(self, errno: libc::c_int);
+}
+
+macro_rules! fail_reply {
+    ($($reply:ty),*) => {
+        $(
+            impl FailReply for $reply {
+                fn fail(self, errno: libc::c_int) {
+                    self.error(errno);
+                }
+            }
+        )*
     }
 }
 
+fail_reply!(ReplyAttr, ReplyData, ReplyWrite, ReplyEmpty, ReplyDirectory);
+
 impl<T: FilesystemMT + Sync + Send + 'static> Filesystem for FuseMT<T> {
     fn init(&mut self, req: &Request) -> Result<(), libc::c_int> {
         debug!("init");
-        self.target.init(req.info())
+        // Loaded now rather than when the file was given, so that all the options that affect the
+        // table have been set.
+        if let Some(ref path) = self.state_file {
+            match self.load_inode_table(path) {
+                Ok(()) => debug!("init: loaded inode table from {:?}", path),
+                Err(e) => error!("init: unable to load inode table from {:?}: {}", path, e),
+            }
+        }
+        self.target.init(req.info()).map_err(|e| e.0)
     }
 
     fn destroy(&mut self, req: &Request) {
         debug!("destroy");
+        // The kernel won't send any more requests, but the ones already dispatched still need to
+        // finish before the filesystem is torn down.
+        self.drain();
+        debug!("destroy: {} inodes were evicted from the inode table",
+               self.inodes.read().evictions());
+        if let Some(ref path) = self.state_file {
+            match self.save_inode_table(path) {
+                Ok(()) => debug!("destroy: saved inode table to {:?}", path),
+                Err(e) => error!("destroy: unable to save inode table to {:?}: {}", path, e),
+            }
+        }
         self.target.destroy(req.info());
     }
 
     fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         let parent_path = get_path!(self, parent, reply);
+        check_name!(name, reply);
         debug!("lookup: {:?}, {:?}", parent_path, name);
-        let path = Arc::new((*parent_path).clone().join(name));
         match self.target.lookup(req.info(), Path::new(&*parent_path), name) {
-            Ok((ref ttl, ref mut attr, generation)) => {
-                let ino = self.inodes.add_or_get(path.clone());
-                self.inodes.lookup(ino);
+            Ok(Entry { ref ttl, ref mut attr }) => {
+                let (ino, generation) = self.lookup_entry(&parent_path.join(name), attr.ino);
                 attr.ino = ino;
-                reply.entry(ttl, attr, generation);
+                reply.entry(&ttl_to_timespec(*ttl), &attr.to_fuse(), generation);
             },
-            Err(e) => reply.error(e),
+            Err(Errno(libc::ENOENT)) if self.negative_ttl.is_some() => {
+                // An entry with inode 0 tells the kernel that the name doesn't exist, and for how
+                // long it can assume that.
+                let ttl = self.negative_ttl.unwrap();
+                reply.entry(&ttl, &negative_entry_attr().to_fuse(), 0);
+            },
+            Err(e) => reply.error(e.0),
         }
     }
 
     fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
-        let path = self.inodes.get_path(ino).unwrap();
-        let lookups = self.inodes.forget(ino, nlookup);
-        debug!("forget: inode {} ({:?}) now at {} lookups", ino, path, lookups);
+        let mut inodes = self.inodes.write();
+        let path = match inodes.get_path(ino) {
+            Some(path) => path,
+            None => {
+                error!("forget: unknown inode {}", ino);
+                return;
+            }
+        };
+        let deleted = inodes.is_deleted(ino) == Some(true);
+        let lookups = inodes.forget(ino, nlookup).unwrap_or(0);
+        debug!("forget: inode {} ({:?}{}) now at {} lookups", ino, path,
+               if deleted { ", deleted" } else { "" }, lookups);
     }
 
     fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
         let path = get_path!(self, ino, reply);
-        debug!("getattr: {:?}", path);
-        match self.target.getattr(req.info(), &path, None) {
-            Ok((ref ttl, ref attr)) => reply.attr(ttl, attr),
-            Err(e) => reply.error(e),
+        let fh = self.deleted_file_handle(ino);
+        debug!("getattr: {:?}{}", path, if fh.is_some() { " (deleted)" } else { "" });
+        let target = self.target.clone();
+        let req_info = req.info();
+        let reply = self.deadline("getattr", reply);
+
+        let key = (path.clone(), fh);
+        let fh = fh.and_then(|fh| self.files.get(fh));
+        let replies = GetattrReplies::default();
+        if let Some(ref waiting) = self.getattr_waiting {
+            let mut waiting = waiting.lock().unwrap();
+            if let Some(followers) = waiting.get(&key) {
+                debug!("getattr: {:?} is already in progress; waiting for it", path);
+                followers.lock().unwrap().push((ino, reply));
+                return;
+            }
+            waiting.insert(key.clone(), replies.clone());
         }
+        let followers = GetattrFollowers {
+            waiting: self.getattr_waiting.clone(),
+            key: key,
+            replies: replies,
+        };
+
+        self.dispatch_future("getattr", Dispatch::Metadata, move|| {
+            let future = guard("getattr", move|| target.getattr_async(req_info, path, fh));
+            executor::then(future, move|mut result| {
+                followers.reply(result);
+                let reply = complete!(reply);
+                match result {
+                    Ok((ref ttl, ref mut attr)) => {
+                        attr.ino = ino;
+                        reply.attr(&ttl_to_timespec(*ttl), &attr.to_fuse())
+                    },
+                    Err(e) => reply.error(e.0),
+                }
+            })
+        });
     }
 
     fn setattr(&mut self,
-               req: &Request,               // passed to all
-               ino: u64,                    // translated to path; passed to all
-               mode: Option<u32>,           // chmod
-               uid: Option<u32>,            // chown
-               gid: Option<u32>,            // chown
-               size: Option<u64>,           // truncate
-               atime: Option<Timespec>,     // utimens
-               mtime: Option<Timespec>,     // utimens
-               fh: Option<u64>,             // passed to all
-               crtime: Option<Timespec>,    // utimens_osx  (OS X only)
-               chgtime: Option<Timespec>,   // utimens_osx  (OS X only)
-               bkuptime: Option<Timespec>,  // utimens_osx  (OS X only)
-               flags: Option<u32>,          // utimens_osx  (OS X only)
+               req: &Request,
+               ino: u64,
+               mode: Option<u32>,
+               uid: Option<u32>,
+               gid: Option<u32>,
+               size: Option<u64>,
+               atime: Option<Timespec>,
+               mtime: Option<Timespec>,
+               fh: Option<u64>,
+               crtime: Option<Timespec>,    // OS X only
+               chgtime: Option<Timespec>,   // OS X only
+               bkuptime: Option<Timespec>,  // OS X only
+               flags: Option<u32>,          // OS X only
                reply: ReplyAttr) {
         let path = get_path!(self, ino, reply);
         debug!("setattr: {:?}", path);
@@ -319,211 +2134,301 @@ impl<T: FilesystemMT + Sync + Send + 'static> Filesystem for FuseMT<T> {
         debug!("\tmtime:\t{:?}", mtime);
         debug!("\tfh:\t{:?}", fh);
 
-        // TODO: figure out what C FUSE does when only some of these are implemented.
-
-        if mode.is_some() {
-            if let Err(e) = self.target.chmod(req.info(), &path, fh, mode.unwrap()) {
-                reply.error(e);
-                return;
-            }
-        }
-
-        if uid.is_some() || gid.is_some() {
-            if let Err(e) = self.target.chown(req.info(), &path, fh, uid, gid) {
-                reply.error(e);
-                return;
-            }
-        }
-
-        if size.is_some() {
-            if let Err(e) = self.target.truncate(req.info(), &path, fh, size.unwrap()) {
-                reply.error(e);
-                return;
-            }
-        }
-
-        if atime.is_some() || mtime.is_some() {
-            if let Err(e) = self.target.utimens(req.info(), &path, fh, atime, mtime) {
-                reply.error(e);
-                return;
-            }
-        }
-
-        if crtime.is_some() || chgtime.is_some() || bkuptime.is_some() || flags.is_some() {
-            if let Err(e) = self.target.utimens_macos(req.info(), &path, fh, crtime, chgtime, bkuptime, flags) {
-                reply.error(e);
-                return
-            }
-        }
+        let fh = fh.or_else(|| self.deleted_file_handle(ino)).and_then(|fh| self.files.get(fh));
+
+        let attrs = SetAttrRequest {
+            mode: mode,
+            uid: uid,
+            gid: gid,
+            size: size,
+            // rust-fuse doesn't say when the kernel means the current time (FATTR_ATIME_NOW and
+            // FATTR_MTIME_NOW), but then it also gives the current time, so it can be used as it is.
+            atime: atime.map(|time| TimeOrNow::SpecificTime(from_timespec(time))),
+            mtime: mtime.map(|time| TimeOrNow::SpecificTime(from_timespec(time))),
+            crtime: crtime.map(from_timespec),
+            chgtime: chgtime.map(from_timespec),
+            bkuptime: bkuptime.map(from_timespec),
+            flags: flags,
+        };
 
-        match self.target.getattr(req.info(), &path, fh) {
-            Ok((ref ttl, ref attr)) => reply.attr(ttl, attr),
-            Err(e) => reply.error(e),
+        let mut result = self.target.setattr(req.info(), &path, fh.as_deref(), attrs);
+        forget_getattrs(&self.getattr_waiting, &path);
+        match result {
+            Ok((ref ttl, ref mut attr)) => {
+                attr.ino = ino;
+                reply.attr(&ttl_to_timespec(*ttl), &attr.to_fuse())
+            },
+            Err(e) => reply.error(e.0),
         }
-   }
+    }
 
     fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
         let path = get_path!(self, ino, reply);
         debug!("readlink: {:?}", path);
         match self.target.readlink(req.info(), &path) {
             Ok(data) => reply.data(&data),
-            Err(e) => reply.error(e),
+            Err(e) => reply.error(e.0),
         }
     }
 
     fn mknod(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32, reply: ReplyEntry) {
         let parent_path = get_path!(self, parent, reply);
-        debug!("mknod: {:?}/{:?}", parent_path, name);
-        match self.target.mknod(req.info(), &parent_path, name, mode, rdev) {
-            Ok((ref ttl, ref attr, generation)) => reply.entry(ttl, attr, generation),
-            Err(e) => reply.error(e),
+        check_name!(name, reply);
+        debug!("mknod: {:?}/{:?} (mode={:#o}, rdev={:?})", parent_path, name, mode,
+               DeviceNumber::from_rdev(rdev));
+        let mut result = self.target.mknod(req.info(), &parent_path, name, mode, rdev);
+        forget_entry_getattrs(&self.getattr_waiting, &parent_path, name);
+        match result {
+            Ok(Entry { ref ttl, ref mut attr }) => {
+                let (ino, generation) = self.lookup_entry(&parent_path.join(name), attr.ino);
+                attr.ino = ino;
+                reply.entry(&ttl_to_timespec(*ttl), &attr.to_fuse(), generation)
+            },
+            Err(e) => reply.error(e.0),
         }
     }
 
     fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
         let parent_path = get_path!(self, parent, reply);
+        check_name!(name, reply);
         debug!("mkdir: {:?}/{:?}", parent_path, name);
-        match self.target.mkdir(req.info(), &parent_path, name, mode) {
-            Ok((ref ttl, ref mut attr, generation)) => {
-                let ino = self.inodes.add(Arc::new(parent_path.join(name)));
+        let mut result = self.target.mkdir(req.info(), &parent_path, name, mode);
+        forget_entry_getattrs(&self.getattr_waiting, &parent_path, name);
+        match result {
+            Ok(Entry { ref ttl, ref mut attr }) => {
+                let (ino, generation) = self.lookup_entry(&parent_path.join(name), attr.ino);
                 attr.ino = ino;
-                reply.entry(ttl, attr, generation)
+                reply.entry(&ttl_to_timespec(*ttl), &attr.to_fuse(), generation)
             },
-            Err(e) => reply.error(e),
+            Err(e) => reply.error(e.0),
         }
     }
 
     fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let parent_path = get_path!(self, parent, reply);
+        check_name!(name, reply);
         debug!("unlink: {:?}/{:?}", parent_path, name);
-        match self.target.unlink(req.info(), &parent_path, name) {
-            Ok(()) => reply.ok(),
-            Err(e) => reply.error(e),
+        let result = self.target.unlink(req.info(), &parent_path, name);
+        forget_entry_getattrs(&self.getattr_waiting, &parent_path, name);
+        match result {
+            Ok(()) => {
+                // The file may still be open, so the inode can't be dropped until it's forgotten.
+                self.inodes.write().unlink(&parent_path.join(name));
+                reply.ok()
+            },
+            Err(e) => reply.error(e.0),
         }
     }
 
     fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let parent_path = get_path!(self, parent, reply);
+        check_name!(name, reply);
         debug!("rmdir: {:?}/{:?}", parent_path, name);
-        match self.target.rmdir(req.info(), &parent_path, name) {
-            Ok(()) => reply.ok(),
-            Err(e) => reply.error(e),
+        let result = self.target.rmdir(req.info(), &parent_path, name);
+        forget_entry_getattrs(&self.getattr_waiting, &parent_path, name);
+        match result {
+            Ok(()) => {
+                self.inodes.write().unlink(&parent_path.join(name));
+                reply.ok()
+            },
+            Err(e) => reply.error(e.0),
         }
     }
 
     fn symlink(&mut self, req: &Request, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
         let parent_path = get_path!(self, parent, reply);
+        check_name!(name, reply);
         debug!("symlink: {:?}/{:?} -> {:?}", parent_path, name, link);
-        match self.target.symlink(req.info(), &parent_path, name, link) {
-            Ok((ref ttl, ref mut attr, generation)) => {
-                let ino = self.inodes.add(Arc::new(parent_path.join(name)));
+        let mut result = self.target.symlink(req.info(), &parent_path, name, link);
+        forget_entry_getattrs(&self.getattr_waiting, &parent_path, name);
+        match result {
+            Ok(Entry { ref ttl, ref mut attr }) => {
+                let (ino, generation) = self.lookup_entry(&parent_path.join(name), attr.ino);
                 attr.ino = ino;
-                reply.entry(ttl, attr, generation)
+                reply.entry(&ttl_to_timespec(*ttl), &attr.to_fuse(), generation)
             },
-            Err(e) => reply.error(e),
+            Err(e) => reply.error(e.0),
         }
     }
 
     fn rename(&mut self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, reply: ReplyEmpty) {
         let parent_path = get_path!(self, parent, reply);
         let newparent_path = get_path!(self, newparent, reply);
+        check_name!(name, reply);
+        check_name!(newname, reply);
         debug!("rename: {:?}/{:?} -> {:?}/{:?}", parent_path, name, newparent_path, newname);
-        match self.target.rename(req.info(), &parent_path, name, &newparent_path, newname) {
+        let result = self.target.rename(req.info(), &parent_path, name, &newparent_path, newname);
+        forget_entry_getattrs(&self.getattr_waiting, &parent_path, name);
+        forget_entry_getattrs(&self.getattr_waiting, &newparent_path, newname);
+        match result {
             Ok(()) => {
-                self.inodes.rename(&parent_path.join(name), Arc::new(newparent_path.join(newname)));
+                self.inodes.write()
+                    .rename(&parent_path.join(name), &newparent_path.join(newname));
                 reply.ok()
             },
-            Err(e) => reply.error(e),
+            Err(e) => reply.error(e.0),
         }
     }
 
     fn link(&mut self, req: &Request, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
         let path = get_path!(self, ino, reply);
         let newparent_path = get_path!(self, newparent, reply);
+        check_name!(newname, reply);
         debug!("link: {:?} -> {:?}/{:?}", path, newparent_path, newname);
-        match self.target.link(req.info(), &path, &newparent_path, newname) {
-            Ok((ref ttl, ref mut attr, generation)) => {
-                // NOTE: this results in the new link having a different inode from the original.
-                // This is needed because our inode table is a 1:1 map between paths and inodes.
-                let new_ino = self.inodes.add(Arc::new(newparent_path.join(newname)));
-                attr.ino = new_ino;
-                reply.entry(ttl, attr, generation);
+        let mut result = self.target.link(req.info(), &path, &newparent_path, newname);
+        forget_getattrs(&self.getattr_waiting, &path);
+        forget_entry_getattrs(&self.getattr_waiting, &newparent_path, newname);
+        match result {
+            Ok(Entry { ref ttl, ref mut attr }) => {
+                // The new link refers to the same inode as the original.
+                let generation = {
+                    let mut inodes = self.inodes.write();
+                    if inodes.add_link(ino, &newparent_path.join(newname)) {
+                        inodes.lookup(ino);
+                        Some(inodes.get_generation(ino))
+                    } else {
+                        None
+                    }
+                };
+                match generation {
+                    Some(generation) => {
+                        attr.ino = ino;
+                        reply.entry(&ttl_to_timespec(*ttl), &attr.to_fuse(), generation);
+                    },
+                    None => {
+                        // The inode was forgotten while the filesystem was making the link.
+                        error!("link: inode {} is no longer in the inode table", ino);
+                        reply.error(libc::ENOENT);
+                    },
+                }
             },
-            Err(e) => reply.error(e),
+            Err(e) => reply.error(e.0),
         }
     }
 
     fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
         let path = get_path!(self, ino, reply);
         debug!("open: {:?}", path);
-        match self.target.open(req.info(), &path, flags) {
-            Ok((fh, flags)) => reply.opened(fh, flags),
-            Err(e) => reply.error(e),
+        match self.target.open(req.info(), &path, FileFlags::new(flags)) {
+            Ok((handle, flags)) => {
+                let fh = self.files.insert(handle);
+                self.add_open_file(ino, fh);
+                reply.opened(fh, flags.bits())
+            },
+            Err(e) => reply.error(e.0),
         }
     }
 
     fn read(&mut self, req: &Request, ino: u64, fh: u64, offset: u64, size: u32, reply: ReplyData) {
         let path = get_path!(self, ino, reply);
         debug!("read: {:?} {:#x} @ {:#x}", path, size, offset);
+        let handle = get_handle!(self.files, fh, reply);
         let target = self.target.clone();
         let req_info = req.info();
-        self.threads.execute(move|| {
-            match target.read(req_info, &path, fh, offset, size) {
-                Ok(ref data) => reply.data(data),
-                Err(e) => reply.error(e),
-            }
+        let reply = ReadReply(Some(self.deadline("read", reply)));
+        let fill = self.fill_reads;
+        self.dispatch_ordered("read", Dispatch::Data(ino), fh, move|| {
+            let future = guard("read", move|| {
+                if fill {
+                    FillRead::start(target, req_info, path, handle, offset, size, reply)
+                } else {
+                    let callback: ReadCallback = Box::new(move|result| reply.send(result));
+                    target.read_async(req_info, path, handle, offset, size, callback)
+                }
+            });
+            executor::then(future, |_: CallbackResult| ())
         });
     }
 
     fn write(&mut self, req: &Request, ino: u64, fh: u64, offset: u64, data: &[u8], flags: u32, reply: ReplyWrite) {
         let path = get_path!(self, ino, reply);
         debug!("write: {:?} {:#x} @ {:#x}", path, data.len(), offset);
+        let handle = get_handle!(self.files, fh, reply);
         let target = self.target.clone();
         let req_info = req.info();
 
         // TODO: it would be better if rust-fuse gave us the buffer by value so we could avoid this copy
         let data_buf = Vec::from(data);
 
-        self.threads.execute(move|| {
-            match target.write(req_info, &path, fh, offset, &data_buf, flags) {
-                Ok(written) => reply.written(written),
-                Err(e) => reply.error(e),
-            }
+        let reply = self.deadline("write", reply);
+        let getattr_waiting = self.getattr_waiting.clone();
+        self.dispatch_ordered("write", Dispatch::Data(ino), fh, move|| {
+            let written_path = path.clone();
+            let future = guard("write", move|| {
+                target.write_async(req_info, path, handle, offset, data_buf, flags)
+            });
+            executor::then(future, move|result| {
+                forget_getattrs(&getattr_waiting, &written_path);
+                let reply = complete!(reply);
+                match result {
+                    Ok(written) => reply.written(written),
+                    Err(e) => reply.error(e.0),
+                }
+            })
         });
     }
 
     fn flush(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
         let path = get_path!(self, ino, reply);
         debug!("flush: {:?}", path);
+        let handle = get_handle!(self.files, fh, reply);
         let target = self.target.clone();
         let req_info = req.info();
-        self.threads.execute(move|| {
-            match target.flush(req_info, &path, fh, lock_owner) {
-                Ok(()) => reply.ok(),
-                Err(e) => reply.error(e),
-            }
+        let reply = self.deadline("flush", reply);
+        self.dispatch_ordered("flush", Dispatch::Data(ino), fh, move|| {
+            let future = guard("flush", move|| {
+                target.flush_async(req_info, path, handle, lock_owner)
+            });
+            executor::then(future, move|result| {
+                let reply = complete!(reply);
+                match result {
+                    Ok(()) => reply.ok(),
+                    Err(e) => reply.error(e.0),
+                }
+            })
         });
     }
 
     fn release(&mut self, req: &Request, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool, reply: ReplyEmpty) {
         let path = get_path!(self, ino, reply);
         debug!("release: {:?}", path);
-        match self.target.release(req.info(), &path, fh, flags, lock_owner, flush) {
-            Ok(()) => reply.ok(),
-            Err(e) => reply.error(e),
-        }
+        let handle = get_handle!(self.files, fh, reply);
+        self.files.remove(fh);
+        self.remove_open_file(ino, fh);
+        let target = self.target.clone();
+        let req_info = req.info();
+        // Queued behind the handle's other operations, including ones that timed out but are
+        // still running, so that the handle isn't released out from under them.
+        self.dispatch_ordered("release", Dispatch::Data(ino), fh, move|| {
+            let future = guard("release", move|| {
+                Box::pin(future::ready(target.release(req_info, &path, &handle, flags, lock_owner,
+                                                      flush)))
+            });
+            executor::then(future, move|result| match result {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e.0),
+            })
+        });
     }
 
     fn fsync(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         let path = get_path!(self, ino, reply);
         debug!("fsync: {:?}", path);
+        let handle = get_handle!(self.files, fh, reply);
         let target = self.target.clone();
         let req_info = req.info();
-        self.threads.execute(move|| {
-            match target.fsync(req_info, &path, fh, datasync) {
-                Ok(()) => reply.ok(),
-                Err(e) => reply.error(e),
-            }
+        let reply = self.deadline("fsync", reply);
+        self.dispatch_ordered("fsync", Dispatch::Data(ino), fh, move|| {
+            let future = guard("fsync", move|| {
+                target.fsync_async(req_info, path, handle, datasync)
+            });
+            executor::then(future, move|result| {
+                let reply = complete!(reply);
+                match result {
+                    Ok(()) => reply.ok(),
+                    Err(e) => reply.error(e.0),
+                }
+            })
         });
     }
 
@@ -531,21 +2436,60 @@ impl<T: FilesystemMT + Sync + Send + 'static> Filesystem for FuseMT<T> {
         let path = get_path!(self, ino, reply);
         debug!("opendir: {:?}", path);
         match self.target.opendir(req.info(), &path, flags) {
-            Ok((fh, flags)) => reply.opened(fh, flags),
-            Err(e) => reply.error(e),
+            Ok((handle, flags)) => {
+                let fh = self.dirs.insert(handle);
+                reply.opened(fh, flags.bits())
+            },
+            Err(e) => reply.error(e.0),
         }
     }
 
-    fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: u64, mut reply: ReplyDirectory) {
+    fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: u64, reply: ReplyDirectory) {
         let path = get_path!(self, ino, reply);
         debug!("readdir: {:?} @ {}", path, offset);
-        match self.target.readdir(req.info(), &path, fh, offset) {
-            Ok(entries) => {
+        let handle = get_handle!(self.dirs, fh, reply);
+        let target = self.target.clone();
+        let inodes = self.inodes.clone();
+        let req_info = req.info();
+        let reply = self.deadline("readdir", reply);
+
+        // Continue from the snapshot, if there is one. Reading from the start takes a new one.
+        let snapshots = self.snapshots.clone();
+        let snapshot = match snapshots {
+            Some(ref snapshots) if offset > 0 => snapshots.lock().unwrap().get(&fh).cloned(),
+            _ => None,
+        };
+
+        self.dispatch_future("readdir", Dispatch::Metadata, move|| {
+            let future = if let Some(snapshot) = snapshot {
+                Box::pin(future::ready(Ok(snapshot_entries(snapshot, offset))))
+            } else {
+                let dir_path = path.clone();
+                let future = guard("readdir", move|| {
+                    target.readdir_async(req_info, dir_path, handle, offset)
+                });
+                match snapshots {
+                    Some(snapshots) if offset == 0 => {
+                        executor::then(future, move|result| take_snapshot(snapshots, fh, result))
+                    },
+                    _ => future,
+                }
+            };
+            executor::then(future, move|result| {
+                let mut reply = complete!(reply);
+                let mut entries = match result {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        reply.error(e.0);
+                        return;
+                    }
+                };
+
                 let parent_inode = if ino == 1 {
                     ino
                 } else {
                     let parent_path: &Path = path.parent().unwrap();
-                    match self.inodes.get_inode(parent_path) {
+                    match inodes.get_inode(parent_path) {
                         Some(inode) => inode,
                         None => {
                             error!("readdir: unable to get inode for parent of {:?}", path);
@@ -555,52 +2499,103 @@ impl<T: FilesystemMT + Sync + Send + 'static> Filesystem for FuseMT<T> {
                     }
                 };
 
-                let mut index = 0;
-                for entry in entries {
-                    let entry_inode = if entry.name == Path::new(".") {
+                // The offset of each entry (unless the filesystem gave its own) is the index of
+                // the one after it, which is where the next call picks up if this one runs out of
+                // room.
+                let mut next_offset = offset;
+                // Entries with invalid names are skipped, so this isn't always the same as how
+                // far the offset has moved.
+                let mut added = 0;
+                loop {
+                    // Reading the entries runs the filesystem's code too, so it needs the same
+                    // protection against panics as starting the operation did.
+                    let entry = match panic::catch_unwind(AssertUnwindSafe(|| entries.next())) {
+                        Ok(Some(entry)) => entry,
+                        Ok(None) => break,
+                        Err(payload) => {
+                            error!("readdir: filesystem panicked: {}", panic_message(&*payload));
+                            Err(Errno(libc::EIO))
+                        }
+                    };
+                    let entry = match entry {
+                        Ok(entry) => entry,
+                        Err(e) if added == 0 => {
+                            reply.error(e.0);
+                            return;
+                        },
+                        Err(e) => {
+                            // Return what was listed so far; the next call gets the error.
+                            debug!("readdir: {:?}: error {} after {} entries", path, e, added);
+                            break;
+                        }
+                    };
+
+                    if !valid_name(&entry.name) {
+                        error!("readdir: {:?}: leaving out entry with invalid name {:?}", path,
+                               entry.name);
+                        next_offset += 1;
+                        continue;
+                    }
+
+                    let entry_inode = if entry.name == "." {
                         ino
-                    } else if entry.name == Path::new("..") {
+                    } else if entry.name == ".." {
                         parent_inode
                     } else {
-                        let path = Arc::new(path.clone().join(&entry.name));
-                        self.inodes.add_or_get(path)
+                        // The lock is only held for each entry, since reading the next one may
+                        // take a while.
+                        match inodes.write().add_or_get_child(ino, &entry.name) {
+                            Some(inode) => inode,
+                            None => {
+                                error!("readdir: directory {:?} is no longer in the inode table",
+                                       path);
+                                reply.error(libc::EIO);
+                                return;
+                            }
+                        }
                     };
 
                     let buffer_full: bool = reply.add(
                         entry_inode,
-                        index,
-                        entry.kind,
-                        entry.name.as_os_str());
+                        entry.offset.unwrap_or(next_offset + 1),
+                        entry.kind.to_fuse(),
+                        &entry.name);
 
                     if buffer_full {
                         debug!("readdir: reply buffer is full");
                         break;
                     }
 
-                    index += 1;
+                    next_offset += 1;
+                    added += 1;
                 }
 
                 reply.ok();
-            },
-            Err(e) => reply.error(e),
-        }
+            })
+        });
     }
 
     fn releasedir(&mut self, req: &Request, ino: u64, fh: u64, flags: u32, reply: ReplyEmpty) {
         let path = get_path!(self, ino, reply);
         debug!("releasedir: {:?}", path);
-        match self.target.releasedir(req.info(), &path, fh, flags) {
+        let handle = get_handle!(self.dirs, fh, reply);
+        self.dirs.remove(fh);
+        if let Some(ref snapshots) = self.snapshots {
+            snapshots.lock().unwrap().remove(&fh);
+        }
+        match self.target.releasedir(req.info(), &path, &handle, flags) {
             Ok(()) => reply.ok(),
-            Err(e) => reply.error(e),
+            Err(e) => reply.error(e.0),
         }
     }
 
     fn fsyncdir(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         let path = get_path!(self, ino, reply);
         debug!("fsyncdir: {:?} (datasync: {:?})", path, datasync);
-        match self.target.fsyncdir(req.info(), &path, fh, datasync) {
+        let handle = get_handle!(self.dirs, fh, reply);
+        match self.target.fsyncdir(req.info(), &path, &handle, datasync) {
             Ok(()) => reply.ok(),
-            Err(e) => reply.error(e),
+            Err(e) => reply.error(e.0),
         }
     }
 
@@ -621,11 +2616,19 @@ impl<T: FilesystemMT + Sync + Send + 'static> Filesystem for FuseMT<T> {
                                        statfs.bsize,
                                        statfs.namelen,
                                        statfs.frsize),
-            Err(e) => reply.error(e),
+            Err(e) => reply.error(e.0),
         }
     }
 
-    // setxattr
+    fn setxattr(&mut self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32, reply: ReplyEmpty) {
+        let path = get_path!(self, ino, reply);
+        debug!("setxattr: {:?} {:?} ({} bytes, flags={:#x}, position={})", path, name, value.len(),
+               flags, position);
+        match self.target.setxattr(req.info(), &path, name, value, flags, position) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.0),
+        }
+    }
 
     fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
         let path = if ino == 1 {
@@ -635,20 +2638,8 @@ impl<T: FilesystemMT + Sync + Send + 'static> Filesystem for FuseMT<T> {
         };
 
         debug!("getxattr: {:?} {:?}", path, name);
-        match self.target.getxattr(req.info(), &path, name, size) {
-            Ok(Xattr::Size(size)) => {
-                debug!("getxattr: sending size {}", size);
-                reply.size(size)
-            },
-            Ok(Xattr::Data(vec)) => {
-                debug!("getxattr: sending {} bytes", vec.len());
-                reply.data(&vec)
-            },
-            Err(e) => {
-                debug!("getxattr: error {}", e);
-                reply.error(e)
-            },
-        }
+        let result = self.target.getxattr(req.info(), &path, name, size);
+        reply_xattr("getxattr", size, result, reply);
     }
 
     fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
@@ -659,39 +2650,235 @@ impl<T: FilesystemMT + Sync + Send + 'static> Filesystem for FuseMT<T> {
         };
 
         debug!("listxattr: {:?}", path);
-        match self.target.listxattr(req.info(), &path, size) {
-            Ok(Xattr::Size(size)) => {
-                debug!("listxattr: sending size {}", size);
-                reply.size(size)
-            },
-            Ok(Xattr::Data(vec)) => {
-                debug!("listxattr: sending {} bytes", vec.len());
-                reply.data(&vec)
-            }
-            Err(e) => reply.error(e),
-        }
+        let result = self.target.listxattr(req.info(), &path, size);
+        reply_xattr("listxattr", size, result, reply);
     }
 
-    // removexattr
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let path = get_path!(self, ino, reply);
+        debug!("removexattr: {:?} {:?}", path, name);
+        match self.target.removexattr(req.info(), &path, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.0),
+        }
+    }
 
-    // access
+    fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        let path = get_path!(self, ino, reply);
+        debug!("access: {:?}, mask={:#o}", path, mask);
+        match self.target.access(req.info(), &path, mask) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.0),
+        }
+    }
 
     fn create(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32, reply: ReplyCreate) {
         let parent_path = get_path!(self, parent, reply);
+        check_name!(name, reply);
         debug!("create: {:?}/{:?} (mode={:#o}, flags={:#x})", parent_path, name, mode, flags);
-        match self.target.create(req.info(), &parent_path, name, mode, flags) {
+        let result = self.target.create(req.info(), &parent_path, name, mode, FileFlags::new(flags));
+        forget_entry_getattrs(&self.getattr_waiting, &parent_path, name);
+        match result {
             Ok(mut create) => {
-                let ino = self.inodes.add(Arc::new(parent_path.join(name)));
+                let (ino, generation) = {
+                    let mut inodes = self.inodes.write();
+                    let ino = inodes.add_with_inode(&parent_path.join(name), create.attr.ino);
+                    if ino == create.attr.ino && create.generation != 0 {
+                        inodes.set_generation(ino, create.generation);
+                    }
+                    (ino, inodes.get_generation(ino))
+                };
+                let fh = self.files.insert(create.fh);
+                self.add_open_file(ino, fh);
                 create.attr.ino = ino;
-                reply.created(&create.ttl, &create.attr, create.generation, create.fh, create.flags);
+                reply.created(&ttl_to_timespec(create.ttl), &create.attr.to_fuse(), generation, fh,
+                              create.flags.bits());
+            },
+            Err(e) => reply.error(e.0),
+        }
+    }
+
+    fn getlk(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, reply: ReplyLock) {
+        let path = get_path!(self, ino, reply);
+        debug!("getlk: {:?} {:#x}-{:#x} (type={}, pid={})", path, start, end, typ, pid);
+        let handle = get_handle!(self.files, fh, reply);
+        let lock = FileLock {
+            start: start,
+            end: end,
+            typ: typ,
+            pid: pid,
+        };
+        match self.target.getlk(req.info(), &path, &handle, lock_owner, lock) {
+            Ok(lock) => reply.locked(lock.start, lock.end, lock.typ, lock.pid),
+            Err(e) => reply.error(e.0),
+        }
+    }
+
+    fn setlk(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+        let path = get_path!(self, ino, reply);
+        debug!("setlk: {:?} {:#x}-{:#x} (type={}, pid={}, sleep={:?})", path, start, end, typ, pid,
+               sleep);
+        let handle = get_handle!(self.files, fh, reply);
+        let lock = FileLock {
+            start: start,
+            end: end,
+            typ: typ,
+            pid: pid,
+        };
+        let target = self.target.clone();
+        let req_info = req.info();
+
+        // This can block waiting for the lock, so it has to be done on another thread.
+        self.dispatch_future("setlk", Dispatch::Blocking, move|| {
+            let future = guard("setlk", move|| {
+                target.setlk_async(req_info, path, handle, lock_owner, lock, sleep)
+            });
+            executor::then(future, move|result| {
+                match result {
+                    Ok(()) => reply.ok(),
+                    Err(e) => reply.error(e.0),
+                }
+            })
+        });
+    }
+
+    fn bmap(&mut self, req: &Request, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        let path = get_path!(self, ino, reply);
+        debug!("bmap: {:?} {:#x} (blocksize={:#x})", path, idx, blocksize);
+        match self.target.bmap(req.info(), &path, blocksize, idx) {
+            Ok(block) => reply.bmap(block),
+            Err(e) => reply.error(e.0),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname(&mut self, req: &Request, name: &OsStr, reply: ReplyEmpty) {
+        debug!("setvolname: {:?}", name);
+        match self.target.setvolname(req.info(), name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.0),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn exchange(&mut self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, options: u64, reply: ReplyEmpty) {
+        let parent_path = get_path!(self, parent, reply);
+        let newparent_path = get_path!(self, newparent, reply);
+        check_name!(name, reply);
+        check_name!(newname, reply);
+        debug!("exchange: {:?}/{:?} <-> {:?}/{:?} (options={:#x})", parent_path, name,
+               newparent_path, newname, options);
+        let result = self.target.exchange(req.info(), &parent_path, name, &newparent_path, newname,
+                                          options);
+        forget_entry_getattrs(&self.getattr_waiting, &parent_path, name);
+        forget_entry_getattrs(&self.getattr_waiting, &newparent_path, newname);
+        match result {
+            Ok(()) => {
+                self.inodes.write()
+                    .exchange(&parent_path.join(name), &newparent_path.join(newname));
+                reply.ok()
             },
-            Err(e) => reply.error(e),
+            Err(e) => reply.error(e.0),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&mut self, req: &Request, ino: u64, reply: ReplyXTimes) {
+        let path = get_path!(self, ino, reply);
+        debug!("getxtimes: {:?}", path);
+        match self.target.getxtimes(req.info(), &path) {
+            Ok(xtimes) => reply.xtimes(to_timespec(xtimes.bkuptime), to_timespec(xtimes.crtime)),
+            Err(e) => reply.error(e.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+    use std::ptr;
+
+    fn attr(perm: u16, uid: u32, gid: u32) -> FileAttr {
+        FileAttr {
+            perm: perm,
+            uid: uid,
+            gid: gid,
+            .. negative_entry_attr()
+        }
+    }
+
+    // A request from a process that doesn't exist, so that it has no supplementary groups.
+    fn request(uid: u32, gid: u32) -> RequestInfo {
+        RequestInfo {
+            unique: 0,
+            uid: uid,
+            gid: gid,
+            pid: u32::MAX,
         }
     }
 
-    // getlk
+    const R: u32 = libc::R_OK as u32;
+    const W: u32 = libc::W_OK as u32;
+    const X: u32 = libc::X_OK as u32;
+
+    #[test]
+    fn access_uses_owner_group_and_other_bits() {
+        let file = attr(0o640, 1000, 100);
+        assert_eq!(check_access(&file, request(1000, 1000), R | W), Ok(()));
+        assert_eq!(check_access(&file, request(1000, 1000), X), Err(Errno(libc::EACCES)));
+        assert_eq!(check_access(&file, request(2000, 100), R), Ok(()));
+        assert_eq!(check_access(&file, request(2000, 100), W), Err(Errno(libc::EACCES)));
+        assert_eq!(check_access(&file, request(2000, 2000), R), Err(Errno(libc::EACCES)));
+        // F_OK only asks whether the file exists.
+        assert_eq!(check_access(&file, request(2000, 2000), libc::F_OK as u32), Ok(()));
+    }
+
+    #[test]
+    fn access_for_root() {
+        let file = attr(0o000, 1000, 100);
+        assert_eq!(check_access(&file, request(0, 0), R | W), Ok(()));
+        // Root may only execute a file that someone may execute, but may search any directory.
+        assert_eq!(check_access(&file, request(0, 0), X), Err(Errno(libc::EACCES)));
+        assert_eq!(check_access(&attr(0o001, 1000, 100), request(0, 0), X), Ok(()));
+        let dir = FileAttr { kind: FileType::Directory, .. file };
+        assert_eq!(check_access(&dir, request(0, 0), X), Ok(()));
+    }
+
+    #[test]
+    fn device_numbers_round_trip() {
+        let devices = [
+            DeviceNumber { major: 0, minor: 0 },
+            DeviceNumber { major: 1, minor: 3 },
+            DeviceNumber { major: 8, minor: 17 },
+            DeviceNumber { major: 0xff, minor: 0xff },
+            DeviceNumber { major: 0x7f, minor: 0xfffff },
+        ];
+        for &device in &devices {
+            assert_eq!(DeviceNumber::from_rdev(device.rdev()), device);
+        }
+    }
 
-    // setlk
+    // The same encoding as the kernel's new_encode_dev().
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn device_numbers_match_the_kernel() {
+        assert_eq!(DeviceNumber { major: 1, minor: 3 }.rdev(), 0x103);
+        assert_eq!(DeviceNumber { major: 259, minor: 0x12345 }.rdev(), 0x12310345);
+        assert_eq!(DeviceNumber::from_rdev(0x12310345), DeviceNumber { major: 259, minor: 0x12345 });
+    }
 
-    // bmap
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn process_groups_match_getgroups() {
+        let count = unsafe { libc::getgroups(0, ptr::null_mut()) };
+        let mut groups = vec![0; count as usize];
+        unsafe { libc::getgroups(count, groups.as_mut_ptr()) };
+
+        let mut found = process_groups(process::id());
+        groups.sort();
+        found.sort();
+        assert_eq!(found, groups);
+        assert_eq!(process_groups(u32::MAX), vec![]);
+    }
 }
@@ -0,0 +1,45 @@
+// HandleTable :: Keeps the state of a filesystem's open files, and the numbers the kernel refers to
+//                them by.
+//
+// Copyright (c) 2016 by William R. Fraser
+//
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The handles of a filesystem's open files, by the number given to the kernel for each.
+///
+/// Handles are shared with the operations using them, so one that's removed from the table (when
+/// the file is released) is dropped once the last of those has finished with it.
+pub struct HandleTable<H> {
+    handles: HashMap<u64, Arc<H>>,
+    next: u64,
+}
+
+impl<H> HandleTable<H> {
+    pub fn new() -> HandleTable<H> {
+        HandleTable {
+            handles: HashMap::new(),
+            next: 1,
+        }
+    }
+
+    /// Add a handle, and get the number for the kernel to refer to it by.
+    pub fn insert(&mut self, handle: H) -> u64 {
+        while self.handles.contains_key(&self.next) {
+            self.next = self.next.wrapping_add(1);
+        }
+        let fh = self.next;
+        self.next = self.next.wrapping_add(1);
+        self.handles.insert(fh, Arc::new(handle));
+        fh
+    }
+
+    pub fn get(&self, fh: u64) -> Option<Arc<H>> {
+        self.handles.get(&fh).cloned()
+    }
+
+    pub fn remove(&mut self, fh: u64) -> Option<Arc<H>> {
+        self.handles.remove(&fh)
+    }
+}
@@ -0,0 +1,159 @@
+// InodeCache :: The inode table, along with copies of its mappings split into shards that each
+//               have their own lock, so that looking up paths and inodes from many threads at
+//               once doesn't contend for the table's lock.
+//
+// Copyright (c) 2016 by William R. Fraser
+//
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use inode_table::{Inode, InodeTable};
+
+const SHARDS: usize = 64;
+
+// Copies of some of the table's mappings, which are only good as long as the table's change count
+// is the same as when they were made.
+struct Shard<K, V> {
+    changes: u64,
+    map: HashMap<K, V>,
+}
+
+impl<K: Hash + Eq, V: Clone> Shard<K, V> {
+    fn new() -> Shard<K, V> {
+        Shard {
+            changes: 0,
+            map: HashMap::new(),
+        }
+    }
+
+    fn get<Q>(&self, key: &Q, changes: u64) -> Option<V>
+        where K: ::std::borrow::Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        if self.changes == changes {
+            self.map.get(key).cloned()
+        } else {
+            None
+        }
+    }
+
+    fn insert(&mut self, key: K, value: V, changes: u64) {
+        if changes > self.changes {
+            self.map.clear();
+            self.changes = changes;
+        }
+        // Otherwise, the table has changed since the value was looked up, and it may be stale.
+        if changes == self.changes {
+            self.map.insert(key, value);
+        }
+    }
+}
+
+/// The inode table, shared between threads.
+///
+/// Changes to the table (adding paths, lookup counts, renames, etc.) are made with the table
+/// locked. Looking up the path of an inode, or the inode of a path, is done without taking the
+/// table's lock when the answer has been looked up before and nothing has been renamed or removed
+/// since.
+pub struct InodeCache {
+    table: RwLock<InodeTable>,
+    // The table's change count when its lock was last released.
+    changes: AtomicU64,
+    paths: Vec<Mutex<Shard<Inode, Arc<PathBuf>>>>,
+    inodes: Vec<Mutex<Shard<PathBuf, Inode>>>,
+}
+
+impl InodeCache {
+    pub fn new(table: InodeTable) -> InodeCache {
+        InodeCache {
+            changes: AtomicU64::new(table.changes()),
+            table: RwLock::new(table),
+            paths: (0 .. SHARDS).map(|_| Mutex::new(Shard::new())).collect(),
+            inodes: (0 .. SHARDS).map(|_| Mutex::new(Shard::new())).collect(),
+        }
+    }
+
+    /// Lock the table for reading.
+    pub fn read<'a>(&'a self) -> RwLockReadGuard<'a, InodeTable> {
+        self.table.read().unwrap()
+    }
+
+    /// Lock the table for making changes to it.
+    pub fn write<'a>(&'a self) -> WriteGuard<'a> {
+        WriteGuard {
+            table: self.table.write().unwrap(),
+            changes: &self.changes,
+        }
+    }
+
+    /// Get the path that corresponds to an inode; see `InodeTable::get_path`.
+    pub fn get_path(&self, inode: Inode) -> Option<Arc<PathBuf>> {
+        let shard = &self.paths[inode as usize % SHARDS];
+        let changes = self.changes.load(Ordering::Acquire);
+        if let Some(path) = shard.lock().unwrap().get(&inode, changes) {
+            return Some(path);
+        }
+
+        let (path, changes) = {
+            let table = self.read();
+            (table.get_path(inode), table.changes())
+        };
+        if let Some(ref path) = path {
+            shard.lock().unwrap().insert(inode, path.clone(), changes);
+        }
+        path
+    }
+
+    /// Get the inode that corresponds to a path; see `InodeTable::get_inode`.
+    pub fn get_inode(&self, path: &Path) -> Option<Inode> {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        let shard = &self.inodes[hasher.finish() as usize % SHARDS];
+        let changes = self.changes.load(Ordering::Acquire);
+        if let Some(inode) = shard.lock().unwrap().get(path, changes) {
+            return Some(inode);
+        }
+
+        let (inode, changes) = {
+            let table = self.read();
+            (table.get_inode(path), table.changes())
+        };
+        if let Some(inode) = inode {
+            shard.lock().unwrap().insert(path.to_owned(), inode, changes);
+        }
+        inode
+    }
+}
+
+/// The inode table, locked for making changes to it. When it's unlocked, any copies of mappings
+/// that the changes made stale stop being used.
+pub struct WriteGuard<'a> {
+    table: RwLockWriteGuard<'a, InodeTable>,
+    changes: &'a AtomicU64,
+}
+
+impl<'a> Deref for WriteGuard<'a> {
+    type Target = InodeTable;
+
+    fn deref(&self) -> &InodeTable {
+        &self.table
+    }
+}
+
+impl<'a> DerefMut for WriteGuard<'a> {
+    fn deref_mut(&mut self) -> &mut InodeTable {
+        &mut self.table
+    }
+}
+
+impl<'a> Drop for WriteGuard<'a> {
+    fn drop(&mut self) {
+        // This happens before the lock is released.
+        self.changes.store(self.table.changes(), Ordering::Release);
+    }
+}
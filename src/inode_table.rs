@@ -3,26 +3,123 @@
 // Copyright (c) 2016 by William R. Fraser
 //
 
-use std::collections::{BTreeMap, VecDeque};
-use std::collections::btree_map::Entry::*;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::sync::Arc;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 pub type Inode = u64;
+pub type Generation = u64;
 pub type LookupCount = u64;
 
+// The table index of the root directory.
+const ROOT: usize = 0;
+
+// The start of a saved inode table, followed by a format version number.
+const STATE_MAGIC: &[u8] = b"fuse_mt inode table\0";
+const STATE_VERSION: u64 = 2;
+
+/// How inode numbers are assigned to paths.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InodeAssignment {
+    /// Inode numbers are handed out in order, and the numbers of forgotten inodes are re-used.
+    /// This is the default.
+    Sequential,
+
+    /// Inode numbers are derived from a hash of the path an inode is first added with, so a path
+    /// gets the same number each time the filesystem is mounted, unless another path in use hashes
+    /// to the same number. Renaming a file doesn't change its inode number.
+    HashedPath,
+
+    /// Inode numbers are supplied by the filesystem, in the `ino` field of the attributes it
+    /// returns, e.g. so that a passthrough filesystem can expose the backing filesystem's numbers.
+    /// Paths that are given the same number are treated as hard links to the same inode.
+    ///
+    /// Numbers 0 and 1 (the root) can't be used; paths given those, or added without a number
+    /// (e.g. by `readdir`), get a number derived from their path, as with `HashedPath`, until the
+    /// filesystem supplies one.
+    Filesystem,
+}
+
+/// Statistics about an inode table, for debugging.
+#[derive(Clone, Copy, Debug)]
+pub struct InodeTableStats {
+    /// The number of inodes in the table, including the root.
+    pub inodes: usize,
+    /// The number of inodes the kernel holds references to (i.e. with a non-zero lookup count).
+    pub referenced: usize,
+    /// The number of inodes whose paths have all been unlinked, but which haven't been forgotten
+    /// yet.
+    pub deleted: usize,
+    /// The number of inodes that have been evicted because of the table's limit.
+    pub evictions: u64,
+    /// A rough estimate of the memory used by the table, in bytes.
+    pub memory: usize,
+}
+
+/// The state of one inode in an inode table, for debugging.
+#[derive(Clone, Debug)]
+pub struct InodeInfo {
+    pub inode: Inode,
+    pub generation: Generation,
+    /// Every path (hard link) that refers to the inode, or if it has been deleted, the last path
+    /// it had.
+    pub paths: Vec<PathBuf>,
+    pub lookups: LookupCount,
+    pub deleted: bool,
+}
+
 #[derive(Debug)]
 struct InodeTableEntry {
-    path: Arc<PathBuf>,
+    inode: Inode,
+    /// The parent directory and name of each path (hard link) that refers to this inode. The
+    /// first one is used when a single path is needed. Only the root and deleted entries have
+    /// none.
+    links: Vec<(usize, Arc<OsStr>)>,
+    /// The entries in this directory, by name (case-folded, if the table is case-insensitive).
+    children: BTreeMap<Arc<OsStr>, usize>,
     lookups: LookupCount,
+    /// If all the links have been removed, the last path the inode had.
+    deleted_path: Option<Arc<PathBuf>>,
+    /// When the entry was last used, if it's unreferenced and the table is limited.
+    last_used: u64,
 }
 
 /// A data structure for mapping paths to inodes and vice versa.
+///
+/// Paths are stored as a tree of name components: each entry only knows its own name and parent
+/// directory, so memory use is proportional to the number of unique components, and renaming a
+/// directory only has to update the directory's own entry. The names themselves are interned, so
+/// names that occur in many directories (e.g. "src") are only stored once.
 #[derive(Debug)]
 pub struct InodeTable {
     table: Vec<Option<InodeTableEntry>>,
+    generations: Vec<Generation>,
     free_list: VecDeque<usize>,
-    by_path: BTreeMap<Arc<PathBuf>, usize>,
+    assignment: InodeAssignment,
+    case_insensitive: bool,
+    /// Every name used in the table. Names that are no longer used are removed now and then.
+    names: HashSet<Arc<OsStr>>,
+    /// With hashed or filesystem-supplied inode numbers, the table index and generation number of
+    /// each inode in the table. Each inode added is given the next generation number in turn, so
+    /// an inode number that is re-used never has the same generation as before, without having
+    /// to remember the numbers of inodes that were removed.
+    by_inode: HashMap<Inode, usize>,
+    hashed_generations: HashMap<Inode, Generation>,
+    next_generation: Generation,
+    limit: Option<usize>,
+    /// Unreferenced entries, least recently used first, along with when they were used. Entries
+    /// that have been used again since are left in the queue, and skipped over.
+    unreferenced: VecDeque<(usize, u64)>,
+    clock: u64,
+    evictions: u64,
+    /// Incremented whenever a path or inode that was already in the table may have been mapped to
+    /// something else, or removed. Adding new paths doesn't count.
+    changes: u64,
 }
 
 impl InodeTable {
@@ -36,15 +133,29 @@ impl InodeTable {
     pub fn new() -> InodeTable {
         let mut inode_table = InodeTable {
             table: Vec::new(),
+            generations: Vec::new(),
             free_list: VecDeque::new(),
-            by_path: BTreeMap::new()
+            assignment: InodeAssignment::Sequential,
+            case_insensitive: false,
+            names: HashSet::new(),
+            by_inode: HashMap::new(),
+            hashed_generations: HashMap::new(),
+            next_generation: 0,
+            limit: None,
+            unreferenced: VecDeque::new(),
+            clock: 0,
+            evictions: 0,
+            changes: 0,
         };
-        let root = Arc::new(PathBuf::from("/"));
         inode_table.table.push(Some(InodeTableEntry {
-            path: root.clone(),
+            inode: 1,
+            links: vec![],
+            children: BTreeMap::new(),
             lookups: 0, // not used for this entry; root is always present.
+            deleted_path: None,
+            last_used: 0,
         }));
-        inode_table.by_path.insert(root, 0);
+        inode_table.generations.push(0);
         inode_table
     }
 
@@ -54,168 +165,1183 @@ impl InodeTable {
     /// The returned inode number may be a re-used number formerly assigned to a now-forgotten
     /// path.
     ///
-    /// The path is added with an initial lookup count of 1.
+    /// The path is added with an initial lookup count of 1. Any of its parent directories that
+    /// are not in the table are added with a lookup count of 0.
+    ///
+    /// If the path was already mapped to an inode, that inode is considered stale, and is marked
+    /// as deleted. A path with no parent directory (i.e. the root) isn't added again; its existing
+    /// inode is returned.
+    ///
+    /// This operation runs in O(d log n) time, where d is the number of components in the path.
+    pub fn add(&mut self, path: &Path) -> Inode {
+        let (parent, name) = match split(path) {
+            Some(split) => split,
+            None => return self.add_or_get(path),
+        };
+        self.evict();
+        let parent_idx = self.find_or_create(parent);
+        let idx = self.create(parent_idx, name, 1);
+        self.entry(idx).inode
+    }
+
+    /// Add a path to the inode table if it does not yet exist.
+    ///
+    /// Returns the inode number the path is now mapped to.
+    ///
+    /// If the path was not in the table, it is added with an initial lookup count of 0. Until its
+    /// lookup count is incremented, it may be evicted if the table grows past its limit.
+    ///
+    /// This operation runs in O(d log n) time, where d is the number of components in the path.
+    pub fn add_or_get(&mut self, path: &Path) -> Inode {
+        if let Some(idx) = self.find(path) {
+            self.touch(idx);
+            return self.entry(idx).inode;
+        }
+
+        self.evict();
+        let idx = self.find_or_create(path);
+        self.entry(idx).inode
+    }
+
+    /// Add a path to the inode table, using the inode number the filesystem supplied for it.
+    ///
+    /// This is like `add`, except that if inode numbers are assigned by the filesystem
+    /// (`InodeAssignment::Filesystem`), the given number is used, as with `add_or_get_with_inode`.
+    ///
+    /// This operation runs in O(d log n) time, where d is the number of components in the path.
+    pub fn add_with_inode(&mut self, path: &Path, inode: Inode) -> Inode {
+        if self.assignment != InodeAssignment::Filesystem || inode <= 1 {
+            return self.add(path);
+        }
+
+        // Whatever the path was mapped to before is stale.
+        self.unlink(path);
+        let inode = self.add_or_get_with_inode(path, inode);
+        self.lookup(inode);
+        inode
+    }
+
+    /// Add a path to the inode table if it does not yet exist, using the inode number the
+    /// filesystem supplied for it.
+    ///
+    /// Returns the inode number the path is now mapped to. Unless inode numbers are assigned by
+    /// the filesystem (`InodeAssignment::Filesystem`), the number given is ignored, and this is
+    /// the same as `add_or_get`.
+    ///
+    /// If the number is already mapped to a different path, the path is added as a hard link to
+    /// it. If the path was already mapped to a different number, the old inode is considered stale
+    /// and is marked as deleted, unless the path was in the table with a lookup count of 0, in
+    /// which case it is just renumbered.
+    ///
+    /// This operation runs in O(d log n) time, where d is the number of components in the path.
+    pub fn add_or_get_with_inode(&mut self, path: &Path, inode: Inode) -> Inode {
+        if self.assignment != InodeAssignment::Filesystem || inode <= 1 {
+            return self.add_or_get(path);
+        }
+
+        // Evict first, so that nothing below is evicted from under us.
+        self.evict();
+        let existing = self.find(path);
+        if let Some(idx) = existing {
+            if self.entry(idx).inode == inode {
+                self.touch(idx);
+                return inode;
+            }
+        }
+
+        if self.by_inode.contains_key(&inode) {
+            if let Some(idx) = existing {
+                self.unlink(path);
+                if self.entry(idx).lookups == 0 {
+                    self.remove(idx);
+                }
+            }
+            debug!("inode table: adding {:?} as a link to inode {}", path, inode);
+            self.add_link(inode, path);
+            if let Some(idx) = self.index(inode) {
+                self.touch(idx);
+            }
+            return inode;
+        }
+
+        let idx = match existing {
+            Some(idx) => {
+                if self.entry(idx).lookups != 0 {
+                    // The kernel already knows the entry by its old number.
+                    debug!("inode table: {:?} is inode {}, not {}", path, self.entry(idx).inode,
+                           inode);
+                    self.touch(idx);
+                    return self.entry(idx).inode;
+                }
+                idx
+            },
+            None => self.find_or_create(path),
+        };
+
+        let old = self.entry(idx).inode;
+        self.changes += 1;
+        self.by_inode.remove(&old);
+        self.hashed_generations.remove(&old);
+        self.by_inode.insert(inode, idx);
+        self.new_generation(inode);
+        self.entry_mut(idx).inode = inode;
+        inode
+    }
+
+    /// Add an entry in a directory to the inode table if it does not yet exist.
+    ///
+    /// This is the same as `add_or_get` with the directory's path joined with the name, but
+    /// doesn't need to build or look up the full path. Returns None if the directory is not in the
+    /// table.
     ///
     /// This operation runs in O(log n) time.
-    pub fn add(&mut self, path: Arc<PathBuf>) -> Inode {
-        let idx = self.free_list.pop_front().unwrap_or_else(|| {
-            self.table.push(None);
-            self.table.len() - 1
+    pub fn add_or_get_child(&mut self, parent: Inode, name: &OsStr) -> Option<Inode> {
+        self.evict();
+        let parent_idx = match self.index(parent) {
+            Some(idx) => idx,
+            None => return None,
+        };
+        let child = self.entry(parent_idx).children.get(&*self.key(name)).cloned();
+        let idx = match child {
+            Some(idx) => {
+                self.touch(idx);
+                idx
+            },
+            None => self.create(parent_idx, name, 0),
+        };
+        Some(self.entry(idx).inode)
+    }
+
+    /// Choose how inode numbers are assigned. This must be done before any paths are added.
+    pub fn set_assignment(&mut self, assignment: InodeAssignment) {
+        assert!(self.table.len() == 1, "inode assignment can't be changed after paths are added");
+        self.assignment = assignment;
+    }
+
+    /// Make paths match regardless of case. The case of the first path an inode is added with is
+    /// preserved, and used whenever its path is returned, until it is renamed. This must be done
+    /// before any paths are added.
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        assert!(self.table.len() == 1, "case sensitivity can't be changed after paths are added");
+        self.case_insensitive = case_insensitive;
+    }
+
+    // Get the key a name is stored under in its directory's children.
+    fn key<'a>(&self, name: &'a OsStr) -> Cow<'a, OsStr> {
+        fold(name, self.case_insensitive)
+    }
+
+    // Get the shared copy of a name, adding it if it's new.
+    fn intern(&mut self, name: &OsStr) -> Arc<OsStr> {
+        if let Some(interned) = self.names.get(name) {
+            return interned.clone();
+        }
+
+        let interned: Arc<OsStr> = Arc::from(name);
+        self.names.insert(interned.clone());
+
+        // Names whose entries have been removed are left in the set; don't let them pile up.
+        if self.names.len() > 4 * self.table.len() {
+            self.names.retain(|name| Arc::strong_count(name) > 1);
+        }
+        interned
+    }
+
+    /// Limit the number of inodes in the table.
+    ///
+    /// Inodes that the kernel holds references to can't be removed, but when the table grows past
+    /// the limit, inodes that have a lookup count of 0 (i.e. that were only added by
+    /// `add_or_get`) are evicted, least recently used first.
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+        if limit.is_none() {
+            self.unreferenced.clear();
+        } else {
+            self.evict();
+        }
+    }
+
+    /// Get the number of inodes that have been evicted because of the table's limit.
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    /// Get a number that changes whenever a path or inode number in the table is remapped or
+    /// removed, so that copies of the mappings made earlier can tell whether they still hold.
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    /// Get the lookup count of an inode, or None, if it is not in the table.
+    ///
+    /// This operation runs in O(1) time.
+    pub fn lookup_count(&self, inode: Inode) -> Option<LookupCount> {
+        self.index(inode).map(|idx| self.entry(idx).lookups)
+    }
+
+    /// Get statistics about the table.
+    ///
+    /// This operation runs in O(n) time.
+    pub fn stats(&self) -> InodeTableStats {
+        let mut stats = InodeTableStats {
+            inodes: self.table.len() - self.free_list.len(),
+            referenced: 0,
+            deleted: 0,
+            evictions: self.evictions,
+            memory: mem::size_of::<InodeTable>(),
+        };
+
+        stats.memory += self.table.capacity() * mem::size_of::<Option<InodeTableEntry>>()
+            + self.generations.capacity() * mem::size_of::<Generation>()
+            + self.free_list.capacity() * mem::size_of::<usize>()
+            + self.unreferenced.capacity() * mem::size_of::<(usize, u64)>()
+            + (self.by_inode.capacity() + self.hashed_generations.capacity())
+                * mem::size_of::<(Inode, usize)>();
+
+        for entry in self.table.iter().filter_map(|entry| entry.as_ref()) {
+            if entry.lookups != 0 {
+                stats.referenced += 1;
+            }
+            if let Some(ref path) = entry.deleted_path {
+                stats.deleted += 1;
+                stats.memory += path.as_os_str().len();
+            }
+            // Each link is referred to twice: by the entry, and by its parent's children.
+            stats.memory += entry.links.len()
+                * (2 * mem::size_of::<Arc<OsStr>>() + 2 * mem::size_of::<usize>());
+        }
+
+        for name in &self.names {
+            stats.memory += name.len() + 2 * mem::size_of::<usize>() + mem::size_of::<Arc<OsStr>>();
+        }
+
+        stats
+    }
+
+    /// Get the state of every inode in the table, ordered by inode number.
+    ///
+    /// This operation runs in O(n d) time, where d is the number of components in the paths.
+    pub fn dump(&self) -> Vec<InodeInfo> {
+        let mut infos: Vec<InodeInfo> = self.table.iter().enumerate()
+            .filter_map(|(idx, entry)| entry.as_ref().map(|entry| {
+                let paths = if entry.links.is_empty() {
+                    vec![(*self.path_of(idx)).clone()]
+                } else {
+                    entry.links.iter()
+                        .map(|&(parent, ref name)| self.path_of(parent).join(&**name))
+                        .collect()
+                };
+                InodeInfo {
+                    inode: entry.inode,
+                    generation: self.get_generation(entry.inode),
+                    paths: paths,
+                    lookups: entry.lookups,
+                    deleted: entry.deleted_path.is_some(),
+                }
+            }))
+            .collect();
+        infos.sort_by_key(|info| info.inode);
+        infos
+    }
+
+    /// Save the paths, inode numbers, and generation numbers in the table, so that they can be
+    /// restored with `load` when the filesystem is mounted again.
+    ///
+    /// Lookup counts are not saved, because the kernel doesn't keep its references across mounts.
+    /// Deleted inodes aren't saved either, but their generation numbers are advanced, as if they
+    /// had been forgotten.
+    pub fn save<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut generations: BTreeMap<Inode, Generation> = match self.assignment {
+            InodeAssignment::Sequential => {
+                self.generations.iter().enumerate()
+                    .filter(|&(_, &generation)| generation != 0)
+                    .map(|(idx, &generation)| ((idx + 1) as Inode, generation))
+                    .collect()
+            },
+            InodeAssignment::HashedPath | InodeAssignment::Filesystem => {
+                self.hashed_generations.iter().map(|(&inode, &generation)| (inode, generation))
+                    .collect()
+            },
+        };
+
+        let mut entries: Vec<(usize, Vec<PathBuf>)> = vec![];
+        for (idx, entry) in self.table.iter().enumerate() {
+            let entry = match *entry {
+                Some(ref entry) if idx != ROOT => entry,
+                _ => continue,
+            };
+            if entry.deleted_path.is_some() {
+                if self.assignment == InodeAssignment::Sequential {
+                    generations.insert(entry.inode, self.get_generation(entry.inode) + 1);
+                } else {
+                    generations.remove(&entry.inode);
+                }
+                continue;
+            }
+            let paths = entry.links.iter()
+                .map(|&(parent, ref name)| self.path_of(parent).join(&**name))
+                .collect();
+            entries.push((idx, paths));
+        }
+
+        // Directories come before the entries in them, so their parents always exist when loading.
+        entries.sort_by_key(|&(_, ref paths)| {
+            paths.iter().map(|path| path.components().count()).max()
         });
+
+        w.write_all(STATE_MAGIC)?;
+        write_u64(w, STATE_VERSION)?;
+        write_u64(w, match self.assignment {
+            InodeAssignment::Sequential => 0,
+            InodeAssignment::HashedPath => 1,
+            InodeAssignment::Filesystem => 2,
+        })?;
+        write_u64(w, self.next_generation)?;
+
+        write_u64(w, generations.len() as u64)?;
+        for (&inode, &generation) in &generations {
+            write_u64(w, inode)?;
+            write_u64(w, generation)?;
+        }
+
+        write_u64(w, entries.len() as u64)?;
+        for &(idx, ref paths) in &entries {
+            write_u64(w, self.entry(idx).inode)?;
+            write_u64(w, paths.len() as u64)?;
+            for path in paths {
+                write_bytes(w, path.as_os_str().as_bytes())?;
+            }
+        }
+
+        w.flush()
+    }
+
+    /// Restore the paths, inode numbers, and generation numbers saved by `save`. This must be done
+    /// before any paths are added, and after choosing the same inode assignment as when the table
+    /// was saved.
+    ///
+    /// The restored inodes have a lookup count of 0, so they may be evicted if the table is
+    /// limited. If the saved state can't be read, the table is left unchanged.
+    pub fn load<R: Read>(&mut self, r: &mut R) -> io::Result<()> {
+        assert!(self.table.len() == 1, "inode table can't be loaded after paths are added");
+
+        let mut magic = vec![0; STATE_MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if magic != STATE_MAGIC {
+            return Err(invalid_data("not a saved inode table"));
+        }
+        if read_u64(r)? != STATE_VERSION {
+            return Err(invalid_data("unsupported inode table version"));
+        }
+        let assignment = match read_u64(r)? {
+            0 => InodeAssignment::Sequential,
+            1 => InodeAssignment::HashedPath,
+            2 => InodeAssignment::Filesystem,
+            _ => return Err(invalid_data("unknown inode assignment")),
+        };
+        if assignment != self.assignment {
+            return Err(invalid_data("inode table was saved with a different inode assignment"));
+        }
+
+        let mut table = InodeTable::new();
+        table.next_generation = read_u64(r)?;
+        table.assignment = self.assignment;
+        table.case_insensitive = self.case_insensitive;
+        table.limit = self.limit;
+
+        let num_generations = read_u64(r)?;
+        for _ in 0..num_generations {
+            let inode = read_u64(r)?;
+            let generation = read_u64(r)?;
+            if inode <= 1 {
+                return Err(invalid_data("invalid inode number"));
+            }
+            if assignment == InodeAssignment::Sequential {
+                table.reserve(inode)?;
+                table.generations[inode as usize - 1] = generation;
+            } else {
+                table.hashed_generations.insert(inode, generation);
+            }
+        }
+
+        let num_entries = read_u64(r)?;
+        for _ in 0..num_entries {
+            let inode = read_u64(r)?;
+            if inode <= 1 || table.index(inode).is_some() {
+                return Err(invalid_data("invalid or duplicate inode number"));
+            }
+
+            let idx = if assignment == InodeAssignment::Sequential {
+                table.reserve(inode)?;
+                inode as usize - 1
+            } else {
+                table.table.push(None);
+                table.generations.push(0);
+                table.by_inode.insert(inode, table.table.len() - 1);
+                table.table.len() - 1
+            };
+
+            table.table[idx] = Some(InodeTableEntry {
+                inode: inode,
+                links: vec![],
+                children: BTreeMap::new(),
+                lookups: 0,
+                deleted_path: None,
+                last_used: 0,
+            });
+
+            let num_links = read_u64(r)?;
+            for _ in 0..num_links {
+                let bytes = read_bytes(r)?;
+                let path = Path::new(OsStr::from_bytes(&bytes));
+                let (parent, name) = match split(path) {
+                    Some(split) => split,
+                    None => return Err(invalid_data("invalid path")),
+                };
+                let parent_idx = match table.find(parent) {
+                    Some(parent_idx) => parent_idx,
+                    None => return Err(invalid_data("path's parent directory is missing")),
+                };
+                table.link(idx, parent_idx, name);
+            }
+
+            if table.entry(idx).links.is_empty() {
+                return Err(invalid_data("inode has no paths"));
+            }
+            table.touch(idx);
+        }
+
+        // Unused slots are free for re-use, lowest first.
+        for (idx, entry) in table.table.iter().enumerate().skip(1) {
+            if entry.is_none() {
+                table.free_list.push_back(idx);
+            }
+        }
+
+        // Only the inodes in the table have their generation numbers kept.
+        if assignment != InodeAssignment::Sequential {
+            let by_inode = &table.by_inode;
+            table.hashed_generations.retain(|inode, _| by_inode.contains_key(inode));
+            let missing: Vec<Inode> = by_inode.keys()
+                .filter(|inode| !table.hashed_generations.contains_key(inode))
+                .cloned()
+                .collect();
+            for inode in missing {
+                table.new_generation(inode);
+            }
+        }
+
+        table.changes = self.changes + 1;
+        *self = table;
+        self.evict();
+        Ok(())
+    }
+
+    // Make sure there's a slot in the table for a sequentially assigned inode number.
+    fn reserve(&mut self, inode: Inode) -> io::Result<()> {
+        // Saved tables can't be bigger than this unless they've been tampered with.
+        if inode > (1 << 32) {
+            return Err(invalid_data("inode number is too big"));
+        }
+        while self.table.len() < inode as usize {
+            self.table.push(None);
+            self.generations.push(0);
+        }
+        Ok(())
+    }
+
+    // Get the table index of an inode, if it's in the table.
+    fn index(&self, inode: Inode) -> Option<usize> {
+        if inode == 1 {
+            return Some(ROOT);
+        }
+        match self.assignment {
+            InodeAssignment::Sequential => {
+                let idx = (inode as usize).wrapping_sub(1);
+                match self.table.get(idx) {
+                    Some(&Some(_)) => Some(idx),
+                    _ => None,
+                }
+            },
+            InodeAssignment::HashedPath | InodeAssignment::Filesystem => {
+                self.by_inode.get(&inode).cloned()
+            },
+        }
+    }
+
+    // Derive an inode number from a path. This uses FNV-1a, so that the number is the same from one
+    // run to the next. If the number is already taken, the next free one is used instead.
+    fn hashed_inode(&self, path: &Path) -> Inode {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in path.as_os_str().as_bytes() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        // 0 isn't a valid inode number, and 1 is the root.
+        while hash <= 1 || self.by_inode.contains_key(&hash) {
+            hash = hash.wrapping_add(1);
+        }
+        hash
+    }
+
+    // Look up the entry for a path.
+    fn find(&self, path: &Path) -> Option<usize> {
+        let mut idx = ROOT;
+        for component in path.components() {
+            match component {
+                Component::RootDir | Component::CurDir => (),
+                Component::Normal(name) => {
+                    match self.entry(idx).children.get(&*self.key(name)) {
+                        Some(&child) => idx = child,
+                        None => return None,
+                    }
+                },
+                _ => return None,
+            }
+        }
+        Some(idx)
+    }
+
+    // Look up the entry for a path, adding unreferenced entries for any part of it that is
+    // missing.
+    fn find_or_create(&mut self, path: &Path) -> usize {
+        let mut idx = ROOT;
+        for component in path.components() {
+            if let Component::Normal(name) = component {
+                let child = self.entry(idx).children.get(&*self.key(name)).cloned();
+                idx = match child {
+                    Some(child) => child,
+                    None => self.create(idx, name, 0),
+                };
+            }
+        }
+        idx
+    }
+
+    // Add a new entry to a directory, in a previously used slot or a new one.
+    //
+    // This doesn't evict anything, because that could remove entries the caller is holding
+    // indexes of; callers must do that first.
+    fn create(&mut self, parent_idx: usize, name: &OsStr, lookups: LookupCount) -> usize {
+        let idx = match self.free_list.pop_front() {
+            Some(idx) => idx,
+            None => {
+                self.table.push(None);
+                self.generations.push(0);
+                self.table.len() - 1
+            }
+        };
         self.table[idx] = Some(InodeTableEntry {
-            path: path.clone(),
-            lookups: 0,
+            inode: (idx + 1) as Inode,
+            links: vec![],
+            children: BTreeMap::new(),
+            lookups: lookups,
+            deleted_path: None,
+            last_used: 0,
         });
-        let previous = self.by_path.insert(path, idx);
-        if previous.is_some() {
-            panic!("attempted to insert duplicate path into inode table: {:?}", previous.unwrap());
+        self.link(idx, parent_idx, name);
+        if self.assignment != InodeAssignment::Sequential {
+            let inode = self.hashed_inode(&self.path_of(idx));
+            self.entry_mut(idx).inode = inode;
+            self.by_inode.insert(inode, idx);
+            self.new_generation(inode);
         }
-        (idx + 1) as Inode
+        self.touch(idx);
+        idx
     }
 
-    /// Add a path to the inode table if it does not yet exist.
-    ///
-    /// Returns the inode number the path is now mapped to.
+    // Mark an unreferenced entry as recently used.
+    fn touch(&mut self, idx: usize) {
+        if self.limit.is_none() {
+            return;
+        }
+
+        {
+            let entry = self.table[idx].as_mut().unwrap();
+            if entry.lookups != 0 || idx == ROOT {
+                return;
+            }
+            self.clock += 1;
+            entry.last_used = self.clock;
+        }
+        self.unreferenced.push_back((idx, self.clock));
+
+        // Re-used entries leave stale records in the queue; don't let them pile up.
+        if self.unreferenced.len() > 2 * self.table.len() {
+            let table = &self.table;
+            self.unreferenced.retain(|&(idx, used)| match table[idx] {
+                Some(ref entry) => entry.lookups == 0 && entry.last_used == used,
+                None => false,
+            });
+        }
+    }
+
+    // Remove least recently used unreferenced entries until the table is within its limit.
+    fn evict(&mut self) {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return,
+        };
+
+        while self.table.len() - self.free_list.len() >= limit {
+            let (idx, used) = match self.unreferenced.pop_front() {
+                Some(record) => record,
+                None => break,
+            };
+
+            let evictable = match self.table[idx] {
+                Some(ref entry) => entry.lookups == 0 && entry.last_used == used,
+                None => false,
+            };
+
+            if evictable {
+                debug!("inode table: evicting inode {} ({:?})", self.entry(idx).inode,
+                       self.path_of(idx));
+                self.evictions += self.remove(idx);
+            }
+        }
+    }
+
+    // Remove an unreferenced entry from the table, making its slot available for re-use.
+    //
+    // Unreferenced entries below it are removed too. If any entries below it are still
+    // referenced, it is kept around as their parent, and removed once they are. Parent
+    // directories that are left empty and unreferenced are removed as well.
+    //
+    // Returns the number of entries removed.
+    fn remove(&mut self, idx: usize) -> u64 {
+        let parents: Vec<usize> = self.entry(idx).links.iter().map(|&(parent, _)| parent).collect();
+        let mut removed = self.remove_subtree(idx);
+        if self.table[idx].is_some() {
+            return removed;
+        }
+
+        for parent in parents {
+            let empty = match self.table[parent] {
+                Some(ref entry) => parent != ROOT && entry.lookups == 0 && entry.children.is_empty(),
+                None => false,
+            };
+            if empty {
+                removed += self.remove(parent);
+            }
+        }
+        removed
+    }
+
+    fn remove_subtree(&mut self, idx: usize) -> u64 {
+        let children: Vec<usize> = self.entry(idx).children.values().cloned().collect();
+        let mut removed = 0;
+        for child in children {
+            if self.entry(child).lookups == 0 {
+                removed += self.remove_subtree(child);
+            }
+        }
+
+        if !self.entry(idx).children.is_empty() {
+            return removed;
+        }
+
+        let entry = self.table[idx].take().unwrap();
+        self.changes += 1;
+        for &(parent, ref name) in &entry.links {
+            let key = self.key(name).into_owned();
+            self.entry_mut(parent).children.remove(&*key);
+        }
+        match self.assignment {
+            InodeAssignment::Sequential => self.generations[idx] += 1,
+            InodeAssignment::HashedPath | InodeAssignment::Filesystem => {
+                self.by_inode.remove(&entry.inode);
+                self.hashed_generations.remove(&entry.inode);
+            }
+        }
+        self.free_list.push_back(idx);
+        removed + 1
+    }
+
+    // Give a hashed or filesystem-supplied inode number that was just added the next generation
+    // number.
+    fn new_generation(&mut self, inode: Inode) {
+        self.hashed_generations.insert(inode, self.next_generation);
+        self.next_generation += 1;
+    }
+
+    /// Get the generation number of an inode.
     ///
-    /// If the path was not in the table, it is added with an initial lookup count of 0.
+    /// Because inode numbers are re-used after they are forgotten, each time that happens the
+    /// generation number changes, so that the combination of inode and generation number is always
+    /// unique. With sequential assignment, an inode number that has never been used has
+    /// generation 0.
     ///
-    /// This operation runs in O(log n) time.
-    pub fn add_or_get(&mut self, path: Arc<PathBuf>) -> Inode {
-        match self.by_path.entry(path.clone()) {
-            Vacant(entry) => {
-                let table_ref = &mut self.table;
-                let idx = self.free_list.pop_front().unwrap_or_else(|| {
-                    table_ref.push(None);
-                    table_ref.len() - 1
-                });
-                table_ref[idx] = Some(InodeTableEntry {
-                    path: path,
-                    lookups: 0,    // lookup must be done later
-                });
-                entry.insert(idx);
-                (idx + 1) as Inode
+    /// This operation runs in O(1) time.
+    pub fn get_generation(&self, inode: Inode) -> Generation {
+        match self.assignment {
+            InodeAssignment::Sequential => {
+                let idx = (inode as usize).wrapping_sub(1);
+                self.generations.get(idx).cloned().unwrap_or(0)
+            },
+            InodeAssignment::HashedPath | InodeAssignment::Filesystem => {
+                self.hashed_generations.get(&inode).cloned().unwrap_or(0)
             },
-            Occupied(entry) => (entry.get() + 1) as Inode
         }
     }
 
+    /// Set the generation number of an inode whose number was supplied by the filesystem
+    /// (`InodeAssignment::Filesystem`), e.g. one it got from a backing filesystem along with the
+    /// inode number. With the other kinds of assignment, the table keeps track of generation
+    /// numbers itself, and this does nothing.
+    ///
+    /// This operation runs in O(1) time.
+    pub fn set_generation(&mut self, inode: Inode, generation: Generation) {
+        if self.assignment == InodeAssignment::Filesystem && self.by_inode.contains_key(&inode) {
+            self.hashed_generations.insert(inode, generation);
+        }
+    }
+
+    /// Add another path (a hard link) to an inode already in the table.
+    ///
+    /// The lookup count is not changed.
+    ///
+    /// Returns false, and does nothing, if the inode is not in the table, or the path has no
+    /// parent directory.
+    ///
+    /// This operation runs in O(d log n) time, where d is the number of components in the path.
+    pub fn add_link(&mut self, inode: Inode, path: &Path) -> bool {
+        let (parent, name) = match split(path) {
+            Some(split) => split,
+            None => return false,
+        };
+        if self.index(inode).is_none() {
+            return false;
+        }
+        self.evict();
+        // Evicting doesn't remove anything with lookups, or the parent of anything, but the inode
+        // may have neither.
+        let idx = match self.index(inode) {
+            Some(idx) => idx,
+            None => return false,
+        };
+        let parent_idx = self.find_or_create(parent);
+        // If the inode was deleted, its old path is no longer valid.
+        if self.entry(idx).deleted_path.is_some() {
+            self.entry_mut(idx).deleted_path = None;
+            self.changes += 1;
+        }
+        self.link(idx, parent_idx, name);
+        true
+    }
+
     /// Get the path that corresponds to an inode, if there is one, or None, if it is not in the
     /// table.
     ///
-    /// This operation runs in O(1) time.
+    /// If the inode has multiple paths (hard links), the first one still in use is returned.
+    ///
+    /// This operation runs in O(d) time, where d is the number of components in the path.
     pub fn get_path(&self, inode: Inode) -> Option<Arc<PathBuf>> {
-        let idx = inode as usize - 1;
-        match self.table[idx] {
-            Some(ref entry) => Some(entry.path.clone()),
+        match self.index(inode) {
+            Some(idx) => Some(self.path_of(idx)),
             None => None,
         }
     }
 
+    // Build the path of an entry from the names of it and its parents.
+    fn path_of(&self, idx: usize) -> Arc<PathBuf> {
+        let mut names: Vec<&OsStr> = vec![];
+        let mut idx = idx;
+        let mut path = PathBuf::from("/");
+        while idx != ROOT {
+            let entry = self.entry(idx);
+            if let Some(ref deleted_path) = entry.deleted_path {
+                path = (**deleted_path).clone();
+                break;
+            }
+            let (parent, ref name) = entry.links[0];
+            names.push(name);
+            idx = parent;
+        }
+        for name in names.iter().rev() {
+            path.push(name);
+        }
+        Arc::new(path)
+    }
+
     /// Get the inode that corresponds to a path, if there is one, or None, if it is not in the
     /// table.
     ///
-    /// This operation runs in O(log n) time.
-    pub fn get_inode(&mut self, path: &Path) -> Option<Inode> {
-        match self.by_path.get(Pathish::new(path)) {
-            Some(idx) => Some((idx + 1) as Inode),
+    /// This operation runs in O(d log n) time, where d is the number of components in the path.
+    pub fn get_inode(&self, path: &Path) -> Option<Inode> {
+        match self.find(path) {
+            Some(idx) => Some(self.entry(idx).inode),
+            None => None,
+        }
+    }
+
+    /// Returns whether the path an inode was mapped to has been unlinked, or None, if the inode is
+    /// not in the table.
+    ///
+    /// This operation runs in O(1) time.
+    pub fn is_deleted(&self, inode: Inode) -> Option<bool> {
+        match self.index(inode) {
+            Some(idx) => Some(self.entry(idx).deleted_path.is_some()),
             None => None,
         }
     }
 
     /// Increment the lookup count on a given inode.
     ///
-    /// Calling this on an invalid inode will result in a panic.
+    /// Returns the new lookup count, or None, if the inode is not in the table.
     ///
     /// This operation runs in O(1) time.
-    pub fn lookup(&mut self, inode: Inode) {
+    pub fn lookup(&mut self, inode: Inode) -> Option<LookupCount> {
         if inode == 1 {
-            return;
+            return Some(1);
         }
 
-        self.table[inode as usize - 1].as_mut().unwrap().lookups += 1;
+        let idx = match self.index(inode) {
+            Some(idx) => idx,
+            None => return None,
+        };
+        let entry = self.entry_mut(idx);
+        entry.lookups += 1;
+        Some(entry.lookups)
     }
 
     /// Decrement the lookup count on a given inode by the given number.
     ///
     /// If the lookup count reaches 0, the path is removed from the table, and the inode number
-    /// is eligible to be re-used.
+    /// is eligible to be re-used. If the number is greater than the lookup count (which means the
+    /// kernel's count disagrees with ours), an error is logged and the count is taken to be 0.
     ///
     /// Returns the new lookup count of the inode. (If it returns 0, that means the inode was
-    /// deleted.)
-    ///
-    /// Calling this on an invalid inode will result in a panic.
+    /// deleted.) Returns None if the inode is not in the table.
     ///
     /// This operation runs in O(1) time normally, or O(log n) time if the inode is deleted.
-    pub fn forget(&mut self, inode: Inode, n: LookupCount) -> LookupCount {
+    pub fn forget(&mut self, inode: Inode, n: LookupCount) -> Option<LookupCount> {
         if inode == 1 {
-            return 1;
+            return Some(1);
         }
 
-        let mut delete = false;
         let lookups: LookupCount;
-        let idx = inode as usize - 1;
+        let idx = match self.index(inode) {
+            Some(idx) => idx,
+            None => return None,
+        };
 
         {
-            let entry = self.table[idx].as_mut().unwrap();
-            assert!(n <= entry.lookups);
-            entry.lookups -= n;
-            lookups = entry.lookups;
-            if lookups == 0 {
-                delete = true;
-                self.by_path.remove(&*entry.path);
+            let entry = self.entry_mut(idx);
+            if n > entry.lookups {
+                error!("forget: inode {} has {} lookups; can't forget {}", inode, entry.lookups, n);
+                entry.lookups = 0;
+            } else {
+                entry.lookups -= n;
             }
+            lookups = entry.lookups;
+        }
+
+        if lookups == 0 {
+            self.remove(idx);
+        }
+
+        Some(lookups)
+    }
+
+    /// Remove the mapping from a path to its inode, without removing the inode itself.
+    ///
+    /// If this was the inode's last path, the inode is marked as deleted, but stays in the table
+    /// (and keeps reporting its last path) until its lookup count reaches zero, because the kernel
+    /// may still have it open. Subsequent lookups of the path will not find it, so a new entry
+    /// created with the same path gets a fresh inode.
+    ///
+    /// Returns the inode number the path was mapped to, if any.
+    ///
+    /// This operation runs in O(d log n) time, where d is the number of components in the path.
+    pub fn unlink(&mut self, path: &Path) -> Option<Inode> {
+        let (parent, name) = match split(path) {
+            Some(split) => split,
+            None => return None,
+        };
+        let parent_idx = match self.find(parent) {
+            Some(idx) => idx,
+            None => return None,
+        };
+        let key = self.key(name).into_owned();
+        match self.entry_mut(parent_idx).children.remove(&*key) {
+            Some(idx) => {
+                self.detach(idx, parent_idx, name);
+                Some(self.entry(idx).inode)
+            },
+            None => None,
         }
+    }
 
-        if delete {
-            self.table[idx] = None;
-            self.free_list.push_back(idx);
+    // Add a name in a directory for an entry. If the name was already mapped to a different
+    // entry, that entry is detached from it.
+    fn link(&mut self, idx: usize, parent_idx: usize, name: &OsStr) {
+        let key = self.key(name).into_owned();
+        let key = self.intern(&key);
+        let name = self.intern(name);
+        if let Some(replaced) = self.entry_mut(parent_idx).children.insert(key, idx) {
+            if replaced != idx {
+                self.detach(replaced, parent_idx, &name);
+            }
         }
+        self.entry_mut(idx).links.push((parent_idx, name));
+    }
 
-        lookups
+    // Remove a link from an entry, after it has been removed from the parent directory. If it was
+    // the last one, the entry is marked as deleted.
+    fn detach(&mut self, idx: usize, parent_idx: usize, name: &OsStr) {
+        self.changes += 1;
+        if self.entry(idx).links.len() > 1 {
+            self.unlink_name(idx, parent_idx, name);
+        } else {
+            let path = self.path_of(idx);
+            let entry = self.entry_mut(idx);
+            entry.links.clear();
+            entry.deleted_path = Some(path);
+        }
     }
 
     /// Change an inode's path to a different one, without changing the inode number.
-    pub fn rename(&mut self, oldpath: &Path, newpath: Arc<PathBuf>) {
-        let idx = self.by_path.remove(Pathish::new(oldpath)).unwrap();
-        self.table[idx].as_mut().unwrap().path = newpath.clone();
-        self.by_path.insert(newpath, idx); // this can replace a path with a new inode
+    ///
+    /// Any inodes whose paths are below the old path are moved to the new path as well.
+    ///
+    /// If the new path was already mapped to an inode, that inode is marked as deleted. If either
+    /// path has no parent directory, nothing is changed.
+    ///
+    /// This operation runs in O(d log n) time, where d is the number of components in the paths.
+    pub fn rename(&mut self, oldpath: &Path, newpath: &Path) {
+        let (oldparent, oldname, newparent, newname) = match (split(oldpath), split(newpath)) {
+            (Some((oldparent, oldname)), Some((newparent, newname))) => {
+                (oldparent, oldname, newparent, newname)
+            },
+            _ => return,
+        };
+        let oldparent_idx = match self.find(oldparent) {
+            Some(idx) => idx,
+            None => return,
+        };
+        if let Some(idx) = self.take(oldparent_idx, oldname) {
+            let newparent_idx = self.find_or_create(newparent);
+            self.link(idx, newparent_idx, newname);
+        }
     }
-}
 
-// Facilitates comparing Rc<PathBuf> and &Path
-struct Pathish {
-    inner: Path,
-}
+    /// Swap the paths of two inodes, without changing their inode numbers.
+    ///
+    /// Any inodes whose paths are below either path are swapped as well. If either path has no
+    /// parent directory, nothing is changed.
+    ///
+    /// This operation runs in O(d log n) time, where d is the number of components in the paths.
+    ///
+    /// It's used for OS X's `exchange`, and is also what a rename with `RENAME_EXCHANGE` needs,
+    /// once rust-fuse passes rename's flags on.
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+    pub fn exchange(&mut self, path_a: &Path, path_b: &Path) {
+        let (parent_a, name_a, parent_b, name_b) = match (split(path_a), split(path_b)) {
+            (Some((parent_a, name_a)), Some((parent_b, name_b))) => {
+                (parent_a, name_a, parent_b, name_b)
+            },
+            _ => return,
+        };
+        let parent_a_idx = self.find_or_create(parent_a);
+        let parent_b_idx = self.find_or_create(parent_b);
+        let a = self.take(parent_a_idx, name_a);
+        let b = self.take(parent_b_idx, name_b);
+        if let Some(a) = a {
+            self.link(a, parent_b_idx, name_b);
+        }
+        if let Some(b) = b {
+            self.link(b, parent_a_idx, name_a);
+        }
+    }
 
-impl Pathish {
-    pub fn new(p: &Path) -> &Pathish {
-        unsafe { ::std::mem::transmute(p) }
+    // Remove a name from a directory without marking the entry it was mapped to as deleted, so
+    // that it can be linked somewhere else.
+    fn take(&mut self, parent_idx: usize, name: &OsStr) -> Option<usize> {
+        let key = self.key(name).into_owned();
+        match self.entry_mut(parent_idx).children.remove(&*key) {
+            Some(idx) => {
+                self.changes += 1;
+                self.unlink_name(idx, parent_idx, name);
+                Some(idx)
+            },
+            None => None,
+        }
+    }
+
+    // Remove a name in a directory from an entry's links.
+    fn unlink_name(&mut self, idx: usize, parent_idx: usize, name: &OsStr) {
+        let case_insensitive = self.case_insensitive;
+        let key = self.key(name).into_owned();
+        self.entry_mut(idx).links.retain(|&(p, ref n)| {
+            !(p == parent_idx && *fold(n, case_insensitive) == *key)
+        });
+    }
+
+    fn entry(&self, idx: usize) -> &InodeTableEntry {
+        self.table[idx].as_ref().unwrap()
+    }
+
+    fn entry_mut(&mut self, idx: usize) -> &mut InodeTableEntry {
+        self.table[idx].as_mut().unwrap()
     }
 }
 
-impl ::std::borrow::Borrow<Pathish> for Arc<PathBuf> {
-    fn borrow(&self) -> &Pathish {
-        Pathish::new(self.as_path())
+// Get the form of a name that is used for comparing it with others: the name itself, or if case
+// doesn't matter, its lower-case form.
+fn fold<'a>(name: &'a OsStr, case_insensitive: bool) -> Cow<'a, OsStr> {
+    if !case_insensitive {
+        return Cow::Borrowed(name);
+    }
+    match name.to_str() {
+        Some(s) => Cow::Owned(OsString::from(s.to_lowercase())),
+        None => Cow::Owned(OsString::from_vec(name.as_bytes().to_ascii_lowercase())),
     }
 }
 
-impl ::std::cmp::Ord for Pathish {
-    fn cmp(&self, other: &Self) -> ::std::cmp::Ordering {
-        self.inner.cmp(&other.inner)
+// Split a path into its parent directory and its last component, or None, if it doesn't have
+// both (e.g. it's the root).
+fn split(path: &Path) -> Option<(&Path, &OsStr)> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Some((parent, name)),
+        _ => None,
     }
 }
 
-impl ::std::cmp::PartialOrd for Pathish {
-    fn partial_cmp(&self, other: &Pathish) -> Option<::std::cmp::Ordering> {
-        self.inner.partial_cmp(&other.inner)
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_u64<W: Write>(w: &mut W, n: u64) -> io::Result<()> {
+    let mut buf = [0u8; 8];
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = (n >> (8 * i)) as u8;
     }
+    w.write_all(&buf)
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(buf.iter().rev().fold(0, |n, &byte| (n << 8) | byte as u64))
+}
+
+fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_u64(w, bytes.len() as u64)?;
+    w.write_all(bytes)
 }
 
-impl ::std::cmp::Eq for Pathish {
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_u64(r)?;
+    let mut bytes = vec![];
+    r.by_ref().take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated path"));
+    }
+    Ok(bytes)
 }
 
-impl ::std::cmp::PartialEq for Pathish {
-    fn eq(&self, other: &Pathish) -> bool {
-        self.inner.eq(&other.inner)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(p: &str) -> &Path {
+        Path::new(p)
+    }
+
+    #[test]
+    fn rename_moves_descendants() {
+        let mut table = InodeTable::new();
+        let dir = table.add(path("/a"));
+        let file = table.add(path("/a/b/c"));
+        table.rename(path("/a"), path("/x"));
+
+        assert_eq!(table.get_inode(path("/x")), Some(dir));
+        assert_eq!(table.get_inode(path("/x/b/c")), Some(file));
+        assert_eq!(*table.get_path(file).unwrap(), PathBuf::from("/x/b/c"));
+        assert_eq!(table.get_inode(path("/a")), None);
+        assert_eq!(table.get_inode(path("/a/b/c")), None);
+    }
+
+    #[test]
+    fn eviction_keeps_looked_up_inodes() {
+        let mut table = InodeTable::new();
+        table.set_limit(Some(4));
+        let kept = table.add(path("/kept"));
+        for i in 0..16 {
+            table.add_or_get(&PathBuf::from(format!("/dir/{}", i)));
+        }
+
+        assert!(table.evictions() > 0);
+        assert_eq!(table.get_inode(path("/dir/0")), None);
+        assert_eq!(table.lookup_count(kept), Some(1));
+        assert_eq!(*table.get_path(kept).unwrap(), PathBuf::from("/kept"));
+
+        // Once the kernel forgets it, it can go.
+        assert_eq!(table.forget(kept, 1), Some(0));
+        assert_eq!(table.get_path(kept), None);
+    }
+
+    #[test]
+    fn save_and_load_keep_inode_numbers() {
+        let mut table = InodeTable::new();
+        let a = table.add(path("/a"));
+        let gone = table.add(path("/gone"));
+        let b = table.add(path("/a/b"));
+        table.forget(gone, 1);
+
+        let mut saved = vec![];
+        table.save(&mut saved).unwrap();
+        let mut loaded = InodeTable::new();
+        loaded.load(&mut &saved[..]).unwrap();
+
+        assert_eq!(loaded.get_inode(path("/a")), Some(a));
+        assert_eq!(loaded.get_inode(path("/a/b")), Some(b));
+        assert_eq!(loaded.get_inode(path("/gone")), None);
+        // The forgotten number won't be handed out again with the generation it had.
+        assert_eq!(loaded.get_generation(gone), 1);
+    }
+
+    #[test]
+    fn hashed_generations_are_only_kept_for_inodes_in_the_table() {
+        let mut table = InodeTable::new();
+        table.set_assignment(InodeAssignment::HashedPath);
+        let first = table.add(path("/a"));
+        let generation = table.get_generation(first);
+        table.forget(first, 1);
+        for i in 0..100 {
+            let inode = table.add(&PathBuf::from(format!("/tmp{}", i)));
+            table.forget(inode, 1);
+        }
+        assert_eq!(table.hashed_generations.len(), 0);
+
+        // The same path gets the same number back, but not with the same generation.
+        let again = table.add(path("/a"));
+        assert_eq!(again, first);
+        assert!(table.get_generation(again) != generation);
+    }
+
+    #[test]
+    fn case_folded_lookup_keeps_stored_case() {
+        let mut table = InodeTable::new();
+        table.set_case_insensitive(true);
+        let inode = table.add(path("/Dir/ReadMe.TXT"));
+
+        assert_eq!(table.get_inode(path("/dir/readme.txt")), Some(inode));
+        assert_eq!(table.add_or_get(path("/DIR/README.txt")), inode);
+        assert_eq!(*table.get_path(inode).unwrap(), PathBuf::from("/Dir/ReadMe.TXT"));
+    }
+
+    #[test]
+    fn unknown_inodes_and_paths_dont_panic() {
+        let mut table = InodeTable::new();
+        assert_eq!(table.is_deleted(42), None);
+        assert_eq!(table.lookup(42), None);
+        assert_eq!(table.forget(42, 1), None);
+        assert!(!table.add_link(42, path("/a")));
+        assert_eq!(table.get_generation(42), 0);
+        assert_eq!(table.unlink(path("/")), None);
+        table.rename(path("/"), path("/a"));
+        assert_eq!(table.add(path("/")), 1);
     }
 }
@@ -3,10 +3,47 @@ extern crate libc;
 extern crate threadpool;
 extern crate time;
 
+#[cfg(feature = "nix")]
+extern crate nix;
+
+#[cfg(feature = "tokio")]
+extern crate tokio as tokio_rt;
+
 #[macro_use]
 extern crate log;
 
+// Declared first, so that its macros can be used by the modules after it.
+#[macro_use]
+mod forward;
+
+mod attr_cache;
+mod errno;
+mod executor;
 mod fusemt;
+mod handle_table;
+mod inode_cache;
 mod inode_table;
+mod logging;
+mod metrics;
+mod mount;
+mod prefetch;
+mod read_buffer;
+mod watchdog;
+mod write_buffer;
+
+pub mod bench;
+
+#[cfg(feature = "tokio")]
+pub mod tokio;
 
+pub use attr_cache::AttrCacheFs;
+pub use errno::Errno;
+pub use executor::{deferred, BoxFuture, ReplyToken};
 pub use fusemt::*;
+pub use inode_table::{InodeAssignment, InodeInfo, InodeTableStats};
+pub use logging::LoggingFs;
+pub use metrics::{LatencyHistogram, Metrics, MetricsFs, MetricsSnapshot, OpMetrics};
+pub use mount::{mount, spawn_mount, MountOption, Session};
+pub use prefetch::{PrefetchFs, PrefetchHandle};
+pub use read_buffer::ReadBuffer;
+pub use write_buffer::{WriteBufferFs, WriteBufferHandle};
@@ -0,0 +1,258 @@
+// LoggingFs :: A wrapper around a filesystem that logs each operation done on it, with its
+//              arguments, result, and how long it took.
+//
+// Copyright (c) 2016 by William R. Fraser
+//
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::LogLevel;
+
+use errno::Errno;
+use executor::{self, BoxFuture};
+use fusemt::*;
+
+// Call an operation of the inner filesystem, and log it along with the given description of its
+// arguments.
+macro_rules! logged {
+    ($s:expr, $op:ident($($arg:expr),*), $($fmt:tt)*) => {
+        {
+            let start = Instant::now();
+            let result = $s.inner.$op($($arg),*);
+            $s.levels.log(stringify!($op), &format_args!($($fmt)*), start, &result);
+            result
+        }
+    }
+}
+
+// Log a future from an asynchronous operation of the inner filesystem once it completes. The
+// arguments are described up front, since they're moved into the call, so that's skipped if
+// nothing would be logged.
+macro_rules! logged_async {
+    ($s:expr, $name:ident, $op:ident($($arg:expr),*), $($fmt:tt)*) => {
+        if !$s.levels.enabled() {
+            $s.inner.clone().$op($($arg),*)
+        } else {
+            let levels = $s.levels;
+            let args = format!($($fmt)*);
+            let start = Instant::now();
+            let future = $s.inner.clone().$op($($arg),*);
+            executor::then(future, move|result| {
+                levels.log(stringify!($op), &args, start, &result);
+                result
+            })
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Levels {
+    ok: LogLevel,
+    error: LogLevel,
+}
+
+impl Levels {
+    // Whether either level is enabled.
+    fn enabled(&self) -> bool {
+        log_enabled!(self.ok) || log_enabled!(self.error)
+    }
+
+    fn log<R: Describe>(&self, op: &str, args: &dyn fmt::Display, start: Instant,
+                        result: &Result<R, Errno>) {
+        let elapsed = start.elapsed();
+        match *result {
+            Ok(ref value) => log!(self.ok, "{}({}) -> {} in {:?}", op, args, value.describe(),
+                                  elapsed),
+            Err(e) => log!(self.error, "{}({}) -> error: {} in {:?}", op, args, e, elapsed),
+        }
+    }
+}
+
+// A short description of what an operation returned, for the log.
+trait Describe {
+    fn describe(&self) -> String;
+}
+
+impl Describe for () {
+    fn describe(&self) -> String {
+        "ok".to_owned()
+    }
+}
+
+impl Describe for usize {
+    fn describe(&self) -> String {
+        format!("{} bytes", self)
+    }
+}
+
+impl Describe for u32 {
+    fn describe(&self) -> String {
+        format!("{} bytes", self)
+    }
+}
+
+impl Describe for u64 {
+    fn describe(&self) -> String {
+        format!("block {}", self)
+    }
+}
+
+impl Describe for Vec<u8> {
+    fn describe(&self) -> String {
+        format!("{} bytes", self.len())
+    }
+}
+
+impl Describe for FileAttr {
+    fn describe(&self) -> String {
+        format!("{:?}, mode {:#o}, size {}, uid {}, gid {}", self.kind, self.perm, self.size,
+                self.uid, self.gid)
+    }
+}
+
+impl Describe for (Duration, FileAttr) {
+    fn describe(&self) -> String {
+        self.1.describe()
+    }
+}
+
+impl Describe for Entry {
+    fn describe(&self) -> String {
+        self.attr.describe()
+    }
+}
+
+impl<H> Describe for (H, OpenFlags) {
+    fn describe(&self) -> String {
+        format!("opened with {:?}", self.1)
+    }
+}
+
+impl<H> Describe for CreatedEntry<H> {
+    fn describe(&self) -> String {
+        format!("{}, opened with {:?}", self.attr.describe(), self.flags)
+    }
+}
+
+impl Describe for DirectoryEntries {
+    fn describe(&self) -> String {
+        "listing".to_owned()
+    }
+}
+
+impl Describe for Statfs {
+    fn describe(&self) -> String {
+        format!("{} of {} blocks free, {} files free", self.bfree, self.blocks, self.ffree)
+    }
+}
+
+impl Describe for Xattr {
+    fn describe(&self) -> String {
+        match *self {
+            Xattr::Size(size) => format!("size {}", size),
+            Xattr::Data(ref data) => format!("{} bytes", data.len()),
+        }
+    }
+}
+
+impl Describe for FileLock {
+    fn describe(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl Describe for XTimes {
+    fn describe(&self) -> String {
+        format!("bkuptime {:?}, crtime {:?}", self.bkuptime, self.crtime)
+    }
+}
+
+/// Wraps a filesystem, logging every operation done on it: its name and arguments, what it
+/// returned (or the error it failed with), and how long it took. This is for debugging a
+/// filesystem without adding logging to each of its operations.
+///
+/// Operations are logged at `LogLevel::Debug`, whether they succeed or fail (errors like `ENOENT`
+/// from `lookup` are part of normal use). The levels can be changed with `level` and
+/// `error_level`, e.g. to log only failures, at `LogLevel::Warn`. The data read and written isn't
+/// logged, only its size.
+pub struct LoggingFs<T> {
+    inner: Arc<T>,
+    levels: Levels,
+}
+
+impl<T: FilesystemMT + Sync + Send + 'static> LoggingFs<T> {
+    /// Wrap a filesystem, logging its operations.
+    pub fn new(inner: T) -> LoggingFs<T> {
+        LoggingFs {
+            inner: Arc::new(inner),
+            levels: Levels {
+                ok: LogLevel::Debug,
+                error: LogLevel::Debug,
+            },
+        }
+    }
+
+    /// Set the level that operations that succeed are logged at.
+    pub fn level(mut self, level: LogLevel) -> Self {
+        self.levels.ok = level;
+        self
+    }
+
+    /// Set the level that operations that fail are logged at.
+    pub fn error_level(mut self, level: LogLevel) -> Self {
+        self.levels.error = level;
+        self
+    }
+}
+
+impl<T: FilesystemMT + Sync + Send + 'static> FilesystemMT for LoggingFs<T> {
+    type FileHandle = T::FileHandle;
+    type DirHandle = T::DirHandle;
+
+    fn destroy(&self, req: RequestInfo) {
+        let start = Instant::now();
+        self.inner.destroy(req);
+        self.levels.log("destroy", &"", start, &Ok::<(), Errno>(()));
+    }
+
+    fn read(&self, req: RequestInfo, path: &Path, fh: &Self::FileHandle, offset: u64, size: u32, callback: impl FnOnce(ResultSlice) -> CallbackResult) -> CallbackResult {
+        let levels = self.levels;
+        let start = Instant::now();
+        self.inner.read(req, path, fh, offset, size, move|result| {
+            levels.log("read", &format_args!("{:?}, offset={}, size={}", path, offset, size),
+                       start, &result.map(|data| data.len()));
+            callback(result)
+        })
+    }
+
+    fn read_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, offset: u64, size: u32, callback: ReadCallback) -> BoxFuture<CallbackResult> {
+        if !self.levels.enabled() {
+            return self.inner.clone().read_async(req, path, fh, offset, size, callback);
+        }
+        let levels = self.levels;
+        let args = format!("{:?}, offset={}, size={}", path, offset, size);
+        let start = Instant::now();
+        let callback: ReadCallback = Box::new(move|result| {
+            levels.log("read_async", &args, start, &result.map(|data| data.len()));
+            callback(result)
+        });
+        self.inner.clone().read_async(req, path, fh, offset, size, callback)
+    }
+
+    forward! {
+        handles: same,
+        via: [logged, logged_async],
+        methods: [
+            init, lookup, getattr, setattr, chmod, chown, truncate, utimens, utimens_macos,
+            readlink, mknod, mkdir, unlink, rmdir, symlink, rename, link, open, write, flush,
+            release, fsync, opendir, readdir, releasedir, fsyncdir, statfs, setxattr, getxattr,
+            listxattr, removexattr, access, create, getlk, setlk, bmap, setvolname, exchange,
+            getxtimes, write_async, flush_async, fsync_async, getattr_async, readdir_async,
+            setlk_async,
+        ],
+    }
+}
@@ -0,0 +1,305 @@
+// MetricsFs :: A wrapper around a filesystem that counts the operations done on it, the errors
+//              they fail with, and the bytes read and written, and keeps histograms of how long
+//              they take.
+//
+// Copyright (c) 2016 by William R. Fraser
+//
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use errno::Errno;
+use executor::{self, BoxFuture};
+use fusemt::*;
+
+// The number of buckets in a latency histogram. The first holds latencies under a microsecond,
+// each one after that holds ones up to twice as long as the one before, and the last holds
+// everything from about 18 minutes up.
+const BUCKETS: usize = 32;
+
+// Call an operation of the inner filesystem, and record it under its name. Anything after the
+// call (a description of it, from `forward!`) is ignored.
+macro_rules! measured {
+    ($s:expr, $op:ident($($arg:expr),*) $($desc:tt)*) => {
+        {
+            let start = Instant::now();
+            let result = $s.inner.$op($($arg),*);
+            $s.metrics.record(stringify!($op), start, result.as_ref().err());
+            result
+        }
+    }
+}
+
+// Record a future from an asynchronous operation of the inner filesystem once it completes, under
+// the name of the synchronous version, so that the numbers don't depend on which one FuseMT
+// called.
+macro_rules! measured_async {
+    ($s:expr, $name:ident, $op:ident($($arg:expr),*) $($desc:tt)*) => {
+        {
+            let metrics = $s.metrics.clone();
+            let start = Instant::now();
+            let future = $s.inner.clone().$op($($arg),*);
+            executor::then(future, move|result| {
+                metrics.record(stringify!($name), start, result.as_ref().err());
+                result
+            })
+        }
+    }
+}
+
+/// A histogram of how long an operation took, in buckets that each cover twice the time of the one
+/// before.
+#[derive(Clone, Debug, Default)]
+pub struct LatencyHistogram {
+    counts: [u64; BUCKETS],
+    sum: Duration,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, latency: Duration) {
+        let micros = latency.as_secs() * 1_000_000 + latency.subsec_micros() as u64;
+        let bucket = (64 - micros.leading_zeros()) as usize;
+        self.counts[cmp::min(bucket, BUCKETS - 1)] += 1;
+        self.sum += latency;
+        self.max = cmp::max(self.max, latency);
+    }
+
+    // The shortest latency that goes in a bucket.
+    fn lower_bound(bucket: usize) -> Duration {
+        if bucket == 0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_micros(1 << (bucket - 1))
+        }
+    }
+
+    /// The buckets, as the shortest latency that goes in each one, and the number of operations
+    /// in it. Each bucket goes up to where the next one starts, and the last has no upper limit.
+    pub fn buckets(&self) -> Vec<(Duration, u64)> {
+        self.counts.iter().enumerate()
+            .map(|(bucket, &count)| (LatencyHistogram::lower_bound(bucket), count))
+            .collect()
+    }
+
+    /// The number of operations recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The latencies of all the operations recorded, added up.
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// The longest latency recorded.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// An estimate of the latency that the given percentage (0 to 100) of operations were at least
+    /// as fast as: the upper limit of the bucket it falls in, or the longest latency recorded, if
+    /// that's less.
+    pub fn percentile(&self, percent: f64) -> Duration {
+        let count = self.count();
+        if count == 0 {
+            return Duration::from_secs(0);
+        }
+        let rank = cmp::max(1, (percent / 100. * count as f64).ceil() as u64);
+        let mut seen = 0;
+        for (bucket, &n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank && bucket + 1 < BUCKETS {
+                return cmp::min(LatencyHistogram::lower_bound(bucket + 1), self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// The numbers recorded for one kind of operation.
+#[derive(Clone, Debug, Default)]
+pub struct OpMetrics {
+    /// The number of times it was done, including the ones that failed.
+    pub count: u64,
+
+    /// The number of times it failed, by the error it failed with.
+    pub errors: HashMap<Errno, u64>,
+
+    /// How long it took.
+    pub latency: LatencyHistogram,
+}
+
+/// The numbers recorded by a `MetricsFs` up to some point.
+#[derive(Clone, Debug, Default)]
+pub struct MetricsSnapshot {
+    /// The numbers for each operation that has been done at least once, by name (e.g. "read").
+    pub ops: BTreeMap<&'static str, OpMetrics>,
+
+    /// The number of bytes returned by reads.
+    pub bytes_read: u64,
+
+    /// The number of bytes that writes said they wrote.
+    pub bytes_written: u64,
+}
+
+/// A handle for getting the numbers recorded by a `MetricsFs`, obtained with `MetricsFs::metrics`.
+/// It can be cloned and sent to other threads, and used while the filesystem is mounted.
+#[derive(Clone)]
+pub struct Metrics {
+    recorded: Arc<Mutex<MetricsSnapshot>>,
+}
+
+impl Metrics {
+    /// Get the numbers recorded so far. They're counted from when the `MetricsFs` was made, and
+    /// never reset, so e.g. rates have to be worked out from the difference between snapshots.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.recorded.lock().unwrap().clone()
+    }
+
+    fn record(&self, op: &'static str, start: Instant, error: Option<&Errno>) {
+        let latency = start.elapsed();
+        let mut recorded = self.recorded.lock().unwrap();
+        let metrics = recorded.ops.entry(op).or_default();
+        metrics.count += 1;
+        if let Some(&errno) = error {
+            *metrics.errors.entry(errno).or_insert(0) += 1;
+        }
+        metrics.latency.record(latency);
+    }
+
+    fn record_read(&self, start: Instant, result: &ResultSlice) {
+        self.record("read", start, result.as_ref().err());
+        if let Ok(data) = *result {
+            self.recorded.lock().unwrap().bytes_read += data.len() as u64;
+        }
+    }
+
+    fn record_write(&self, result: &ResultWrite) {
+        if let Ok(written) = *result {
+            self.recorded.lock().unwrap().bytes_written += written as u64;
+        }
+    }
+}
+
+/// Wraps a filesystem, recording how many times each operation is done, the errors they fail with,
+/// how long they take, and how many bytes are read and written, for the application's monitoring
+/// to collect with `Metrics::snapshot`.
+///
+/// The time recorded is how long the filesystem took, which doesn't include time spent waiting to
+/// be dispatched (see `FuseMT::operation_hook` for that).
+pub struct MetricsFs<T> {
+    inner: Arc<T>,
+    metrics: Metrics,
+}
+
+impl<T: FilesystemMT + Sync + Send + 'static> MetricsFs<T> {
+    /// Wrap a filesystem, recording metrics for its operations.
+    pub fn new(inner: T) -> MetricsFs<T> {
+        MetricsFs {
+            inner: Arc::new(inner),
+            metrics: Metrics {
+                recorded: Arc::new(Mutex::new(MetricsSnapshot::default())),
+            },
+        }
+    }
+
+    /// Get a handle for getting the numbers recorded, which can be kept after the filesystem is
+    /// handed to `FuseMT`.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+}
+
+impl<T: FilesystemMT + Sync + Send + 'static> FilesystemMT for MetricsFs<T> {
+    type FileHandle = T::FileHandle;
+    type DirHandle = T::DirHandle;
+
+    fn destroy(&self, req: RequestInfo) {
+        self.inner.destroy(req)
+    }
+
+    fn read(&self, req: RequestInfo, path: &Path, fh: &Self::FileHandle, offset: u64, size: u32, callback: impl FnOnce(ResultSlice) -> CallbackResult) -> CallbackResult {
+        let metrics = &self.metrics;
+        let start = Instant::now();
+        self.inner.read(req, path, fh, offset, size, move|result| {
+            metrics.record_read(start, &result);
+            callback(result)
+        })
+    }
+
+    fn write(&self, req: RequestInfo, path: &Path, fh: &Self::FileHandle, offset: u64, data: &[u8], flags: u32) -> ResultWrite {
+        let result = measured!(self, write(req, path, fh, offset, data, flags));
+        self.metrics.record_write(&result);
+        result
+    }
+
+    fn read_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, offset: u64, size: u32, callback: ReadCallback) -> BoxFuture<CallbackResult> {
+        let metrics = self.metrics.clone();
+        let start = Instant::now();
+        let callback: ReadCallback = Box::new(move|result| {
+            metrics.record_read(start, &result);
+            callback(result)
+        });
+        self.inner.clone().read_async(req, path, fh, offset, size, callback)
+    }
+
+    fn write_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, offset: u64, data: Vec<u8>, flags: u32) -> BoxFuture<ResultWrite> {
+        let metrics = self.metrics.clone();
+        let future = measured_async!(self, write, write_async(req, path, fh, offset, data, flags));
+        executor::then(future, move|result| {
+            metrics.record_write(&result);
+            result
+        })
+    }
+
+    forward! {
+        handles: same,
+        via: [measured, measured_async],
+        methods: [
+            init, lookup, getattr, setattr, chmod, chown, truncate, utimens, utimens_macos,
+            readlink, mknod, mkdir, unlink, rmdir, symlink, rename, link, open, flush, release,
+            fsync, opendir, readdir, releasedir, fsyncdir, statfs, setxattr, getxattr, listxattr,
+            removexattr, access, create, getlk, setlk, bmap, setvolname, exchange, getxtimes,
+            flush_async, fsync_async, getattr_async, readdir_async, setlk_async,
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_are_bucket_limits() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(50.), Duration::from_secs(0));
+
+        for _ in 0..90 {
+            histogram.record(Duration::from_micros(100));
+        }
+        for _ in 0..10 {
+            histogram.record(Duration::from_millis(10));
+        }
+        assert_eq!(histogram.count(), 100);
+
+        // 100us is in the bucket that goes up to 128us.
+        assert_eq!(histogram.percentile(0.), Duration::from_micros(128));
+        assert_eq!(histogram.percentile(50.), Duration::from_micros(128));
+        assert_eq!(histogram.percentile(90.), Duration::from_micros(128));
+        // 10ms is in the bucket that goes up to 16.384ms, but nothing took that long.
+        assert_eq!(histogram.percentile(95.), Duration::from_millis(10));
+        assert_eq!(histogram.percentile(100.), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn last_bucket_has_no_upper_limit() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.record(Duration::from_secs(3600));
+        assert_eq!(histogram.buckets().last(), Some(&(Duration::from_micros(1 << 30), 1)));
+        assert_eq!(histogram.percentile(99.), Duration::from_secs(3600));
+    }
+}
@@ -0,0 +1,294 @@
+// Mount :: Mounts a FuseMT filesystem, either running it on the current thread until it's
+//          unmounted, or in the background.
+//
+// Copyright (c) 2016 by William R. Fraser
+//
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use fuse;
+use libc;
+use threadpool::ThreadPool;
+
+use fusemt::*;
+use read_buffer;
+
+/// An option to mount a filesystem with, which is passed on to the kernel (and `fusermount`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MountOption {
+    /// Let users other than the one who mounted the filesystem access it. Unless the filesystem
+    /// is mounted by root, this needs `user_allow_other` in `/etc/fuse.conf`.
+    AllowOther,
+    /// Let root access the filesystem, as well as the user who mounted it.
+    AllowRoot,
+    /// Have the kernel check permissions against the mode bits itself, instead of calling
+    /// `access` and leaving it to the filesystem.
+    DefaultPermissions,
+    /// Unmount the filesystem when the process that mounted it exits, even if it crashes.
+    AutoUnmount,
+    /// The name of what's mounted, shown as the source of the mount (e.g. by `mount` and `df`).
+    FsName(String),
+    /// The type of the filesystem, shown as `fuse.<subtype>`.
+    Subtype(String),
+    /// Mount the filesystem read-only.
+    RO,
+    /// Mount the filesystem read-write (the default).
+    RW,
+    /// Don't allow executing files on the filesystem.
+    NoExec,
+    /// Allow executing files on the filesystem (the default).
+    Exec,
+    /// Ignore set-user-ID and set-group-ID bits.
+    NoSuid,
+    /// Honor set-user-ID and set-group-ID bits (the default, when mounted by root).
+    Suid,
+    /// Don't allow opening device files on the filesystem.
+    NoDev,
+    /// Allow opening device files on the filesystem (the default, when mounted by root).
+    Dev,
+    /// Don't update access times.
+    NoAtime,
+    /// Update access times (the default).
+    Atime,
+    /// Do all I/O to the filesystem synchronously.
+    Sync,
+    /// Do I/O to the filesystem asynchronously (the default).
+    Async,
+    /// Make changes to directories synchronously.
+    DirSync,
+    /// Any other option, as it's given to `-o`, e.g. `MountOption::Custom("max_read=131072".into())`.
+    Custom(String),
+}
+
+impl MountOption {
+    // The option as it's given to `-o`.
+    fn to_option_string(&self) -> String {
+        match *self {
+            MountOption::AllowOther => "allow_other".to_owned(),
+            MountOption::AllowRoot => "allow_root".to_owned(),
+            MountOption::DefaultPermissions => "default_permissions".to_owned(),
+            MountOption::AutoUnmount => "auto_unmount".to_owned(),
+            MountOption::FsName(ref name) => format!("fsname={}", escape_option(name)),
+            MountOption::Subtype(ref subtype) => format!("subtype={}", escape_option(subtype)),
+            MountOption::RO => "ro".to_owned(),
+            MountOption::RW => "rw".to_owned(),
+            MountOption::NoExec => "noexec".to_owned(),
+            MountOption::Exec => "exec".to_owned(),
+            MountOption::NoSuid => "nosuid".to_owned(),
+            MountOption::Suid => "suid".to_owned(),
+            MountOption::NoDev => "nodev".to_owned(),
+            MountOption::Dev => "dev".to_owned(),
+            MountOption::NoAtime => "noatime".to_owned(),
+            MountOption::Atime => "atime".to_owned(),
+            MountOption::Sync => "sync".to_owned(),
+            MountOption::Async => "async".to_owned(),
+            MountOption::DirSync => "dirsync".to_owned(),
+            MountOption::Custom(ref option) => option.clone(),
+        }
+    }
+}
+
+// Escape the commas in an option's value (and the backslashes that escape them), so that they
+// aren't taken as separating it from the next option.
+fn escape_option(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(',', "\\,")
+}
+
+// The arguments to pass to the kernel (and `fusermount`) for a set of options.
+pub(crate) fn mount_args<'a, I>(options: I) -> Vec<OsString>
+    where I: IntoIterator<Item = &'a MountOption>
+{
+    let mut args = vec![];
+    for option in options {
+        args.push(OsString::from("-o"));
+        args.push(OsString::from(option.to_option_string()));
+    }
+    args
+}
+
+// The largest read the kernel will ask for with a set of options.
+pub(crate) fn max_read(options: &[MountOption]) -> usize {
+    let mut max_read = read_buffer::default_max_read();
+    for option in options {
+        if let MountOption::Custom(ref option) = *option {
+            let mut parts = option.splitn(2, '=');
+            if parts.next() == Some("max_read") {
+                if let Some(Ok(n)) = parts.next().map(str::parse) {
+                    max_read = n;
+                }
+            }
+        }
+    }
+    max_read
+}
+
+// Check the options for mistakes that would otherwise only show up as the mount failing with a
+// bare EPERM (and fusermount printing the reason to stderr), so that the error says what's wrong.
+pub(crate) fn check_options(options: &[MountOption]) -> io::Result<()> {
+    let allow_other = options.contains(&MountOption::AllowOther);
+    let allow_root = options.contains(&MountOption::AllowRoot);
+    if allow_other && allow_root {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "the allow_other and allow_root mount options can't be used together"));
+    }
+
+    // Only root can use these without fusermount's permission, which on Linux is given in
+    // /etc/fuse.conf.
+    if cfg!(target_os = "linux") && (allow_other || allow_root) && !privileged()
+            && !fuse_conf_allows_other() {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!(
+            "the {} mount option needs `user_allow_other` in /etc/fuse.conf when not mounting as \
+             root", if allow_other { "allow_other" } else { "allow_root" })));
+    }
+
+    // libfuse leaves auto_unmount to fusermount even when it could mount the filesystem itself,
+    // since fusermount is what waits for the process to exit and then unmounts it.
+    if cfg!(target_os = "linux") && options.contains(&MountOption::AutoUnmount)
+            && !fusermount_installed() {
+        return Err(io::Error::new(io::ErrorKind::NotFound,
+            "the auto_unmount mount option needs fusermount, which isn't installed"));
+    }
+
+    Ok(())
+}
+
+fn privileged() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+// Whether /etc/fuse.conf lets users other than root use allow_other and allow_root.
+fn fuse_conf_allows_other() -> bool {
+    match fs::read_to_string("/etc/fuse.conf") {
+        Ok(conf) => conf.lines().any(|line| {
+            line.split('#').next().unwrap_or("").trim() == "user_allow_other"
+        }),
+        Err(_) => false,
+    }
+}
+
+// Whether fusermount can be found where libfuse looks for it: in its install directory (usually
+// /bin or /usr/bin), or on the PATH.
+fn fusermount_installed() -> bool {
+    let mut dirs = vec![PathBuf::from("/bin"), PathBuf::from("/usr/bin")];
+    if let Some(path) = env::var_os("PATH") {
+        dirs.extend(env::split_paths(&path));
+    }
+    dirs.iter().any(|dir| dir.join("fusermount").is_file())
+}
+
+/// Mount a filesystem, and handle its requests on the current thread until it's unmounted.
+///
+/// `options` are used after any set with `FuseMT::mount_options`. Options that can't work (e.g.
+/// `MountOption::AllowOther` without permission for it in `/etc/fuse.conf`) fail with an error
+/// that says why, before anything is mounted.
+///
+/// When running as root, the filesystem is mounted directly; otherwise it's mounted by running
+/// `fusermount`, which has to be installed (FUSE 3's `fusermount3` can't be used).
+pub fn mount<T, P>(fs: FuseMT<T>, mountpoint: &P, options: &[MountOption]) -> io::Result<()>
+    where T: FilesystemMT + Sync + Send + 'static,
+          P: AsRef<Path> + ?Sized
+{
+    let options = fs.all_mount_options(options)?;
+    let options: Vec<&OsStr> = options.iter().map(|option| option.as_os_str()).collect();
+    let mut session = fuse::Session::new(fs, mountpoint.as_ref(), &options)?;
+    session.run()
+}
+
+/// Mount a filesystem, and handle its requests on a new thread. The filesystem stays mounted until
+/// the returned session is dropped, or `unmount` is called on it. The options are the same as for
+/// `mount`.
+pub fn spawn_mount<T, P>(fs: FuseMT<T>, mountpoint: &P, options: &[MountOption])
+        -> io::Result<Session>
+    where T: FilesystemMT + Sync + Send + 'static,
+          P: AsRef<Path> + ?Sized
+{
+    let mountpoint = mountpoint.as_ref().to_path_buf();
+    let options = fs.all_mount_options(options)?;
+    let options: Vec<&OsStr> = options.iter().map(|option| option.as_os_str()).collect();
+    let pools = fs.worker_pools();
+    // This is only unsafe because the session could otherwise outlive things the filesystem
+    // borrows, and FuseMT doesn't borrow anything.
+    let session = unsafe { fuse::spawn_mount(fs, &mountpoint, &options) }?;
+    Ok(Session {
+        session: Some(session),
+        pools: pools,
+        mountpoint: mountpoint,
+    })
+}
+
+/// A filesystem mounted with `spawn_mount`. Dropping it unmounts the filesystem, like `unmount`
+/// does.
+pub struct Session {
+    // None once it has been unmounted.
+    session: Option<fuse::BackgroundSession<'static>>,
+    pools: Vec<ThreadPool>,
+    mountpoint: PathBuf,
+}
+
+impl Session {
+    /// Get the path the filesystem is mounted at.
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Unmount the filesystem, and wait for the thread handling its requests, and the worker
+    /// threads, to finish what they're doing. Once this returns, nothing is left running the
+    /// filesystem's operations (except futures spawned with `FuseMT::spawn_futures_with`).
+    pub fn unmount(mut self) {
+        self.finish();
+    }
+
+    fn finish(&mut self) {
+        if let Some(session) = self.session.take() {
+            debug!("unmounting {:?}", self.mountpoint);
+            // Dropping the session unmounts the filesystem, and joins its thread.
+            drop(session);
+            for pool in self.pools.drain(..) {
+                pool.join();
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn option_values_are_escaped() {
+        assert_eq!(MountOption::FsName("a,b\\c".to_owned()).to_option_string(), "fsname=a\\,b\\\\c");
+        assert_eq!(MountOption::Subtype("plain".to_owned()).to_option_string(), "subtype=plain");
+        // Custom options are passed on as they are, since they may be several options already.
+        assert_eq!(MountOption::Custom("ro,noexec".to_owned()).to_option_string(), "ro,noexec");
+
+        let args = mount_args(&[MountOption::RO, MountOption::FsName("x,y".to_owned())]);
+        assert_eq!(args, vec![OsString::from("-o"), OsString::from("ro"),
+                              OsString::from("-o"), OsString::from("fsname=x\\,y")]);
+    }
+    #[test]
+    fn conflicting_options_are_rejected() {
+        let error = check_options(&[MountOption::AllowOther, MountOption::AllowRoot]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(check_options(&[MountOption::RO, MountOption::DefaultPermissions]).is_ok());
+    }
+
+    #[test]
+    fn allow_other_needs_root_or_fuse_conf() {
+        let result = check_options(&[MountOption::AllowOther]);
+        if !cfg!(target_os = "linux") || privileged() || fuse_conf_allows_other() {
+            assert!(result.is_ok());
+        } else {
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        }
+    }
+}
@@ -0,0 +1,370 @@
+// PrefetchFs :: A wrapper around a filesystem that notices when a file is being read
+//               sequentially, and reads ahead of the application in the background.
+//
+// Copyright (c) 2016 by William R. Fraser
+//
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::ffi::OsStr;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+
+use libc;
+use threadpool::ThreadPool;
+
+use errno::Errno;
+use executor::{self, BoxFuture};
+use fusemt::*;
+
+// How many reads in a row have to follow on from each other before reading ahead starts.
+const SEQUENTIAL_READS: u32 = 2;
+
+// How many blocks to read ahead, at most.
+const MAX_BLOCKS: usize = 2;
+
+type Waiter = Box<dyn FnOnce(&Result<Vec<u8>, Errno>) + Send>;
+
+enum BlockState {
+    Reading(Vec<Waiter>),
+    Done(Result<Vec<u8>, Errno>),
+}
+
+// Data that has been (or is being) read ahead.
+struct Block {
+    offset: u64,
+    size: u64,
+    state: Mutex<BlockState>,
+}
+
+impl Block {
+    // Whether the block has all the data for a read. A block that's been read and came up short
+    // (because it reached the end of the file) has everything there is after its offset.
+    fn covers(&self, offset: u64, size: u64) -> bool {
+        if offset < self.offset {
+            return false;
+        }
+        if offset + size <= self.offset + self.size {
+            return true;
+        }
+        match *self.state.lock().unwrap() {
+            BlockState::Done(Ok(ref data)) => (data.len() as u64) < self.size,
+            _ => false,
+        }
+    }
+
+    fn failed(&self) -> bool {
+        match *self.state.lock().unwrap() {
+            BlockState::Done(Err(_)) => true,
+            _ => false,
+        }
+    }
+
+    // Whether the block has been read and reached the end of the file.
+    fn at_eof(&self) -> bool {
+        match *self.state.lock().unwrap() {
+            BlockState::Done(Ok(ref data)) => (data.len() as u64) < self.size,
+            _ => false,
+        }
+    }
+
+    // Call a function with the block's data, now if it has been read, or once it has.
+    fn with_data(&self, waiter: Waiter) {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            BlockState::Reading(ref mut waiters) => waiters.push(waiter),
+            BlockState::Done(ref result) => waiter(result),
+        }
+    }
+
+    fn finish(&self, result: Result<Vec<u8>, Errno>) {
+        let mut state = self.state.lock().unwrap();
+        let waiters = match mem::replace(&mut *state, BlockState::Done(result)) {
+            BlockState::Reading(waiters) => waiters,
+            BlockState::Done(_) => unreachable!(),
+        };
+        if let BlockState::Done(ref result) = *state {
+            for waiter in waiters {
+                waiter(result);
+            }
+        }
+    }
+}
+
+// Fills in a block that's being read ahead. If it's dropped without doing so (e.g. because the
+// filesystem panicked), the block fails with EIO, so that reads waiting on it don't wait forever.
+struct Fill(Option<Arc<Block>>);
+
+impl Fill {
+    fn finish(mut self, result: Result<Vec<u8>, Errno>) {
+        if let Some(block) = self.0.take() {
+            block.finish(result);
+        }
+    }
+}
+
+impl Drop for Fill {
+    fn drop(&mut self) {
+        if let Some(block) = self.0.take() {
+            block.finish(Err(Errno(libc::EIO)));
+        }
+    }
+}
+
+// What's known about how a file handle is being read.
+struct Stream {
+    // Where the next read starts if it follows on from the last one.
+    next: u64,
+    // How many reads in a row have followed on from each other.
+    run: u32,
+    blocks: VecDeque<Arc<Block>>,
+    // How many blocks are being read ahead on the handle.
+    in_flight: usize,
+    // Whether the handle is being released, so nothing more should be read ahead on it.
+    closed: bool,
+}
+
+/// The handle of a file opened through a `PrefetchFs`: the wrapped filesystem's handle, and what
+/// has been read ahead on it.
+pub struct PrefetchHandle<H> {
+    inner: Arc<H>,
+    stream: Mutex<Stream>,
+    // Signalled when the last read-ahead in flight on the handle finishes.
+    idle: Condvar,
+}
+
+impl<H> PrefetchHandle<H> {
+    fn new(inner: H) -> PrefetchHandle<H> {
+        PrefetchHandle {
+            inner: Arc::new(inner),
+            stream: Mutex::new(Stream {
+                next: 0,
+                run: 0,
+                blocks: VecDeque::new(),
+                in_flight: 0,
+                closed: false,
+            }),
+            idle: Condvar::new(),
+        }
+    }
+
+    // Drop what has been read ahead.
+    fn discard(&self) {
+        let mut stream = self.stream.lock().unwrap();
+        stream.run = 0;
+        stream.blocks.clear();
+    }
+
+    // Stop reading ahead, and wait for the read-aheads in flight to finish, so that the wrapped
+    // handle can be released without them still using it.
+    fn close(&self) {
+        let mut stream = self.stream.lock().unwrap();
+        stream.closed = true;
+        stream.run = 0;
+        stream.blocks.clear();
+        while stream.in_flight > 0 {
+            stream = self.idle.wait(stream).unwrap();
+        }
+    }
+}
+
+// Counts a block being read ahead on a handle as in flight until it's dropped.
+struct InFlight<H>(Arc<PrefetchHandle<H>>);
+
+impl<H> Drop for InFlight<H> {
+    fn drop(&mut self) {
+        let mut stream = self.0.stream.lock().unwrap();
+        stream.in_flight -= 1;
+        if stream.in_flight == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+/// Wraps a filesystem, reading ahead of the application in the background when a file handle is
+/// being read sequentially, and then answering the reads that follow from what was read ahead.
+/// This helps a lot when each read from the backend has a high latency (e.g. HTTP range
+/// requests).
+///
+/// Reading ahead is done through `read_async`, on the wrapper's own threads, on the same file
+/// handle the application is reading, so the filesystem has to be able to handle more than one
+/// read on a handle at a time (e.g. by using `pread`). Data that has been read ahead is dropped
+/// when the handle is written to or truncated, but changes made through other handles (or other
+/// paths) while a file is being read may not be seen in what was already read ahead. Releasing a
+/// handle waits for the reads ahead still running on it, so the filesystem's `release` never runs
+/// while they are.
+pub struct PrefetchFs<T> {
+    inner: Arc<T>,
+    window: u64,
+    threads: ThreadPool,
+}
+
+impl<T: FilesystemMT + Sync + Send + 'static> PrefetchFs<T> {
+    /// Wrap a filesystem, reading ahead in blocks of `window` bytes, using the given number of
+    /// threads.
+    pub fn new(inner: T, window: u32, num_threads: usize) -> PrefetchFs<T> {
+        PrefetchFs {
+            inner: Arc::new(inner),
+            window: window as u64,
+            threads: ThreadPool::new(num_threads),
+        }
+    }
+
+    // Keep track of a read, and find the block that has its data, if any. Returns the blocks that
+    // need to be read ahead along with it.
+    fn track(&self, fh: &PrefetchHandle<T::FileHandle>, offset: u64, size: u64)
+            -> (Option<Arc<Block>>, Vec<Arc<Block>>) {
+        let mut stream = fh.stream.lock().unwrap();
+
+        if offset == stream.next {
+            stream.run += 1;
+        } else {
+            stream.run = 0;
+            stream.blocks.clear();
+        }
+        stream.next = offset + size;
+
+        // Blocks the reads have moved past, or that failed, aren't needed anymore.
+        stream.blocks.retain(|block| block.offset + block.size > offset && !block.failed());
+        let hit = stream.blocks.iter().find(|block| block.covers(offset, size)).cloned();
+
+        let mut start = vec![];
+        if stream.run >= SEQUENTIAL_READS && !stream.closed {
+            loop {
+                let ahead = match stream.blocks.back() {
+                    Some(block) if block.at_eof() => break,
+                    Some(block) => block.offset + block.size,
+                    None => stream.next,
+                };
+                if stream.blocks.len() >= MAX_BLOCKS || ahead >= stream.next + self.window {
+                    break;
+                }
+                let block = Arc::new(Block {
+                    offset: ahead,
+                    size: self.window,
+                    state: Mutex::new(BlockState::Reading(vec![])),
+                });
+                stream.blocks.push_back(block.clone());
+                stream.in_flight += 1;
+                start.push(block);
+            }
+        }
+
+        (hit, start)
+    }
+
+    // Read a block ahead in the background. It was counted as in flight on the handle by `track`,
+    // and stops counting once the read is done.
+    fn read_ahead(&self, req: RequestInfo, path: Arc<PathBuf>,
+                  fh: Arc<PrefetchHandle<T::FileHandle>>, block: Arc<Block>) {
+        debug!("prefetch: reading {:?} {:#x} @ {:#x}", path, block.size, block.offset);
+        let inner = self.inner.clone();
+        let pool = self.threads.clone();
+        let in_flight = InFlight(fh);
+        self.threads.execute(move|| {
+            let (offset, size) = (block.offset, block.size as u32);
+            let fh = in_flight.0.inner.clone();
+            let fill = Fill(Some(block));
+            let callback: ReadCallback = Box::new(move|result| {
+                fill.finish(result.map(|data| data.to_vec()));
+                drop(in_flight);
+                CallbackResult::dropped()
+            });
+            let future = inner.read_async(req, path, fh, offset, size, callback);
+            executor::spawn(&pool, executor::then(future, |_| ()));
+        });
+    }
+}
+
+impl<T: FilesystemMT + Sync + Send + 'static> FilesystemMT for PrefetchFs<T> {
+    type FileHandle = PrefetchHandle<T::FileHandle>;
+    type DirHandle = T::DirHandle;
+
+    fn setattr(&self, req: RequestInfo, path: &Path, fh: Option<&Self::FileHandle>, attrs: SetAttrRequest) -> ResultGetattr {
+        // What was read ahead may be past the new end of the file.
+        if let (Some(fh), Some(_)) = (fh, attrs.size) {
+            fh.discard();
+        }
+        self.inner.setattr(req, path, fh.map(|fh| &*fh.inner), attrs)
+    }
+
+    fn truncate(&self, req: RequestInfo, path: &Path, fh: Option<&Self::FileHandle>, size: u64) -> ResultEmpty {
+        if let Some(fh) = fh {
+            fh.discard();
+        }
+        self.inner.truncate(req, path, fh.map(|fh| &*fh.inner), size)
+    }
+
+    fn open(&self, req: RequestInfo, path: &Path, flags: FileFlags) -> ResultOpen<Self::FileHandle> {
+        self.inner.open(req, path, flags).map(|(fh, flags)| (PrefetchHandle::new(fh), flags))
+    }
+
+    fn write(&self, req: RequestInfo, path: &Path, fh: &Self::FileHandle, offset: u64, data: &[u8], flags: u32) -> ResultWrite {
+        fh.discard();
+        self.inner.write(req, path, &fh.inner, offset, data, flags)
+    }
+
+    fn release(&self, req: RequestInfo, path: &Path, fh: &Self::FileHandle, flags: u32, lock_owner: u64, flush: bool) -> ResultEmpty {
+        // Reads ahead run on this wrapper's threads, so nothing else waits for them to finish
+        // before the handle is released.
+        fh.close();
+        self.inner.release(req, path, &fh.inner, flags, lock_owner, flush)
+    }
+
+    fn create(&self, req: RequestInfo, parent: &Path, name: &OsStr, mode: u32, flags: FileFlags) -> ResultCreate<Self::FileHandle> {
+        self.inner.create(req, parent, name, mode, flags).map(|created| CreatedEntry {
+            ttl: created.ttl,
+            attr: created.attr,
+            generation: created.generation,
+            fh: PrefetchHandle::new(created.fh),
+            flags: created.flags,
+        })
+    }
+
+    fn read_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, offset: u64, size: u32, callback: ReadCallback) -> BoxFuture<CallbackResult> {
+        let (hit, start) = self.track(&fh, offset, size as u64);
+        for block in start {
+            self.read_ahead(req, path.clone(), fh.clone(), block);
+        }
+
+        let block = match hit {
+            Some(block) => block,
+            None => {
+                return self.inner.clone().read_async(req, path, fh.inner.clone(), offset, size,
+                                                     callback);
+            }
+        };
+
+        let (token, future) = executor::deferred();
+        let start = (offset - block.offset) as usize;
+        block.with_data(Box::new(move|result| {
+            let replied = match *result {
+                Ok(ref data) if start >= data.len() => callback(Ok(&[])),
+                Ok(ref data) => {
+                    let end = cmp::min(start + size as usize, data.len());
+                    callback(Ok(&data[start..end]))
+                },
+                Err(e) => callback(Err(e)),
+            };
+            token.reply(Ok(replied));
+        }));
+        executor::then(future, |result| result.unwrap_or_else(|_| CallbackResult::dropped()))
+    }
+
+    fn write_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, offset: u64, data: Vec<u8>, flags: u32) -> BoxFuture<ResultWrite> {
+        fh.discard();
+        self.inner.clone().write_async(req, path, fh.inner.clone(), offset, data, flags)
+    }
+
+    forward! {
+        handles: wrapped,
+        methods: [
+            init, destroy, lookup, getattr, chmod, chown, utimens, utimens_macos, readlink, mknod,
+            mkdir, unlink, rmdir, symlink, rename, link, read, flush, fsync, opendir, readdir,
+            releasedir, fsyncdir, statfs, setxattr, getxattr, listxattr, removexattr, access,
+            getlk, setlk, bmap, setvolname, exchange, getxtimes, flush_async, fsync_async,
+            getattr_async, readdir_async, setlk_async,
+        ],
+    }
+}
@@ -0,0 +1,108 @@
+// ReadBuffer :: Buffers for reading file data into, which are reused from one read to the next
+//               instead of being allocated for each.
+//
+// Copyright (c) 2016 by William R. Fraser
+//
+
+use std::cmp;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc;
+
+// How many unused buffers the pool holds on to.
+const MAX_POOLED: usize = 16;
+
+// How many pages the kernel asks for in one read at most, unless the `max_read` mount option says
+// less. rust-fuse doesn't ask for more in INIT, so this is all it gets.
+const MAX_PAGES: usize = 32;
+
+// The size of the buffers in the pool: the most the kernel will ask for in one read. 0 until it's
+// first needed.
+static BUFFER_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+static POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+// Get the size of the buffers in the pool.
+fn buffer_size() -> usize {
+    match BUFFER_SIZE.load(Ordering::Relaxed) {
+        0 => {
+            let size = default_max_read();
+            BUFFER_SIZE.store(size, Ordering::Relaxed);
+            size
+        },
+        size => size,
+    }
+}
+
+// The most the kernel will ask for in one read without the `max_read` mount option.
+pub(crate) fn default_max_read() -> usize {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    MAX_PAGES * if page_size > 0 { page_size as usize } else { 4096 }
+}
+
+// Size the buffers in the pool for the largest read the kernel was mounted to ask for. Buffers of
+// the old size are freed when they're returned.
+pub(crate) fn set_max_read(size: usize) {
+    let size = cmp::min(size, default_max_read());
+    if BUFFER_SIZE.swap(size, Ordering::Relaxed) != size {
+        POOL.lock().unwrap().clear();
+    }
+}
+
+/// A buffer for `read` to read file data into before passing it to the callback, borrowed from a
+/// pool shared by all threads. When it's dropped (usually right after the callback has replied
+/// with it), it goes back to the pool, so that the next read can use it without allocating.
+///
+/// Pooled buffers are allocated big enough for the largest read the kernel will ask for (the
+/// `max_read` mount option, if it's given when mounting with `fuse_mt::mount` or
+/// `fuse_mt::spawn_mount`), so any read request can use one. Bigger buffers can be borrowed, but
+/// are freed instead of going back to the pool.
+///
+/// The contents of a new buffer are unspecified: it may still hold data from an earlier read.
+pub struct ReadBuffer {
+    buf: Vec<u8>,
+}
+
+impl ReadBuffer {
+    /// Borrow a buffer of the given size.
+    pub fn new(size: usize) -> ReadBuffer {
+        let pool_size = buffer_size();
+        let mut buf = if size <= pool_size {
+            POOL.lock().unwrap().pop().unwrap_or_else(|| Vec::with_capacity(pool_size))
+        } else {
+            Vec::with_capacity(size)
+        };
+        buf.resize(size, 0);
+        ReadBuffer { buf: buf }
+    }
+}
+
+impl Deref for ReadBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for ReadBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for ReadBuffer {
+    fn drop(&mut self) {
+        let buf = mem::replace(&mut self.buf, Vec::new());
+        if buf.capacity() != buffer_size() {
+            return;
+        }
+        let mut pool = POOL.lock().unwrap();
+        if pool.len() < MAX_POOLED {
+            pool.push(buf);
+        }
+    }
+}
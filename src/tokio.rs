@@ -0,0 +1,131 @@
+// Tokio :: Mounts a FuseMT filesystem alongside a tokio runtime, running the futures returned by
+//          its asynchronous operations as tasks on the runtime.
+//
+// Copyright (c) 2016 by William R. Fraser
+//
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use libc;
+use tokio_rt::runtime::Handle;
+use tokio_rt::task::JoinHandle;
+
+use errno::Errno;
+use executor::{self, BoxFuture};
+use fusemt::*;
+use mount::MountOption;
+
+/// Mount a filesystem, running the futures returned by its asynchronous operations (e.g.
+/// `read_async`) as tasks on a tokio runtime, so that it can share the runtime with the rest of
+/// the application.
+///
+/// The FUSE session itself is blocking, so it runs on one of the runtime's blocking threads. The
+/// returned handle completes when the filesystem is unmounted, or with an error if it couldn't be
+/// mounted; the options are checked the same way as by `fuse_mt::mount`.
+///
+/// Asynchronous operations that aren't overridden call the synchronous ones on the runtime's
+/// threads, so those shouldn't block for long.
+pub fn mount<T, P>(handle: &Handle, fs: FuseMT<T>, mountpoint: P, options: &[MountOption])
+        -> JoinHandle<io::Result<()>>
+    where T: FilesystemMT + Sync + Send + 'static,
+          P: AsRef<Path> + Send + 'static
+{
+    let spawn_handle = handle.clone();
+    let fs = fs.spawn_futures_with(move|future| {
+        spawn_handle.spawn(future);
+    });
+
+    let options = options.to_vec();
+    handle.spawn_blocking(move|| ::mount::mount(fs, &mountpoint, &options))
+}
+
+/// Wraps a synchronous filesystem so that its dispatched operations (the ones with an
+/// asynchronous version, e.g. `read_async`) run on the runtime's blocking threads, using
+/// `spawn_blocking`, instead of tying up the threads that run its tasks.
+///
+/// This lets a filesystem that only implements the synchronous operations be mounted with `mount`
+/// alongside ones that implement the asynchronous ones.
+pub struct SpawnBlocking<T> {
+    inner: Arc<T>,
+    handle: Handle,
+}
+
+impl<T: FilesystemMT + Sync + Send + 'static> SpawnBlocking<T> {
+    pub fn new(handle: &Handle, inner: T) -> SpawnBlocking<T> {
+        SpawnBlocking {
+            inner: Arc::new(inner),
+            handle: handle.clone(),
+        }
+    }
+
+    // Call an operation on a blocking thread. If it panics, the operation fails with EIO.
+    fn spawn<R, F>(&self, f: F) -> BoxFuture<Result<R, Errno>>
+        where R: Send + 'static,
+              F: FnOnce(&T) -> Result<R, Errno> + Send + 'static
+    {
+        let inner = self.inner.clone();
+        let task = self.handle.spawn_blocking(move|| f(&inner));
+        executor::then(Box::pin(task), |result| match result {
+            Ok(result) => result,
+            Err(e) => {
+                error!("blocking operation failed: {}", e);
+                Err(Errno(libc::EIO))
+            }
+        })
+    }
+}
+
+impl<T: FilesystemMT + Sync + Send + 'static> FilesystemMT for SpawnBlocking<T> {
+    type FileHandle = T::FileHandle;
+    type DirHandle = T::DirHandle;
+
+    fn read_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, offset: u64, size: u32, callback: ReadCallback) -> BoxFuture<CallbackResult> {
+        let inner = self.inner.clone();
+        let task = self.handle.spawn_blocking(move|| inner.read(req, &path, &fh, offset, size, callback));
+        executor::then(Box::pin(task), |result| match result {
+            Ok(result) => result,
+            Err(e) => {
+                // The callback was dropped with the task, which failed the read.
+                error!("blocking operation failed: {}", e);
+                CallbackResult::dropped()
+            }
+        })
+    }
+
+    fn write_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, offset: u64, data: Vec<u8>, flags: u32) -> BoxFuture<ResultWrite> {
+        self.spawn(move|fs| fs.write(req, &path, &fh, offset, &data, flags))
+    }
+
+    fn flush_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, lock_owner: u64) -> BoxFuture<ResultEmpty> {
+        self.spawn(move|fs| fs.flush(req, &path, &fh, lock_owner))
+    }
+
+    fn fsync_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, datasync: bool) -> BoxFuture<ResultEmpty> {
+        self.spawn(move|fs| fs.fsync(req, &path, &fh, datasync))
+    }
+
+    fn getattr_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Option<Arc<Self::FileHandle>>) -> BoxFuture<ResultGetattr> {
+        self.spawn(move|fs| fs.getattr(req, &path, fh.as_deref()))
+    }
+
+    fn readdir_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::DirHandle>, offset: u64) -> BoxFuture<ResultReaddir> {
+        self.spawn(move|fs| fs.readdir(req, &path, &fh, offset))
+    }
+
+    fn setlk_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, lock_owner: u64, lock: FileLock, sleep: bool) -> BoxFuture<ResultEmpty> {
+        self.spawn(move|fs| fs.setlk(req, &path, &fh, lock_owner, lock, sleep))
+    }
+
+    forward! {
+        handles: same,
+        methods: [
+            init, destroy, lookup, getattr, setattr, chmod, chown, truncate, utimens,
+            utimens_macos, readlink, mknod, mkdir, unlink, rmdir, symlink, rename, link, open,
+            read, write, flush, release, fsync, opendir, readdir, releasedir, fsyncdir, statfs,
+            setxattr, getxattr, listxattr, removexattr, access, create, getlk, setlk, bmap,
+            setvolname, exchange, getxtimes,
+        ],
+    }
+}
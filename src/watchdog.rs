@@ -0,0 +1,167 @@
+// Watchdog :: Fails requests that the filesystem takes too long to reply to, so that one hung
+//             operation can't leave the process that made it waiting forever.
+//
+// Copyright (c) 2016 by William R. Fraser
+//
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Key = (Instant, u64);
+
+// Called with nothing when a deadline passes.
+type Expiry = Box<dyn FnOnce() + Send>;
+
+struct State {
+    next_id: u64,
+    deadlines: BTreeMap<Key, Expiry>,
+    running: bool,
+}
+
+/// Keeps track of the deadlines of operations in progress, and runs a thread that expires them
+/// while there are any.
+pub struct Watchdog {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Watchdog {
+    pub fn new() -> Arc<Watchdog> {
+        Arc::new(Watchdog {
+            state: Mutex::new(State {
+                next_id: 0,
+                deadlines: BTreeMap::new(),
+                running: false,
+            }),
+            changed: Condvar::new(),
+        })
+    }
+
+    fn schedule(this: &Arc<Watchdog>, timeout: Duration, expiry: Expiry) -> Key {
+        let mut state = this.state.lock().unwrap();
+        let key = (Instant::now() + timeout, state.next_id);
+        state.next_id += 1;
+        state.deadlines.insert(key, expiry);
+        if state.running {
+            this.changed.notify_one();
+        } else {
+            state.running = true;
+            let watchdog = this.clone();
+            thread::spawn(move|| watchdog.run());
+        }
+        key
+    }
+
+    fn cancel(&self, key: &Key) {
+        self.state.lock().unwrap().deadlines.remove(key);
+    }
+
+    // Expire deadlines as they pass. The thread exits once there aren't any left, and a new one is
+    // started when there are again.
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let key = match state.deadlines.keys().next() {
+                Some(key) => *key,
+                None => {
+                    state.running = false;
+                    return;
+                }
+            };
+
+            let now = Instant::now();
+            if key.0 > now {
+                state = self.changed.wait_timeout(state, key.0 - now).unwrap().0;
+                continue;
+            }
+
+            let expiry = state.deadlines.remove(&key).unwrap();
+            drop(state);
+            expiry();
+            state = self.state.lock().unwrap();
+        }
+    }
+}
+
+/// The reply to an operation that has a deadline. Whichever comes first, the operation completing
+/// or the deadline passing, gets to use it.
+pub struct Deadline<R> {
+    op: &'static str,
+    reply: Arc<Mutex<Option<R>>>,
+    watch: Option<(Arc<Watchdog>, Key)>,
+}
+
+impl<R: Send + 'static> Deadline<R> {
+    /// Give a reply a deadline, if a watchdog and timeout are given. If the deadline passes before
+    /// the operation completes, the reply is passed to `expire`.
+    pub fn new<F>(op: &'static str, reply: R, watchdog: Option<(&Arc<Watchdog>, Duration)>, expire: F)
+            -> Deadline<R>
+        where F: FnOnce(R) + Send + 'static
+    {
+        let reply = Arc::new(Mutex::new(Some(reply)));
+        let watch = watchdog.map(|(watchdog, timeout)| {
+            let expired_reply = reply.clone();
+            let key = Watchdog::schedule(watchdog, timeout, Box::new(move|| {
+                if let Some(reply) = expired_reply.lock().unwrap().take() {
+                    warn!("{}: timed out after {:?}", op, timeout);
+                    expire(reply);
+                }
+            }));
+            (watchdog.clone(), key)
+        });
+        Deadline {
+            op: op,
+            reply: reply,
+            watch: watch,
+        }
+    }
+
+    /// Take the reply once the operation completes, or None if its deadline has already passed.
+    pub fn complete(self) -> Option<R> {
+        if let Some((ref watchdog, ref key)) = self.watch {
+            watchdog.cancel(key);
+        }
+        let reply = self.reply.lock().unwrap().take();
+        if reply.is_none() {
+            warn!("{}: completed after it timed out", self.op);
+        }
+        reply
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn completing_first_keeps_the_reply() {
+        let watchdog = Watchdog::new();
+        let (expired, expirations) = mpsc::channel();
+        let deadline = Deadline::new("test", 42, Some((&watchdog, Duration::from_secs(60))),
+                                     move|reply| expired.send(reply).unwrap());
+
+        assert_eq!(deadline.complete(), Some(42));
+        assert!(watchdog.state.lock().unwrap().deadlines.is_empty());
+        assert!(expirations.try_recv().is_err());
+    }
+
+    #[test]
+    fn expiring_first_takes_the_reply() {
+        let watchdog = Watchdog::new();
+        let (expired, expirations) = mpsc::channel();
+        let deadline = Deadline::new("test", 42, Some((&watchdog, Duration::from_millis(10))),
+                                     move|reply| expired.send(reply).unwrap());
+
+        assert_eq!(expirations.recv_timeout(Duration::from_secs(10)), Ok(42));
+        assert_eq!(deadline.complete(), None);
+    }
+
+    #[test]
+    fn no_watchdog_means_no_deadline() {
+        let deadline = Deadline::new("test", 42, None, |_| panic!("expired without a deadline"));
+        assert_eq!(deadline.complete(), Some(42));
+    }
+}
@@ -0,0 +1,441 @@
+// WriteBufferFs :: A wrapper around a filesystem that gathers up small writes that follow on from
+//                  each other into bigger ones.
+//
+// Copyright (c) 2016 by William R. Fraser
+//
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use libc;
+
+use errno::Errno;
+use executor::{self, BoxFuture};
+use fusemt::*;
+
+// Data that has been written to a file handle, but not yet to the filesystem.
+struct Pending<H> {
+    req: RequestInfo,
+    path: Arc<PathBuf>,
+    fh: Arc<H>,
+    offset: u64,
+    data: Vec<u8>,
+    flags: u32,
+}
+
+struct Handle<H> {
+    pending: Option<Pending<H>>,
+    // An error from writing out pending data, which is returned by the next operation on the
+    // handle that can fail.
+    error: Option<Errno>,
+}
+
+// The handles with pending data or errors, by the number each `WriteBufferHandle` was given.
+type Handles<H> = Arc<Mutex<HashMap<u64, Handle<H>>>>;
+
+// Record an error from writing out pending data, for the handle's next operation to return.
+fn record_error<H>(handles: &Handles<H>, fh: u64, len: usize, result: ResultWrite) {
+    let error = match result {
+        Ok(written) if written as usize == len => return,
+        Ok(written) => {
+            error!("write buffer: short write on handle {} ({} of {} bytes)", fh, written, len);
+            Errno(libc::EIO)
+        },
+        Err(e) => e,
+    };
+    if let Some(handle) = handles.lock().unwrap().get_mut(&fh) {
+        handle.error = Some(error);
+    } else {
+        error!("write buffer: writing to released handle {} failed: {}", fh, error);
+    }
+}
+
+// Take the error from writing out a handle's pending data, if there was one.
+fn take_error<H>(handles: &Handles<H>, fh: u64) -> ResultEmpty {
+    match handles.lock().unwrap().get_mut(&fh).and_then(|handle| handle.error.take()) {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// The handle of a file opened through a `WriteBufferFs`: the wrapped filesystem's handle, and the
+/// number its buffered writes are kept under.
+pub struct WriteBufferHandle<H> {
+    id: u64,
+    inner: Arc<H>,
+}
+
+/// Wraps a filesystem, gathering up writes to a file handle that follow on from each other into a
+/// buffer, and writing them to the filesystem all at once, for backends where each write has a
+/// fixed cost (e.g. object stores, databases).
+///
+/// The buffer is written out when it reaches the size limit, when a write doesn't follow on from
+/// it, and before operations that need it to have been (`flush`, `fsync`, `release`, and reads,
+/// `getattr`, `setattr`, `truncate`, `rename`, and `unlink` of the same path). Since writes
+/// succeed as soon as they're buffered, an error from writing the buffer out is returned by the
+/// next write, `flush`, or `fsync` on the handle instead, as it would be by the kernel's own
+/// write-back cache.
+pub struct WriteBufferFs<T: FilesystemMT> {
+    inner: Arc<T>,
+    max_buffer: usize,
+    handles: Handles<T::FileHandle>,
+    next_id: AtomicU64,
+}
+
+impl<T: FilesystemMT + Sync + Send + 'static> WriteBufferFs<T> {
+    /// Wrap a filesystem, buffering up to `max_buffer` bytes per file handle.
+    pub fn new(inner: T, max_buffer: usize) -> WriteBufferFs<T> {
+        WriteBufferFs {
+            inner: Arc::new(inner),
+            max_buffer: max_buffer,
+            handles: Arc::new(Mutex::new(HashMap::new())),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    fn wrap(&self, fh: T::FileHandle) -> WriteBufferHandle<T::FileHandle> {
+        WriteBufferHandle {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            inner: Arc::new(fh),
+        }
+    }
+
+    // Take the pending data of the handles that write to a path (or anything under it).
+    fn take_path(&self, path: &Path) -> Vec<(u64, Pending<T::FileHandle>)> {
+        let mut handles = self.handles.lock().unwrap();
+        let mut taken = vec![];
+        for (&fh, handle) in handles.iter_mut() {
+            if handle.pending.as_ref().map_or(false, |pending| pending.path.starts_with(path)) {
+                taken.push((fh, handle.pending.take().unwrap()));
+            }
+        }
+        taken
+    }
+
+    // Write out pending data.
+    fn write_pending(&self, fh: u64, pending: Pending<T::FileHandle>) -> BoxFuture<()> {
+        let handles = self.handles.clone();
+        let len = pending.data.len();
+        let future = self.inner.clone().write_async(pending.req, pending.path, pending.fh,
+                                                    pending.offset, pending.data, pending.flags);
+        executor::then(future, move|result| record_error(&handles, fh, len, result))
+    }
+
+    fn write_all_pending(&self, taken: Vec<(u64, Pending<T::FileHandle>)>) -> BoxFuture<()> {
+        let mut future: BoxFuture<()> = Box::pin(future::ready(()));
+        for (fh, pending) in taken {
+            let next = self.write_pending(fh, pending);
+            future = executor::and_then(future, move|()| next);
+        }
+        future
+    }
+
+    // Write out pending data, from a synchronous operation.
+    fn write_pending_sync(&self, taken: Vec<(u64, Pending<T::FileHandle>)>) {
+        for (fh, pending) in taken {
+            let result = self.inner.write(pending.req, &pending.path, &pending.fh, pending.offset,
+                                          &pending.data, pending.flags);
+            record_error(&self.handles, fh, pending.data.len(), result);
+        }
+    }
+
+    fn write_out_path(&self, path: &Path) {
+        let taken = self.take_path(path);
+        self.write_pending_sync(taken);
+    }
+
+    fn write_out_handle(&self, fh: u64) {
+        let pending = self.handles.lock().unwrap().get_mut(&fh).and_then(|handle| handle.pending.take());
+        if let Some(pending) = pending {
+            self.write_pending_sync(vec![(fh, pending)]);
+        }
+    }
+
+    // Write out a handle's pending data, and then get the error from doing so (or from earlier),
+    // if any.
+    fn write_out_and_check(&self, fh: u64) -> BoxFuture<ResultEmpty> {
+        let pending = self.handles.lock().unwrap().get_mut(&fh).and_then(|handle| handle.pending.take());
+        let written = match pending {
+            Some(pending) => self.write_pending(fh, pending),
+            None => Box::pin(future::ready(())),
+        };
+        let handles = self.handles.clone();
+        executor::then(written, move|()| take_error(&handles, fh))
+    }
+}
+
+impl<T: FilesystemMT + Sync + Send + 'static> FilesystemMT for WriteBufferFs<T> {
+    type FileHandle = WriteBufferHandle<T::FileHandle>;
+    type DirHandle = T::DirHandle;
+
+    fn getattr(&self, req: RequestInfo, path: &Path, fh: Option<&Self::FileHandle>) -> ResultGetattr {
+        self.write_out_path(path);
+        self.inner.getattr(req, path, fh.map(|fh| &*fh.inner))
+    }
+
+    fn setattr(&self, req: RequestInfo, path: &Path, fh: Option<&Self::FileHandle>, attrs: SetAttrRequest) -> ResultGetattr {
+        self.write_out_path(path);
+        self.inner.setattr(req, path, fh.map(|fh| &*fh.inner), attrs)
+    }
+
+    fn truncate(&self, req: RequestInfo, path: &Path, fh: Option<&Self::FileHandle>, size: u64) -> ResultEmpty {
+        self.write_out_path(path);
+        self.inner.truncate(req, path, fh.map(|fh| &*fh.inner), size)
+    }
+
+    fn unlink(&self, req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+        self.write_out_path(&parent.join(name));
+        self.inner.unlink(req, parent, name)
+    }
+
+    fn rename(&self, req: RequestInfo, parent: &Path, name: &OsStr, newparent: &Path, newname: &OsStr) -> ResultEmpty {
+        self.write_out_path(&parent.join(name));
+        self.inner.rename(req, parent, name, newparent, newname)
+    }
+
+    fn open(&self, req: RequestInfo, path: &Path, flags: FileFlags) -> ResultOpen<Self::FileHandle> {
+        self.inner.open(req, path, flags).map(|(fh, flags)| (self.wrap(fh), flags))
+    }
+
+    fn read(&self, req: RequestInfo, path: &Path, fh: &Self::FileHandle, offset: u64, size: u32, callback: impl FnOnce(ResultSlice) -> CallbackResult) -> CallbackResult {
+        self.write_out_path(path);
+        self.inner.read(req, path, &fh.inner, offset, size, callback)
+    }
+
+    fn write(&self, req: RequestInfo, path: &Path, fh: &Self::FileHandle, offset: u64, data: &[u8], flags: u32) -> ResultWrite {
+        self.write_out_handle(fh.id);
+        take_error(&self.handles, fh.id)?;
+        self.inner.write(req, path, &fh.inner, offset, data, flags)
+    }
+
+    fn flush(&self, req: RequestInfo, path: &Path, fh: &Self::FileHandle, lock_owner: u64) -> ResultEmpty {
+        self.write_out_handle(fh.id);
+        take_error(&self.handles, fh.id)?;
+        self.inner.flush(req, path, &fh.inner, lock_owner)
+    }
+
+    fn release(&self, req: RequestInfo, path: &Path, fh: &Self::FileHandle, flags: u32, lock_owner: u64, flush: bool) -> ResultEmpty {
+        self.write_out_handle(fh.id);
+        self.handles.lock().unwrap().remove(&fh.id);
+        self.inner.release(req, path, &fh.inner, flags, lock_owner, flush)
+    }
+
+    fn fsync(&self, req: RequestInfo, path: &Path, fh: &Self::FileHandle, datasync: bool) -> ResultEmpty {
+        self.write_out_handle(fh.id);
+        take_error(&self.handles, fh.id)?;
+        self.inner.fsync(req, path, &fh.inner, datasync)
+    }
+
+    fn create(&self, req: RequestInfo, parent: &Path, name: &OsStr, mode: u32, flags: FileFlags) -> ResultCreate<Self::FileHandle> {
+        self.inner.create(req, parent, name, mode, flags).map(|created| CreatedEntry {
+            ttl: created.ttl,
+            attr: created.attr,
+            generation: created.generation,
+            fh: self.wrap(created.fh),
+            flags: created.flags,
+        })
+    }
+
+    fn read_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, offset: u64, size: u32, callback: ReadCallback) -> BoxFuture<CallbackResult> {
+        let written = self.write_all_pending(self.take_path(&path));
+        executor::and_then(written, move|()| {
+            self.inner.clone().read_async(req, path, fh.inner.clone(), offset, size, callback)
+        })
+    }
+
+    fn write_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, offset: u64, data: Vec<u8>, flags: u32) -> BoxFuture<ResultWrite> {
+        let len = data.len() as u32;
+        let (earlier, full) = {
+            let mut handles = self.handles.lock().unwrap();
+            let handle = handles.entry(fh.id).or_insert_with(|| Handle {
+                pending: None,
+                error: None,
+            });
+            if let Some(e) = handle.error.take() {
+                return Box::pin(future::ready(Err(e)));
+            }
+
+            // Data that this write doesn't follow on from has to be written out first.
+            let follows = match handle.pending {
+                Some(ref pending) => pending.offset + pending.data.len() as u64 == offset
+                    && pending.flags == flags,
+                None => true,
+            };
+            let earlier = if follows { None } else { handle.pending.take() };
+
+            match handle.pending {
+                Some(ref mut pending) => pending.data.extend_from_slice(&data),
+                None => {
+                    handle.pending = Some(Pending {
+                        req: req,
+                        path: path,
+                        fh: fh.inner.clone(),
+                        offset: offset,
+                        data: data,
+                        flags: flags,
+                    });
+                },
+            }
+
+            let full = if handle.pending.as_ref().unwrap().data.len() >= self.max_buffer {
+                handle.pending.take()
+            } else {
+                None
+            };
+            (earlier, full)
+        };
+
+        let taken = earlier.into_iter().chain(full).map(|pending| (fh.id, pending)).collect();
+        executor::then(self.write_all_pending(taken), move|()| Ok(len))
+    }
+
+    fn flush_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, lock_owner: u64) -> BoxFuture<ResultEmpty> {
+        executor::and_then(self.write_out_and_check(fh.id), move|result| match result {
+            Ok(()) => self.inner.clone().flush_async(req, path, fh.inner.clone(), lock_owner),
+            Err(e) => Box::pin(future::ready(Err(e))),
+        })
+    }
+
+    fn fsync_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Arc<Self::FileHandle>, datasync: bool) -> BoxFuture<ResultEmpty> {
+        executor::and_then(self.write_out_and_check(fh.id), move|result| match result {
+            Ok(()) => self.inner.clone().fsync_async(req, path, fh.inner.clone(), datasync),
+            Err(e) => Box::pin(future::ready(Err(e))),
+        })
+    }
+
+    fn getattr_async(self: Arc<Self>, req: RequestInfo, path: Arc<PathBuf>, fh: Option<Arc<Self::FileHandle>>) -> BoxFuture<ResultGetattr> {
+        let written = self.write_all_pending(self.take_path(&path));
+        executor::and_then(written, move|()| self.inner.clone().getattr_async(req, path, fh.map(|fh| fh.inner.clone())))
+    }
+
+    forward! {
+        handles: wrapped,
+        methods: [
+            init, destroy, lookup, chmod, chown, utimens, utimens_macos, readlink, mknod, mkdir,
+            rmdir, symlink, link, opendir, readdir, releasedir, fsyncdir, statfs, setxattr,
+            getxattr, listxattr, removexattr, access, getlk, setlk, bmap, setvolname, exchange,
+            getxtimes, readdir_async, setlk_async,
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    // Records the writes that reach it, and fails them with `fail`, if it's set.
+    #[derive(Default)]
+    struct Recorder {
+        writes: Mutex<Vec<(u64, Vec<u8>)>>,
+        fail: Mutex<Option<Errno>>,
+    }
+
+    impl FilesystemMT for Recorder {
+        type FileHandle = ();
+        type DirHandle = ();
+
+        fn open(&self, _req: RequestInfo, _path: &Path, _flags: FileFlags) -> ResultOpen<()> {
+            Ok(((), OpenFlags::empty()))
+        }
+
+        fn write(&self, _req: RequestInfo, _path: &Path, _fh: &(), offset: u64, data: &[u8], _flags: u32) -> ResultWrite {
+            if let Some(e) = *self.fail.lock().unwrap() {
+                return Err(e);
+            }
+            self.writes.lock().unwrap().push((offset, data.to_vec()));
+            Ok(data.len() as u32)
+        }
+
+        fn flush(&self, _req: RequestInfo, _path: &Path, _fh: &(), _lock_owner: u64) -> ResultEmpty {
+            Ok(())
+        }
+    }
+
+    struct NoWake;
+
+    impl Wake for NoWake {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    // The recorder's futures are all ready right away, so one poll is enough.
+    fn ready<T>(mut future: BoxFuture<T>) -> T {
+        let waker = Waker::from(Arc::new(NoWake));
+        match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future wasn't ready"),
+        }
+    }
+
+    fn req() -> RequestInfo {
+        RequestInfo {
+            unique: 0,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        }
+    }
+
+    fn setup(max_buffer: usize) -> (Arc<WriteBufferFs<Recorder>>, Arc<PathBuf>, Arc<WriteBufferHandle<()>>) {
+        let fs = WriteBufferFs::new(Recorder::default(), max_buffer);
+        let path = Arc::new(PathBuf::from("/file"));
+        let fh = Arc::new(fs.open(req(), &path, FileFlags::new(0)).unwrap().0);
+        (Arc::new(fs), path, fh)
+    }
+
+    fn write(fs: &Arc<WriteBufferFs<Recorder>>, path: &Arc<PathBuf>, fh: &Arc<WriteBufferHandle<()>>, offset: u64, data: &[u8]) -> ResultWrite {
+        ready(fs.clone().write_async(req(), path.clone(), fh.clone(), offset, data.to_vec(), 0))
+    }
+
+    fn flush(fs: &Arc<WriteBufferFs<Recorder>>, path: &Arc<PathBuf>, fh: &Arc<WriteBufferHandle<()>>) -> ResultEmpty {
+        ready(fs.clone().flush_async(req(), path.clone(), fh.clone(), 0))
+    }
+
+    #[test]
+    fn adjacent_writes_are_coalesced() {
+        let (fs, path, fh) = setup(1024);
+        assert_eq!(write(&fs, &path, &fh, 0, b"ab"), Ok(2));
+        assert_eq!(write(&fs, &path, &fh, 2, b"cd"), Ok(2));
+        assert!(fs.inner.writes.lock().unwrap().is_empty());
+
+        // A write that doesn't follow on writes out what came before it.
+        assert_eq!(write(&fs, &path, &fh, 10, b"ef"), Ok(2));
+        assert_eq!(*fs.inner.writes.lock().unwrap(), vec![(0, b"abcd".to_vec())]);
+
+        assert_eq!(flush(&fs, &path, &fh), Ok(()));
+        assert_eq!(*fs.inner.writes.lock().unwrap(), vec![(0, b"abcd".to_vec()), (10, b"ef".to_vec())]);
+    }
+
+    #[test]
+    fn full_buffer_is_written_out() {
+        let (fs, path, fh) = setup(4);
+        assert_eq!(write(&fs, &path, &fh, 0, b"abc"), Ok(3));
+        assert_eq!(write(&fs, &path, &fh, 3, b"def"), Ok(3));
+        assert_eq!(*fs.inner.writes.lock().unwrap(), vec![(0, b"abcdef".to_vec())]);
+    }
+
+    #[test]
+    fn write_out_errors_go_to_the_next_operation() {
+        let (fs, path, fh) = setup(1024);
+        *fs.inner.fail.lock().unwrap() = Some(Errno(libc::ENOSPC));
+        assert_eq!(write(&fs, &path, &fh, 0, b"ab"), Ok(2));
+        assert_eq!(flush(&fs, &path, &fh), Err(Errno(libc::ENOSPC)));
+        // The error is only returned once.
+        assert_eq!(flush(&fs, &path, &fh), Ok(()));
+
+        // An error from a write that didn't follow on is returned by the next write.
+        assert_eq!(write(&fs, &path, &fh, 0, b"ab"), Ok(2));
+        assert_eq!(write(&fs, &path, &fh, 10, b"cd"), Ok(2));
+        assert_eq!(write(&fs, &path, &fh, 12, b"ef"), Err(Errno(libc::ENOSPC)));
+
+        // The synchronous operations write out what's pending and return errors too.
+        assert_eq!(fs.flush(req(), &path, &fh, 0), Err(Errno(libc::ENOSPC)));
+        assert_eq!(fs.flush(req(), &path, &fh, 0), Ok(()));
+        assert_eq!(write(&fs, &path, &fh, 20, b"gh"), Ok(2));
+        assert_eq!(fs.fsync(req(), &path, &fh, false), Err(Errno(libc::ENOSPC)));
+    }
+}